@@ -0,0 +1,244 @@
+//! 用于端到端测试 OpenID Connect 登录的 IdP，不校验用户，直接签发授权码
+//! ID token 使用 HS256 及 client secret 签名
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use clap::Parser;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+#[derive(Parser, Clone)]
+pub struct Args {
+    #[clap(long, default_value = "127.0.0.1:9000")]
+    pub listen: String,
+    #[clap(long, default_value = "proxy")]
+    pub client_id: String,
+    #[clap(long, default_value = "proxy-secret")]
+    pub client_secret: String,
+    /// 登录的用户，可以在授权请求中用 `login_hint` 覆盖
+    #[clap(long, default_value = "alice")]
+    pub user: String,
+    /// 用户的 `groups` 声明
+    #[clap(long, default_value = "staff")]
+    pub groups: Vec<String>,
+    /// 令牌的有效期，单位秒，设置较短时可以测试刷新
+    #[clap(long, default_value_t = 300)]
+    pub expires_in: u64,
+}
+
+struct Grant {
+    user: String,
+    nonce: Option<String>,
+    challenge: Option<String>,
+}
+
+struct Idp {
+    args: Args,
+    issuer: String,
+    codes: Mutex<HashMap<String, Grant>>,
+    refresh_tokens: Mutex<HashMap<String, String>>,
+}
+
+type Shared = Arc<Idp>;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn discovery(State(idp): State<Shared>) -> Json<Value> {
+    let issuer = &idp.issuer;
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "jwks_uri": format!("{issuer}/jwks"),
+        "end_session_endpoint": format!("{issuer}/logout"),
+        "response_types_supported": ["code"],
+        "id_token_signing_alg_values_supported": ["HS256"],
+    }))
+}
+
+async fn jwks() -> Json<Value> {
+    Json(json!({ "keys": [] }))
+}
+
+#[derive(Deserialize)]
+struct AuthorizeParams {
+    client_id: String,
+    redirect_uri: String,
+    state: Option<String>,
+    nonce: Option<String>,
+    code_challenge: Option<String>,
+    login_hint: Option<String>,
+}
+
+/// 直接同意授权，跳转回客户端
+async fn authorize(
+    State(idp): State<Shared>,
+    Query(params): Query<AuthorizeParams>,
+) -> Result<Redirect, (StatusCode, String)> {
+    if params.client_id != idp.args.client_id {
+        return Err((StatusCode::BAD_REQUEST, "unknown client_id".to_string()));
+    }
+    let code = Uuid::new_v4().simple().to_string();
+    let grant = Grant {
+        user: params.login_hint.unwrap_or_else(|| idp.args.user.clone()),
+        nonce: params.nonce,
+        challenge: params.code_challenge,
+    };
+    idp.codes.lock().unwrap().insert(code.clone(), grant);
+    let mut query = vec![("code", code)];
+    query.extend(params.state.map(|state| ("state", state)));
+    let location = Url::parse_with_params(&params.redirect_uri, &query)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(Redirect::to(location.as_str()))
+}
+
+#[derive(Deserialize)]
+struct TokenParams {
+    grant_type: String,
+    code: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+}
+
+fn client_authenticated(idp: &Idp, headers: &HeaderMap) -> bool {
+    let expected = format!("{}:{}", idp.args.client_id, idp.args.client_secret);
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| STANDARD.decode(v).ok())
+        .is_some_and(|v| v == expected.as_bytes())
+}
+
+async fn token(
+    State(idp): State<Shared>,
+    headers: HeaderMap,
+    Form(params): Form<TokenParams>,
+) -> impl IntoResponse {
+    let error = |error: &str| (StatusCode::BAD_REQUEST, Json(json!({ "error": error })));
+    if !client_authenticated(&idp, &headers) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "error": "invalid_client" })),
+        );
+    }
+    let (user, nonce) = match params.grant_type.as_str() {
+        "authorization_code" => {
+            let grant = params
+                .code
+                .and_then(|code| idp.codes.lock().unwrap().remove(&code));
+            let Some(grant) = grant else {
+                return error("invalid_grant");
+            };
+            if let Some(challenge) = &grant.challenge {
+                let verifier = params.code_verifier.unwrap_or_default();
+                if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != *challenge {
+                    return error("invalid_grant");
+                }
+            }
+            (grant.user, grant.nonce)
+        }
+        "refresh_token" => {
+            let user = params
+                .refresh_token
+                .and_then(|token| idp.refresh_tokens.lock().unwrap().remove(&token));
+            let Some(user) = user else {
+                return error("invalid_grant");
+            };
+            (user, None)
+        }
+        _ => return error("unsupported_grant_type"),
+    };
+    let mut claims = json!({
+        "iss": idp.issuer,
+        "aud": idp.args.client_id,
+        "sub": user,
+        "email": format!("{user}@example.com"),
+        "groups": idp.args.groups,
+        "iat": now(),
+        "exp": now() + idp.args.expires_in,
+    });
+    if let Some(nonce) = nonce {
+        claims["nonce"] = json!(nonce);
+    }
+    let key = EncodingKey::from_secret(idp.args.client_secret.as_bytes());
+    let id_token = match encode(&Header::default(), &claims, &key) {
+        Ok(id_token) => id_token,
+        Err(_) => return error("server_error"),
+    };
+    let refresh_token = Uuid::new_v4().simple().to_string();
+    idp.refresh_tokens
+        .lock()
+        .unwrap()
+        .insert(refresh_token.clone(), user);
+    let response = json!({
+        "access_token": Uuid::new_v4().simple().to_string(),
+        "token_type": "Bearer",
+        "id_token": id_token,
+        "refresh_token": refresh_token,
+        "expires_in": idp.args.expires_in,
+    });
+    (StatusCode::OK, Json(response))
+}
+
+#[derive(Deserialize)]
+struct LogoutParams {
+    post_logout_redirect_uri: Option<String>,
+}
+
+async fn logout(Query(params): Query<LogoutParams>) -> impl IntoResponse {
+    match params.post_logout_redirect_uri {
+        Some(uri) => Redirect::to(&uri).into_response(),
+        None => "logged out".into_response(),
+    }
+}
+
+/// IdP 的路由，`issuer` 为外部访问的地址，代理的测试中同样使用
+pub fn app(args: Args, issuer: String) -> Router {
+    let idp = Arc::new(Idp {
+        args,
+        issuer,
+        codes: Mutex::new(HashMap::new()),
+        refresh_tokens: Mutex::new(HashMap::new()),
+    });
+    Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/jwks", get(jwks))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .route("/logout", get(logout))
+        .with_state(idp)
+}
+
+// cargo run --example stub_idp -- --listen 127.0.0.1:9000 --expires-in 30
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let issuer = format!("http://{}", args.listen);
+    let listener = tokio::net::TcpListener::bind(&args.listen).await.unwrap();
+    axum::serve(listener, app(args, issuer)).await.unwrap();
+}
@@ -10,39 +10,72 @@ use pingora::{apps::http_app::ServeHttp, prelude::timeout, protocols::http::Serv
 use tokio::sync::{broadcast, RwLock};
 use tower::ServiceExt;
 
-use crate::svcs::{self, DNSResolver, Op, UpstreamsHealthCheck};
+use crate::{
+    lb::Policies,
+    svcs::{
+        self, DNSResolver, Discovery, DomainConfig, Hosts, Op, Registry, ResolverSettings,
+        StaticDiscovery, UpstreamsHealthCheck, DEFAULT_PROVIDER,
+    },
+};
 
 use super::route::{routes, RouteState};
 
+/// 操作队列的容量，Ingress 等批量变更时一次会产生多个操作
+const OP_QUEUE_SIZE: usize = 1024;
+
 pub struct HttpAdminApp {
     routes: Router,
-    add_domain_queen: broadcast::Receiver<Op>,
+    add_domain_queen: broadcast::Sender<Op>,
     backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+    domains: Arc<RwLock<HashMap<String, Arc<DomainConfig>>>>,
+    providers: Arc<RwLock<HashMap<String, Arc<dyn Discovery>>>>,
 }
 
-impl Default for HttpAdminApp {
-    fn default() -> Self {
+impl HttpAdminApp {
+    /// `providers` 为额外的服务发现，内置的 `dns` 和 `static` 总是可用
+    pub fn new(
+        resolver_settings: ResolverSettings,
+        hosts: Hosts,
+        mut providers: HashMap<String, Arc<dyn Discovery>>,
+        policies: Policies,
+    ) -> Result<Self, svcs::Error> {
         let backgrounds = Arc::new(RwLock::new(HashMap::new()));
-        let (tx, add_domain_queen) = broadcast::channel(5);
-        let state = RouteState::new(tx, backgrounds.clone());
+        let domains = Arc::new(RwLock::new(HashMap::new()));
+        let resolver_profiles = Arc::new(RwLock::new(HashMap::new()));
+        let hosts = Arc::new(RwLock::new(hosts));
+        let resolver =
+            DNSResolver::new(&resolver_settings, resolver_profiles.clone(), hosts.clone())?;
+        providers.insert(DEFAULT_PROVIDER.to_string(), Arc::new(resolver));
+        providers.insert("static".to_string(), Arc::new(StaticDiscovery));
+        let providers = Arc::new(RwLock::new(providers));
+
+        let (add_domain_queen, _) = broadcast::channel(OP_QUEUE_SIZE);
+        let state = RouteState::new(
+            add_domain_queen.clone(),
+            backgrounds.clone(),
+            domains.clone(),
+            resolver_profiles,
+            hosts,
+            providers.clone(),
+            policies,
+        );
         let routes = routes(state);
-        Self {
+        Ok(Self {
             routes,
             add_domain_queen,
             backgrounds,
-        }
+            domains,
+            providers,
+        })
     }
-}
 
-impl HttpAdminApp {
-    pub fn dns_resolver(&self) -> Result<DNSResolver, svcs::Error> {
-        let resolver = DNSResolver::new(
-            None,
-            None,
-            self.add_domain_queen.resubscribe(),
+    pub fn registry(&self) -> Registry {
+        Registry::new(
+            self.providers.clone(),
+            self.add_domain_queen.clone(),
             self.backgrounds.clone(),
-        )?;
-        Ok(resolver)
+            self.domains.clone(),
+        )
     }
 }
 
@@ -1,14 +1,24 @@
+use std::{collections::HashMap, sync::Arc};
+
 use app::HttpAdminApp;
 use pingora::services::listening::Service;
 
-use crate::svcs::{self, DNSResolver};
+use crate::{
+    lb::Policies,
+    svcs::{self, Discovery, Hosts, Registry, ResolverSettings},
+};
 
 mod app;
 mod route;
 
-pub fn service() -> Result<(Service<HttpAdminApp>, DNSResolver), svcs::Error> {
-    let app = HttpAdminApp::default();
-    let resolver = app.dns_resolver()?;
+pub fn service(
+    resolver_settings: ResolverSettings,
+    hosts: Hosts,
+    providers: HashMap<String, Arc<dyn Discovery>>,
+    policies: Policies,
+) -> Result<(Service<HttpAdminApp>, Registry), svcs::Error> {
+    let app = HttpAdminApp::new(resolver_settings, hosts, providers, policies)?;
+    let registry = app.registry();
     let svc = Service::new("Admin Service HTTP".to_string(), app);
-    Ok((svc, resolver))
+    Ok((svc, registry))
 }
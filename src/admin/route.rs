@@ -1,30 +1,67 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
 
 use axum::{
     extract::State,
     routing::{get, post},
     Json, Router,
 };
-use hyper::StatusCode;
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    Method, StatusCode,
+};
+use ipnet::IpNet;
+use pingora::http::RequestHeader;
+use prometheus::TextEncoder;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, RwLock};
 
-use crate::svcs::{Op, UpstreamsHealthCheck};
+use crate::{
+    lb::{
+        evaluate_url_rules, AuthMethod, AuthRule, CorsPolicy, DryRunReport, Fallback, Fallbacks,
+        IpAccessList, IpRules, Maintenance, OidcConfig, PageTemplate, Policies, RateLimit,
+        RateLimitKey, SitePages, UrlAction, UrlOutcome, UrlRule, DEFAULT_SITE, LB, MAX_WINDOW_SECS,
+    },
+    svcs::{
+        normalize_domain, parse_authority, BackendState, ConcurrencyLimit, Discovery, DomainConfig,
+        HeaderAction, HeaderRules, Hosts, HostsEntry, Op, ResolverProfile, ResolverSettings,
+        ResponseRewrite, Timeouts, UpstreamsHealthCheck, DEFAULT_PROVIDER,
+    },
+};
 
 #[derive(Clone)]
 pub struct RouteState {
     add_domain_queen: broadcast::Sender<Op>,
     backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+    domains: Arc<RwLock<HashMap<String, Arc<DomainConfig>>>>,
+    resolver_profiles: Arc<RwLock<HashMap<String, ResolverProfile>>>,
+    hosts: Arc<RwLock<Hosts>>,
+    providers: Arc<RwLock<HashMap<String, Arc<dyn Discovery>>>>,
+    policies: Policies,
 }
 
 impl RouteState {
     pub fn new(
         add_domain_queen: broadcast::Sender<Op>,
         backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+        domains: Arc<RwLock<HashMap<String, Arc<DomainConfig>>>>,
+        resolver_profiles: Arc<RwLock<HashMap<String, ResolverProfile>>>,
+        hosts: Arc<RwLock<Hosts>>,
+        providers: Arc<RwLock<HashMap<String, Arc<dyn Discovery>>>>,
+        policies: Policies,
     ) -> Self {
         Self {
             add_domain_queen,
             backgrounds,
+            domains,
+            resolver_profiles,
+            hosts,
+            providers,
+            policies,
         }
     }
 }
@@ -36,6 +73,45 @@ pub fn routes(state: RouteState) -> Router {
             "/domain",
             post(add_domain).delete(del_domain).get(get_domains),
         )
+        .route(
+            "/resolver",
+            post(add_resolver).delete(del_resolver).get(get_resolvers),
+        )
+        .route("/hosts", post(add_hosts).delete(del_hosts).get(get_hosts))
+        .route("/discovery", get(get_discovery))
+        .route(
+            "/domain/backend",
+            post(set_backend_override)
+                .delete(clear_backend_override)
+                .get(get_backend_overrides),
+        )
+        .route("/pages", post(set_page).delete(del_page).get(get_pages))
+        .route(
+            "/maintenance",
+            post(set_maintenance).delete(del_maintenance),
+        )
+        .route("/fallback", post(set_fallback).get(get_fallback))
+        .route(
+            "/rules",
+            post(set_url_rules).delete(del_url_rules).get(get_url_rules),
+        )
+        .route("/rules/test", post(test_url_rules))
+        .route("/dry-run", post(dry_run))
+        .route("/metrics", get(metrics))
+        .route(
+            "/access",
+            post(set_ip_rules).delete(del_ip_rules).get(get_ip_access),
+        )
+        .route("/access/trusted", post(set_trusted_proxies))
+        .route(
+            "/ratelimit",
+            post(set_rate_limits)
+                .delete(del_rate_limits)
+                .get(get_rate_limits),
+        )
+        .route("/auth", post(set_auth).delete(del_auth).get(get_auth))
+        .route("/oidc", post(set_oidc).delete(del_oidc).get(get_oidc))
+        .route("/cors", post(set_cors).delete(del_cors).get(get_cors))
         .with_state(state)
 }
 
@@ -50,33 +126,1256 @@ struct ParamsDomain {
 
 async fn add_domain(
     State(state): State<RouteState>,
-    Json(param): Json<ParamsDomain>,
-) -> &'static str {
-    state.add_domain_queen.send(Op::Add(param.domain)).unwrap();
-    "ok"
+    Json(mut param): Json<DomainConfig>,
+) -> Result<&'static str, (StatusCode, String)> {
+    param.domain = domain_of(&param.domain)?;
+    if let Some(host) = &param.upstream_host {
+        if HeaderValue::from_str(host).is_err() || parse_authority(host).is_err() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid upstream host {host}"),
+            ));
+        }
+    }
+    if let Some(rewrite) = &mut param.rewrite {
+        check_rewrite(rewrite)?;
+    }
+    check_headers(&param.headers)?;
+    check_timeouts(&param.timeouts)?;
+    if let Some(limit) = &param.concurrency {
+        check_concurrency(limit)?;
+    }
+    if let Some(target) = &param.alias_of {
+        let target = domain_of(target)?;
+        check_alias(&state, &param.domain, &target).await?;
+        param.alias_of = Some(target);
+    }
+    let provider = param.provider.as_deref().unwrap_or(DEFAULT_PROVIDER);
+    if !state.providers.read().await.contains_key(provider) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Discovery provider {provider} not found"),
+        ));
+    }
+    if let Some(name) = &param.resolver {
+        if !state.resolver_profiles.read().await.contains_key(name) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Resolver profile {name} not found"),
+            ));
+        }
+    }
+    state.add_domain_queen.send(Op::Add(param)).unwrap();
+    Ok("ok")
 }
 
 async fn del_domain(
     State(state): State<RouteState>,
     Json(param): Json<ParamsDomain>,
-) -> &'static str {
-    state.add_domain_queen.send(Op::Del(param.domain)).unwrap();
-    "ok"
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    state
+        .add_domain_queen
+        .send(Op::Del(domain.clone()))
+        .unwrap();
+    state.policies.concurrency.remove(&domain);
+    Ok("ok")
+}
+
+/// 检查同时处理的请求数的限制及自适应的范围
+/// pingora 对响应头和响应体使用同一个读超时，`first_byte_ms` 与 `read_idle_ms` 不能设置为不同的值
+fn check_timeouts(timeouts: &Timeouts) -> Result<(), (StatusCode, String)> {
+    match (timeouts.first_byte_ms, timeouts.read_idle_ms) {
+        (Some(first_byte), Some(read_idle)) if first_byte != read_idle => Err((
+            StatusCode::BAD_REQUEST,
+            "first_byte_ms and read_idle_ms share the upstream read timeout and must be equal"
+                .to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_concurrency(limit: &ConcurrencyLimit) -> Result<(), (StatusCode, String)> {
+    let invalid = |reason: &str| Err((StatusCode::BAD_REQUEST, reason.to_string()));
+    if limit.max_in_flight == 0 {
+        return invalid("Concurrency max_in_flight must be greater than 0");
+    }
+    let Some(adaptive) = &limit.adaptive else {
+        return Ok(());
+    };
+    if adaptive.min_limit == 0 || adaptive.min_limit > adaptive.max_limit {
+        return invalid("Concurrency min_limit must be between 1 and max_limit");
+    }
+    if !(adaptive.backoff > 0.0 && adaptive.backoff < 1.0) {
+        return invalid("Concurrency backoff must be between 0 and 1");
+    }
+    Ok(())
+}
+
+/// 规范化需要改写的后端域名，检查协议及路径前缀
+fn check_rewrite(rewrite: &mut ResponseRewrite) -> Result<(), (StatusCode, String)> {
+    for host in &mut rewrite.hosts {
+        *host = domain_of(host)?;
+    }
+    if let Some(scheme) = &rewrite.scheme {
+        if scheme != "http" && scheme != "https" {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid rewrite scheme {scheme}"),
+            ));
+        }
+    }
+    if let Some(prefix) = &rewrite.path_prefix {
+        if !prefix.upstream.starts_with('/') || !prefix.client.starts_with('/') {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Rewrite path prefix must start with /".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 检查头改写规则中的名称，值为模板，替换后不合法时在请求时跳过
+fn check_headers(rules: &HeaderRules) -> Result<(), (StatusCode, String)> {
+    for name in rules.actions().flat_map(HeaderAction::names) {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid header name {name}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 别名只能指向不是别名的其它域名，已被别名指向的域名不能再成为别名
+async fn check_alias(
+    state: &RouteState,
+    domain: &str,
+    target: &str,
+) -> Result<(), (StatusCode, String)> {
+    if domain == target {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Domain {domain} can not be an alias of itself"),
+        ));
+    }
+    let domains = state.domains.read().await;
+    if domains.get(target).is_some_and(|c| c.alias_of.is_some()) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Target {target} is an alias"),
+        ));
+    }
+    if let Some(alias) = domains
+        .values()
+        .find(|c| c.alias_of.as_deref() == Some(domain))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Domain {domain} is the target of alias {}", alias.domain),
+        ));
+    }
+    Ok(())
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct DomainAddress {
     domain: String,
     address: Vec<String>,
+    /// 来自静态解析表的地址
+    hosts_override: Vec<String>,
+    /// 使用该域名后端集合的别名
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<String>,
 }
 
 async fn get_domains(State(state): State<RouteState>) -> (StatusCode, Json<Vec<DomainAddress>>) {
+    let configs = state.domains.read().await;
     let mut domains = Vec::new();
     for (domain, background) in state.backgrounds.read().await.iter() {
+        let mut aliases: Vec<String> = configs
+            .values()
+            .filter(|c| c.alias_of.as_ref() == Some(domain))
+            .map(|c| c.domain.clone())
+            .collect();
+        aliases.sort();
         domains.push(DomainAddress {
             domain: domain.clone(),
             address: background.get_backends(),
+            hosts_override: background.get_hosts_overrides(),
+            aliases,
         });
     }
     (StatusCode::OK, Json(domains))
 }
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsResolver {
+    name: String,
+    #[serde(flatten)]
+    settings: ResolverSettings,
+}
+
+async fn add_resolver(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsResolver>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let profile = ResolverProfile::try_from(param.settings)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    state
+        .resolver_profiles
+        .write()
+        .await
+        .insert(param.name, profile);
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsResolverName {
+    name: String,
+}
+
+async fn del_resolver(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsResolverName>,
+) -> Result<&'static str, (StatusCode, String)> {
+    match state.resolver_profiles.write().await.remove(&param.name) {
+        Some(_) => Ok("ok"),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            format!("Resolver profile {} not found", param.name),
+        )),
+    }
+}
+
+async fn get_resolvers(
+    State(state): State<RouteState>,
+) -> (StatusCode, Json<HashMap<String, ResolverSettings>>) {
+    let profiles = state
+        .resolver_profiles
+        .read()
+        .await
+        .iter()
+        .map(|(name, profile)| (name.clone(), profile.settings.clone()))
+        .collect();
+    (StatusCode::OK, Json(profiles))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DiscoveryStatus {
+    /// 最近的错误，例如文件解析失败
+    errors: Vec<String>,
+}
+
+async fn get_discovery(
+    State(state): State<RouteState>,
+) -> (StatusCode, Json<HashMap<String, DiscoveryStatus>>) {
+    let providers = state
+        .providers
+        .read()
+        .await
+        .iter()
+        .map(|(name, provider)| {
+            let errors = provider.errors();
+            (name.clone(), DiscoveryStatus { errors })
+        })
+        .collect();
+    (StatusCode::OK, Json(providers))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsHosts {
+    name: String,
+    ips: Vec<IpAddr>,
+    /// 有效期，单位秒，为空时永久有效
+    expire: Option<u64>,
+}
+
+async fn add_hosts(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsHosts>,
+) -> Result<&'static str, (StatusCode, String)> {
+    if param.ips.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "ips must not be empty".to_string()));
+    }
+    let name = domain_of(&param.name)?;
+    state
+        .hosts
+        .write()
+        .await
+        .insert(name, param.ips, param.expire.map(Duration::from_secs));
+    state.add_domain_queen.send(Op::Refresh).unwrap();
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsHostsName {
+    name: String,
+}
+
+async fn del_hosts(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsHostsName>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let name = domain_of(&param.name)?;
+    if !state.hosts.write().await.remove(&name) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Hosts entry {name} not found"),
+        ));
+    }
+    state.add_domain_queen.send(Op::Refresh).unwrap();
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct HostsView {
+    ips: Vec<IpAddr>,
+    /// 过期时间，unix 时间戳，单位秒
+    expires_at: Option<u64>,
+}
+
+impl From<HostsEntry> for HostsView {
+    fn from(entry: HostsEntry) -> Self {
+        Self {
+            ips: entry.ips,
+            expires_at: entry
+                .expires_at
+                .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        }
+    }
+}
+
+async fn get_hosts(
+    State(state): State<RouteState>,
+) -> (StatusCode, Json<HashMap<String, HostsView>>) {
+    let hosts = state
+        .hosts
+        .write()
+        .await
+        .entries()
+        .into_iter()
+        .map(|(name, entry)| (name, entry.into()))
+        .collect();
+    (StatusCode::OK, Json(hosts))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsBackendOverride {
+    domain: String,
+    backend: SocketAddr,
+    state: BackendState,
+    /// 覆盖的有效期，单位秒，为空时永久有效
+    expire: Option<u64>,
+}
+
+async fn set_backend_override(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsBackendOverride>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let backgrounds = state.backgrounds.read().await;
+    let background = find_backend(&backgrounds, &param.domain, &param.backend)?;
+    background.set_override(
+        param.backend,
+        param.state,
+        param.expire.map(Duration::from_secs),
+    );
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsBackend {
+    domain: String,
+    backend: SocketAddr,
+}
+
+async fn clear_backend_override(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsBackend>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let backgrounds = state.backgrounds.read().await;
+    let background = find_backend(&backgrounds, &param.domain, &param.backend)?;
+    if !background.clear_override(&param.backend) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Backend {} has no override", param.backend),
+        ));
+    }
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BackendOverrideView {
+    domain: String,
+    backend: SocketAddr,
+    state: BackendState,
+    /// 过期时间，unix 时间戳，单位秒
+    expires_at: Option<u64>,
+}
+
+async fn get_backend_overrides(
+    State(state): State<RouteState>,
+) -> (StatusCode, Json<Vec<BackendOverrideView>>) {
+    let mut overrides = Vec::new();
+    for (domain, background) in state.backgrounds.read().await.iter() {
+        for (backend, o) in background.overrides() {
+            overrides.push(BackendOverrideView {
+                domain: domain.clone(),
+                backend,
+                state: o.state,
+                expires_at: o
+                    .expires_at
+                    .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+            });
+        }
+    }
+    (StatusCode::OK, Json(overrides))
+}
+
+fn find_backend<'a>(
+    backgrounds: &'a HashMap<String, Arc<UpstreamsHealthCheck>>,
+    domain: &str,
+    backend: &SocketAddr,
+) -> Result<&'a Arc<UpstreamsHealthCheck>, (StatusCode, String)> {
+    let domain = domain_of(domain)?;
+    let background = backgrounds
+        .get(&domain)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Domain {domain} not found")))?;
+    if !background.has_backend(backend) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Backend {backend} not found in domain {domain}"),
+        ));
+    }
+    Ok(background)
+}
+
+/// 域名为空时为所有域名的默认配置
+fn site_of(domain: Option<String>) -> Result<String, (StatusCode, String)> {
+    domain.map_or_else(|| Ok(DEFAULT_SITE.to_string()), |domain| domain_of(&domain))
+}
+
+/// 与代理匹配请求的 Host 使用相同的规则规范化域名
+fn domain_of(domain: &str) -> Result<String, (StatusCode, String)> {
+    normalize_domain(domain).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+fn check_page(page: &PageTemplate) -> Result<(), (StatusCode, String)> {
+    HeaderValue::from_str(&page.content_type).map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid content type {}", page.content_type),
+        )
+    })?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsPage {
+    /// 为空时对所有域名生效
+    domain: Option<String>,
+    status: u16,
+    #[serde(flatten)]
+    page: PageTemplate,
+}
+
+async fn set_page(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsPage>,
+) -> Result<&'static str, (StatusCode, String)> {
+    if !(400..=599).contains(&param.status) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid error status {}", param.status),
+        ));
+    }
+    check_page(&param.page)?;
+    state
+        .policies
+        .pages
+        .write()
+        .await
+        .entry(site_of(param.domain)?)
+        .or_default()
+        .errors
+        .insert(param.status, param.page);
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsPageStatus {
+    domain: Option<String>,
+    status: u16,
+}
+
+async fn del_page(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsPageStatus>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let site = site_of(param.domain)?;
+    let mut pages = state.policies.pages.write().await;
+    let removed = pages
+        .get_mut(&site)
+        .and_then(|pages| pages.errors.remove(&param.status));
+    if removed.is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Page {} of {site} not found", param.status),
+        ));
+    }
+    pages.retain(|_, pages| !pages.is_empty());
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PagesView {
+    domain: String,
+    #[serde(flatten)]
+    pages: SitePages,
+}
+
+async fn get_pages(State(state): State<RouteState>) -> (StatusCode, Json<Vec<PagesView>>) {
+    let pages = state
+        .policies
+        .pages
+        .read()
+        .await
+        .iter()
+        .map(|(domain, pages)| PagesView {
+            domain: domain.clone(),
+            pages: pages.clone(),
+        })
+        .collect();
+    (StatusCode::OK, Json(pages))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsMaintenance {
+    /// 为空时所有域名进入维护模式
+    domain: Option<String>,
+    #[serde(flatten)]
+    maintenance: Maintenance,
+}
+
+async fn set_maintenance(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsMaintenance>,
+) -> Result<&'static str, (StatusCode, String)> {
+    if !(200..=599).contains(&param.maintenance.status) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid maintenance status {}", param.maintenance.status),
+        ));
+    }
+    if let Some(page) = &param.maintenance.page {
+        check_page(page)?;
+    }
+    state
+        .policies
+        .pages
+        .write()
+        .await
+        .entry(site_of(param.domain)?)
+        .or_default()
+        .maintenance = Some(param.maintenance);
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsSite {
+    domain: Option<String>,
+}
+
+async fn del_maintenance(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsSite>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let site = site_of(param.domain)?;
+    let mut pages = state.policies.pages.write().await;
+    let removed = pages
+        .get_mut(&site)
+        .and_then(|pages| pages.maintenance.take());
+    if removed.is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Maintenance of {site} not found"),
+        ));
+    }
+    pages.retain(|_, pages| !pages.is_empty());
+    Ok("ok")
+}
+
+/// 检查处理方式中的状态码及地址是否合法
+fn check_fallback(fallback: &mut Fallback) -> Result<(), (StatusCode, String)> {
+    match fallback {
+        Fallback::Error => Ok(()),
+        Fallback::Upstream { domain } => {
+            *domain = domain_of(domain)?;
+            Ok(())
+        }
+        Fallback::Redirect {
+            location, status, ..
+        } => {
+            if !(300..=399).contains(&*status) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid redirect status {status}"),
+                ));
+            }
+            HeaderValue::from_str(location).map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid redirect location {location}"),
+                )
+            })?;
+            Ok(())
+        }
+        Fallback::Static { status, page } => {
+            if !(200..=599).contains(&*status) {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid static status {status}"),
+                ));
+            }
+            check_page(page)
+        }
+    }
+}
+
+async fn set_fallback(
+    State(state): State<RouteState>,
+    Json(mut param): Json<Fallbacks>,
+) -> Result<&'static str, (StatusCode, String)> {
+    check_fallback(&mut param.unknown)?;
+    check_fallback(&mut param.missing)?;
+    *state.policies.fallbacks.write().await = param;
+    Ok("ok")
+}
+
+async fn get_fallback(State(state): State<RouteState>) -> (StatusCode, Json<Fallbacks>) {
+    let fallbacks = state.policies.fallbacks.read().await.clone();
+    (StatusCode::OK, Json(fallbacks))
+}
+
+/// 重定向只允许 301、302、303、307、308，改写后的路径及前缀以 `/` 开头
+fn check_url_rules(rules: &[UrlRule]) -> Result<(), (StatusCode, String)> {
+    let invalid =
+        |i: usize, reason: String| (StatusCode::BAD_REQUEST, format!("Rule {i}: {reason}"));
+    for (i, rule) in rules.iter().enumerate() {
+        match &rule.action {
+            UrlAction::Rewrite { to } => {
+                // 替换整个路径或从路径开头匹配时，替换结果需以 `/` 或捕获组开头
+                let from_start = match &rule.path {
+                    Some(pattern) => pattern.as_str().starts_with('^'),
+                    None => true,
+                };
+                if from_start && !to.starts_with(['/', '$']) {
+                    return Err(invalid(i, format!("Rewrite path {to} must start with /")));
+                }
+            }
+            UrlAction::StripPrefix { prefix } | UrlAction::AddPrefix { prefix }
+                if !prefix.starts_with('/') =>
+            {
+                return Err(invalid(i, format!("Prefix {prefix} must start with /")));
+            }
+            UrlAction::Redirect {
+                location, status, ..
+            } => {
+                if ![301, 302, 303, 307, 308].contains(status) {
+                    return Err(invalid(i, format!("Invalid redirect status {status}")));
+                }
+                if HeaderValue::from_str(location).is_err() {
+                    return Err(invalid(i, format!("Invalid redirect location {location}")));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsUrlRules {
+    /// 请求的 Host 或域名
+    domain: String,
+    /// 按顺序执行，替换已有的规则
+    rules: Vec<UrlRule>,
+}
+
+async fn set_url_rules(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsUrlRules>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    check_url_rules(&param.rules)?;
+    state
+        .policies
+        .url_rules
+        .write()
+        .await
+        .insert(domain, param.rules);
+    Ok("ok")
+}
+
+async fn del_url_rules(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsDomain>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    if state
+        .policies
+        .url_rules
+        .write()
+        .await
+        .remove(&domain)
+        .is_none()
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Rules of {domain} not found"),
+        ));
+    }
+    Ok("ok")
+}
+
+async fn get_url_rules(State(state): State<RouteState>) -> (StatusCode, Json<Vec<ParamsUrlRules>>) {
+    let rules = state
+        .policies
+        .url_rules
+        .read()
+        .await
+        .iter()
+        .map(|(domain, rules)| ParamsUrlRules {
+            domain: domain.clone(),
+            rules: rules.clone(),
+        })
+        .collect();
+    (StatusCode::OK, Json(rules))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsUrlTest {
+    /// 请求的 Host
+    domain: String,
+    /// 请求的路径及查询参数
+    path: String,
+    /// 测试的规则，为空时使用已保存的规则
+    rules: Option<Vec<UrlRule>>,
+}
+
+#[derive(Debug, Serialize)]
+struct UrlTestView {
+    /// 发送给后端的路径及查询参数
+    uri: String,
+    #[serde(flatten)]
+    outcome: UrlOutcome,
+}
+
+/// 返回地址规则执行的结果，不发送请求
+async fn test_url_rules(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsUrlTest>,
+) -> Result<Json<UrlTestView>, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    let rules = match param.rules {
+        Some(rules) => {
+            check_url_rules(&rules)?;
+            rules
+        }
+        None => state
+            .policies
+            .url_rules
+            .read()
+            .await
+            .get(&domain)
+            .cloned()
+            .unwrap_or_default(),
+    };
+    let (path, query) = match param.path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (param.path.as_str(), None),
+    };
+    if !path.starts_with('/') {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Path {path} must start with /"),
+        ));
+    }
+    let outcome = evaluate_url_rules(&rules, &domain, path, query);
+    Ok(Json(UrlTestView {
+        uri: outcome.uri(),
+        outcome,
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsDryRun {
+    #[serde(default = "default_dry_run_method")]
+    method: String,
+    /// 请求的 Host，为空时模拟没有 Host 的请求
+    host: Option<String>,
+    /// 请求的路径及查询参数，也可以是 absolute-form 的完整地址
+    #[serde(default = "default_dry_run_path")]
+    path: String,
+    /// 其它请求头
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    client_ip: Option<IpAddr>,
+    /// 是否模拟 HTTPS 请求
+    #[serde(default)]
+    tls: bool,
+}
+
+fn default_dry_run_method() -> String {
+    "GET".to_string()
+}
+
+fn default_dry_run_path() -> String {
+    "/".to_string()
+}
+
+/// 模拟一个请求，返回匹配的域名及规则、发送给后端的请求及将要选择的后端，不发送请求
+async fn dry_run(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsDryRun>,
+) -> Result<Json<DryRunReport>, (StatusCode, String)> {
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e);
+    let method = Method::from_bytes(param.method.as_bytes())
+        .map_err(|e| bad_request(format!("Invalid method {}: {e}", param.method)))?;
+    let mut req = RequestHeader::build(method, param.path.as_bytes(), None)
+        .map_err(|e| bad_request(format!("Invalid path {}: {e}", param.path)))?;
+    for (name, value) in &param.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| bad_request(format!("Invalid header name {name}: {e}")))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| bad_request(format!("Invalid header value {value}: {e}")))?;
+        req.append_header(name, value)
+            .map_err(|e| bad_request(e.to_string()))?;
+    }
+    if let Some(host) = &param.host {
+        let value = HeaderValue::from_str(host)
+            .map_err(|e| bad_request(format!("Invalid host {host}: {e}")))?;
+        req.insert_header(header::HOST, value)
+            .map_err(|e| bad_request(e.to_string()))?;
+    }
+    let lb = LB {
+        backgrounds: state.backgrounds.clone(),
+        domains: state.domains.clone(),
+        policies: state.policies.clone(),
+    };
+    let scheme = if param.tls { "https" } else { "http" };
+    Ok(Json(lb.dry_run(&req, param.client_ip, scheme).await))
+}
+
+/// 请求数及窗口长度至少为 1，请求头及 Cookie 的名称合法
+fn check_rate_limits(rules: &[RateLimit]) -> Result<(), (StatusCode, String)> {
+    let invalid =
+        |i: usize, reason: String| (StatusCode::BAD_REQUEST, format!("Rule {i}: {reason}"));
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.limit == 0 || rule.window_secs == 0 || rule.burst == Some(0) {
+            return Err(invalid(
+                i,
+                "limit, window_secs and burst must be positive".to_string(),
+            ));
+        }
+        if rule.window_secs > MAX_WINDOW_SECS {
+            return Err(invalid(
+                i,
+                format!("window_secs must not exceed {MAX_WINDOW_SECS}"),
+            ));
+        }
+        let name = match &rule.key {
+            RateLimitKey::Ip => continue,
+            RateLimitKey::Header { name } | RateLimitKey::ApiKey { header: name, .. } => name,
+            RateLimitKey::Cookie { name } => {
+                if name.is_empty() || name.contains([';', '=', ' ']) {
+                    return Err(invalid(i, format!("Invalid cookie name {name}")));
+                }
+                continue;
+            }
+        };
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(invalid(i, format!("Invalid header name {name}")));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsRateLimits {
+    /// 请求的 Host 或域名
+    domain: String,
+    /// 替换已有的规则，计数器重新开始
+    rules: Vec<RateLimit>,
+}
+
+async fn set_rate_limits(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsRateLimits>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    check_rate_limits(&param.rules)?;
+    state.policies.rate_limits.set(domain, param.rules).await;
+    Ok("ok")
+}
+
+async fn del_rate_limits(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsDomain>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    if !state.policies.rate_limits.remove(&domain).await {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Rate limits of {domain} not found"),
+        ));
+    }
+    Ok("ok")
+}
+
+async fn get_rate_limits(
+    State(state): State<RouteState>,
+) -> (StatusCode, Json<Vec<ParamsRateLimits>>) {
+    let rules = state
+        .policies
+        .rate_limits
+        .rules()
+        .await
+        .into_iter()
+        .map(|(domain, rules)| ParamsRateLimits { domain, rules })
+        .collect();
+    (StatusCode::OK, Json(rules))
+}
+
+/// Prometheus 格式的指标
+async fn metrics() -> Result<String, (StatusCode, String)> {
+    let mut buffer = String::new();
+    TextEncoder::new()
+        .encode_utf8(&prometheus::gather(), &mut buffer)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(buffer)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsIpRules {
+    /// 请求的 Host 或域名，为空时为全局列表
+    domain: Option<String>,
+    #[serde(flatten)]
+    rules: IpRules,
+}
+
+/// 替换全局或域名的地址列表，不影响从文件加载的列表
+async fn set_ip_rules(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsIpRules>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let mut runtime = state.policies.ip_access.runtime.write().await;
+    match param.domain {
+        Some(domain) => {
+            let domain = domain_of(&domain)?;
+            runtime.domains.insert(domain, param.rules);
+        }
+        None => runtime.global = param.rules,
+    }
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsIpScope {
+    /// 请求的 Host 或域名，为空时为全局列表
+    domain: Option<String>,
+}
+
+async fn del_ip_rules(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsIpScope>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let mut runtime = state.policies.ip_access.runtime.write().await;
+    let Some(domain) = param.domain else {
+        runtime.global = IpRules::default();
+        return Ok("ok");
+    };
+    let domain = domain_of(&domain)?;
+    if runtime.domains.remove(&domain).is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Access rules of {domain} not found"),
+        ));
+    }
+    Ok("ok")
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsTrustedProxies {
+    proxies: Vec<IpNet>,
+}
+
+/// 替换可信的负载均衡地址，不影响从文件加载的地址
+async fn set_trusted_proxies(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsTrustedProxies>,
+) -> &'static str {
+    state
+        .policies
+        .ip_access
+        .runtime
+        .write()
+        .await
+        .trusted_proxies = param.proxies;
+    "ok"
+}
+
+#[derive(Debug, Serialize)]
+struct IpAccessView {
+    /// 通过管理 API 设置的列表
+    runtime: IpAccessList,
+    /// 从文件加载的列表
+    file: IpAccessList,
+}
+
+async fn get_ip_access(State(state): State<RouteState>) -> (StatusCode, Json<IpAccessView>) {
+    let access = &state.policies.ip_access;
+    let view = IpAccessView {
+        runtime: access.runtime.read().await.clone(),
+        file: access.file.read().await.clone(),
+    };
+    (StatusCode::OK, Json(view))
+}
+
+/// 转发的请求头及 API key 所在的请求头的名称合法，API key 不为空
+fn check_auth_rules(rules: &[AuthRule]) -> Result<(), (StatusCode, String)> {
+    let invalid =
+        |i: usize, reason: String| (StatusCode::BAD_REQUEST, format!("Rule {i}: {reason}"));
+    for (i, rule) in rules.iter().enumerate() {
+        for name in rule.forward.keys() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(invalid(i, format!("Invalid header name {name}")));
+            }
+        }
+        if let AuthMethod::ApiKey { header, keys, .. } = &rule.method {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                return Err(invalid(i, format!("Invalid header name {header}")));
+            }
+            if keys.is_empty() || keys.iter().any(|entry| entry.key.is_empty()) {
+                return Err(invalid(i, "API keys must not be empty".to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsAuth {
+    /// 请求的 Host 或域名
+    domain: String,
+    /// 替换已有的规则，按顺序使用第一个匹配路径的规则
+    rules: Vec<AuthRule>,
+}
+
+/// 加载 htpasswd 文件、密钥及 JWKS 后替换域名的认证规则
+async fn set_auth(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsAuth>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    check_auth_rules(&param.rules)?;
+    state
+        .policies
+        .auth
+        .set(domain, param.rules)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok("ok")
+}
+
+async fn del_auth(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsDomain>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    if !state.policies.auth.remove(&domain).await {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("Auth rules of {domain} not found"),
+        ));
+    }
+    Ok("ok")
+}
+
+async fn get_auth(State(state): State<RouteState>) -> (StatusCode, Json<Vec<ParamsAuth>>) {
+    let rules = state
+        .policies
+        .auth
+        .rules()
+        .await
+        .into_iter()
+        .map(|(domain, rules)| ParamsAuth {
+            domain,
+            rules: rules.iter().map(AuthRule::redacted).collect(),
+        })
+        .collect();
+    (StatusCode::OK, Json(rules))
+}
+
+/// 路径以 `/` 开头，Cookie 名称合法，密钥足够长，转发的请求头名称合法
+fn check_oidc(config: &OidcConfig) -> Result<(), (StatusCode, String)> {
+    let invalid = |reason: String| (StatusCode::BAD_REQUEST, reason);
+    for path in [&config.callback_path, &config.logout_path] {
+        if !path.starts_with('/') {
+            return Err(invalid(format!("Path {path} must start with /")));
+        }
+    }
+    let name = &config.cookie_name;
+    if name.is_empty()
+        || !name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+    {
+        return Err(invalid(format!("Invalid cookie name {name}")));
+    }
+    if config.cookie_secret.len() < 32 {
+        return Err(invalid(
+            "cookie_secret must be at least 32 characters".to_string(),
+        ));
+    }
+    if !config.scopes.iter().any(|scope| scope == "openid") {
+        return Err(invalid("scopes must include openid".to_string()));
+    }
+    for name in config.forward.keys() {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(invalid(format!("Invalid header name {name}")));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsOidc {
+    /// 请求的 Host 或域名
+    domain: String,
+    #[serde(flatten)]
+    config: OidcConfig,
+}
+
+/// 获取 IdP 的端点后替换域名的 OpenID Connect 配置
+async fn set_oidc(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsOidc>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    check_oidc(&param.config)?;
+    state
+        .policies
+        .oidc
+        .set(domain, param.config)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok("ok")
+}
+
+async fn del_oidc(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsDomain>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    if !state.policies.oidc.remove(&domain).await {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("OIDC config of {domain} not found"),
+        ));
+    }
+    Ok("ok")
+}
+
+async fn get_oidc(State(state): State<RouteState>) -> (StatusCode, Json<Vec<ParamsOidc>>) {
+    let configs = state
+        .policies
+        .oidc
+        .configs()
+        .await
+        .into_iter()
+        .map(|(domain, config)| ParamsOidc {
+            domain,
+            config: config.redacted(),
+        })
+        .collect();
+    (StatusCode::OK, Json(configs))
+}
+
+/// 来源为 `*` 或不带路径的地址，`*` 不能携带凭证，方法及请求头的名称合法
+fn check_cors(policy: &CorsPolicy) -> Result<(), (StatusCode, String)> {
+    let invalid = |reason: String| (StatusCode::BAD_REQUEST, reason);
+    if policy.credentials && policy.origins.iter().any(|origin| origin == "*") {
+        return Err(invalid(
+            "Origin * can not be used with credentials".to_string(),
+        ));
+    }
+    for origin in &policy.origins {
+        let valid = origin == "*"
+            || origin.parse::<hyper::Uri>().is_ok_and(|uri| {
+                uri.scheme().is_some() && uri.path() == "/" && !origin.ends_with('/')
+            });
+        if !valid {
+            return Err(invalid(format!("Invalid origin {origin}")));
+        }
+    }
+    for method in &policy.methods {
+        if method != "*" && Method::from_bytes(method.as_bytes()).is_err() {
+            return Err(invalid(format!("Invalid method {method}")));
+        }
+    }
+    for name in policy.headers.iter().chain(&policy.expose_headers) {
+        if name != "*" && HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(invalid(format!("Invalid header name {name}")));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ParamsCors {
+    /// 请求的 Host 或域名
+    domain: String,
+    #[serde(flatten)]
+    policy: CorsPolicy,
+}
+
+async fn set_cors(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsCors>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    check_cors(&param.policy)?;
+    state.policies.cors.set(domain, param.policy).await;
+    Ok("ok")
+}
+
+async fn del_cors(
+    State(state): State<RouteState>,
+    Json(param): Json<ParamsDomain>,
+) -> Result<&'static str, (StatusCode, String)> {
+    let domain = domain_of(&param.domain)?;
+    if !state.policies.cors.remove(&domain).await {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("CORS policy of {domain} not found"),
+        ));
+    }
+    Ok("ok")
+}
+
+async fn get_cors(State(state): State<RouteState>) -> (StatusCode, Json<Vec<ParamsCors>>) {
+    let policies = state
+        .policies
+        .cors
+        .policies()
+        .await
+        .into_iter()
+        .map(|(domain, policy)| ParamsCors { domain, policy })
+        .collect();
+    (StatusCode::OK, Json(policies))
+}
@@ -0,0 +1,607 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::header;
+use pingora::http::RequestHeader;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+
+use super::{
+    htpasswd::{self, Htpasswd},
+    jwt::{JwtAuth, JwtVerifier},
+};
+use crate::svcs::PathPattern;
+
+/// 认证规则，按顺序使用第一个匹配路径的规则
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuthRule {
+    /// 匹配路径的正则表达式，为空时匹配所有请求
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathPattern>,
+    #[serde(flatten)]
+    pub method: AuthMethod,
+    /// 认证后还需满足的声明，声明为数组时包含期望值即可，期望值为数组时满足其中之一即可
+    /// 不满足时返回 403
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub require: BTreeMap<String, Value>,
+    /// 转发给后端的请求头名称到声明名称，嵌套的声明用 `.` 分隔
+    /// 客户端传入的同名请求头总是被删除
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub forward: BTreeMap<String, String>,
+}
+
+/// 认证方式，Basic 及 API key 的用户名或名称作为 `sub` 声明
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuthMethod {
+    /// 不需要认证，用于排除部分路径
+    None,
+    /// HTTP Basic，密码哈希来自 htpasswd 文件
+    Basic {
+        htpasswd: PathBuf,
+        #[serde(default = "default_realm")]
+        realm: String,
+    },
+    /// 请求头中的 API key，没有时使用查询参数
+    ApiKey {
+        #[serde(default = "default_api_key_header")]
+        header: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        query: Option<String>,
+        keys: Vec<ApiKey>,
+    },
+    /// `Authorization: Bearer` 中的 JWT
+    Jwt(JwtAuth),
+}
+
+/// 管理 API 返回配置时代替密钥的值
+pub const REDACTED: &str = "<redacted>";
+
+impl AuthRule {
+    /// 隐藏 API key 及 JWT 密钥后的规则，用于管理 API 返回配置
+    pub fn redacted(&self) -> Self {
+        let mut rule = self.clone();
+        match &mut rule.method {
+            AuthMethod::ApiKey { keys, .. } => {
+                for entry in keys {
+                    entry.key = REDACTED.to_string();
+                }
+            }
+            AuthMethod::Jwt(config) => {
+                if let Some(secret) = &mut config.secret {
+                    *secret = REDACTED.to_string();
+                }
+            }
+            AuthMethod::None | AuthMethod::Basic { .. } => {}
+        }
+        rule
+    }
+}
+
+fn default_realm() -> String {
+    "Restricted".to_string()
+}
+
+fn default_api_key_header() -> String {
+    "x-api-key".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ApiKey {
+    /// 作为 `sub` 声明转发给后端
+    pub name: String,
+    pub key: String,
+}
+
+/// 认证失败时的响应
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuthFailure {
+    /// 401 或 403
+    pub status: u16,
+    /// `WWW-Authenticate` 响应头
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub challenge: Option<String>,
+    pub reason: String,
+}
+
+/// 认证的结果
+#[derive(Debug, Clone)]
+pub struct AuthResult {
+    /// 匹配的规则的序号，没有匹配的规则时为空
+    pub rule: Option<usize>,
+    /// 认证通过时的 `sub` 声明
+    pub subject: Option<String>,
+    /// 转发给后端的请求头，值为空时只删除客户端传入的请求头
+    /// 所有规则转发的请求头都会删除，不论是否匹配
+    pub forward: Vec<(String, Option<String>)>,
+    /// 从发送给后端的查询参数中删除的 API key 参数
+    pub strip_query: Option<String>,
+    pub failure: Option<AuthFailure>,
+}
+
+enum Verifier {
+    None,
+    Basic(Htpasswd),
+    ApiKey,
+    Jwt(JwtVerifier),
+}
+
+struct Compiled {
+    rule: AuthRule,
+    verifier: Verifier,
+}
+
+/// 各域名的认证规则及加载好的 htpasswd 文件、密钥
+#[derive(Default)]
+pub struct AuthStore {
+    /// 键为请求的 Host 或域名
+    domains: RwLock<HashMap<String, Arc<Vec<Compiled>>>>,
+}
+
+impl AuthStore {
+    pub async fn rules(&self) -> HashMap<String, Vec<AuthRule>> {
+        self.domains
+            .read()
+            .await
+            .iter()
+            .map(|(domain, rules)| {
+                let rules = rules.iter().map(|compiled| compiled.rule.clone()).collect();
+                (domain.clone(), rules)
+            })
+            .collect()
+    }
+
+    /// 加载规则需要的文件及 JWKS 后替换域名的规则，任一规则加载失败时不修改
+    pub async fn set(&self, domain: String, rules: Vec<AuthRule>) -> Result<(), String> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for (i, rule) in rules.into_iter().enumerate() {
+            let verifier = match &rule.method {
+                AuthMethod::None => Verifier::None,
+                AuthMethod::Basic { htpasswd, .. } => {
+                    Verifier::Basic(Htpasswd::load(htpasswd).map_err(|e| format!("Rule {i}: {e}"))?)
+                }
+                AuthMethod::ApiKey { .. } => Verifier::ApiKey,
+                AuthMethod::Jwt(config) => Verifier::Jwt(
+                    JwtVerifier::new(config.clone())
+                        .await
+                        .map_err(|e| format!("Rule {i}: {e}"))?,
+                ),
+            };
+            compiled.push(Compiled { rule, verifier });
+        }
+        self.domains
+            .write()
+            .await
+            .insert(domain, Arc::new(compiled));
+        Ok(())
+    }
+
+    /// 删除域名的规则，返回是否存在
+    pub async fn remove(&self, domain: &str) -> bool {
+        self.domains.write().await.remove(domain).is_some()
+    }
+
+    /// 按请求的 Host 的规则认证，没有时使用处理请求的域名的规则，域名没有规则时返回空
+    pub async fn check(&self, names: [Option<&str>; 2], req: &RequestHeader) -> Option<AuthResult> {
+        let rules = {
+            let store = self.domains.read().await;
+            names
+                .into_iter()
+                .flatten()
+                .find_map(|name| store.get(name))?
+                .clone()
+        };
+        // 客户端可能伪造任一规则转发的请求头，先全部删除
+        let mut strip: Vec<&String> = rules
+            .iter()
+            .flat_map(|compiled| compiled.rule.forward.keys())
+            .collect();
+        strip.sort_unstable();
+        strip.dedup();
+        let mut result = AuthResult {
+            rule: None,
+            subject: None,
+            forward: strip.into_iter().map(|name| (name.clone(), None)).collect(),
+            strip_query: None,
+            failure: None,
+        };
+        let path = req.uri.path();
+        let Some((i, compiled)) = rules.iter().enumerate().find(|(_, compiled)| {
+            compiled
+                .rule
+                .path
+                .as_ref()
+                .map_or(true, |p| p.is_match(path))
+        }) else {
+            return Some(result);
+        };
+        let rule = &compiled.rule;
+        result.rule = Some(i);
+        if let AuthMethod::ApiKey { query, .. } = &rule.method {
+            result.strip_query = query.clone();
+        }
+        let claims = match compiled.authenticate(req).await {
+            Ok(claims) => claims,
+            Err(failure) => {
+                result.failure = Some(failure);
+                return Some(result);
+            }
+        };
+        match authorize(&claims, &rule.require, &rule.forward) {
+            Ok(forward) => {
+                result.subject = claim(&claims, "sub").and_then(claim_value);
+                result.forward.extend(forward);
+            }
+            Err(failure) => result.failure = Some(failure),
+        }
+        Some(result)
+    }
+}
+
+impl Compiled {
+    /// 认证请求，返回声明
+    async fn authenticate(&self, req: &RequestHeader) -> Result<Value, AuthFailure> {
+        match (&self.rule.method, &self.verifier) {
+            (AuthMethod::Basic { realm, .. }, Verifier::Basic(users)) => {
+                let challenge = format!("Basic realm=\"{}\"", realm.replace('"', "'"));
+                let unauthorized = |reason: &str| AuthFailure {
+                    status: 401,
+                    challenge: Some(challenge.clone()),
+                    reason: reason.to_string(),
+                };
+                let (user, password) =
+                    basic_credentials(req).ok_or_else(|| unauthorized("Missing credentials"))?;
+                // 未知的用户同样校验一次，响应时间不泄露用户是否存在
+                let (hash, known) = match users.hash_of(&user) {
+                    Some(hash) => (hash, true),
+                    None => (users.dummy_hash(), false),
+                };
+                let verified =
+                    tokio::task::spawn_blocking(move || htpasswd::verify(&password, &hash))
+                        .await
+                        .unwrap_or(false);
+                if !verified || !known {
+                    return Err(unauthorized("Invalid credentials"));
+                }
+                Ok(json!({ "sub": user }))
+            }
+            (
+                AuthMethod::ApiKey {
+                    header: name,
+                    query,
+                    keys,
+                },
+                Verifier::ApiKey,
+            ) => {
+                let unauthorized = |reason: &str| AuthFailure {
+                    status: 401,
+                    challenge: None,
+                    reason: reason.to_string(),
+                };
+                let key = req
+                    .headers
+                    .get(name.as_str())
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string)
+                    .or_else(|| query_param(req, query.as_deref()?))
+                    .ok_or_else(|| unauthorized("Missing API key"))?;
+                let found = keys
+                    .iter()
+                    .find(|entry| constant_time_eq(entry.key.as_bytes(), key.as_bytes()))
+                    .ok_or_else(|| unauthorized("Invalid API key"))?;
+                Ok(json!({ "sub": found.name }))
+            }
+            (AuthMethod::Jwt(_), Verifier::Jwt(verifier)) => {
+                let token = req
+                    .headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| bearer(v))
+                    .ok_or_else(|| AuthFailure {
+                        status: 401,
+                        challenge: Some("Bearer".to_string()),
+                        reason: "Missing bearer token".to_string(),
+                    })?;
+                verifier.verify(token).map_err(|reason| AuthFailure {
+                    status: 401,
+                    challenge: Some("Bearer error=\"invalid_token\"".to_string()),
+                    reason,
+                })
+            }
+            _ => Ok(json!({})),
+        }
+    }
+}
+
+/// 检查认证后的声明是否满足规则，满足时返回转发给后端的请求头，不满足时返回 403
+pub(super) fn authorize(
+    claims: &Value,
+    require: &BTreeMap<String, Value>,
+    forward: &BTreeMap<String, String>,
+) -> Result<Vec<(String, Option<String>)>, AuthFailure> {
+    if let Some((name, _)) = require
+        .iter()
+        .find(|(name, expected)| !claim_matches(claim(claims, name), expected))
+    {
+        return Err(AuthFailure {
+            status: 403,
+            challenge: None,
+            reason: format!("Claim {name} does not meet the requirement"),
+        });
+    }
+    let forward = forward
+        .iter()
+        .map(|(header, name)| {
+            let value = claim(claims, name)
+                .and_then(claim_value)
+                .filter(|v| !v.chars().any(char::is_control));
+            (header.clone(), value)
+        })
+        .collect();
+    Ok(forward)
+}
+
+/// `Authorization: Basic` 中的用户名及密码
+fn basic_credentials(req: &RequestHeader) -> Option<(String, String)> {
+    let value = req.headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+fn bearer(value: &str) -> Option<&str> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+fn query_param(req: &RequestHeader, name: &str) -> Option<String> {
+    req.uri.query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (key == name && !value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// 删除查询参数中的 API key，其它参数保持原样
+pub(super) fn remove_query_param(uri: &str, name: &str) -> String {
+    let Some((path, query)) = uri.split_once('?') else {
+        return uri.to_string();
+    };
+    let rest: Vec<&str> = query
+        .split('&')
+        .filter(|pair| pair.split_once('=').map_or(*pair, |(key, _)| key) != name)
+        .collect();
+    if rest.is_empty() {
+        return path.to_string();
+    }
+    format!("{path}?{}", rest.join("&"))
+}
+
+/// 比较的时间与内容无关，避免逐字节猜测 API key
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 按 `.` 分隔的名称查找嵌套的声明，完整名称存在时优先
+pub(super) fn claim<'a>(claims: &'a Value, name: &str) -> Option<&'a Value> {
+    if let Some(value) = claims.get(name) {
+        return Some(value);
+    }
+    name.split('.')
+        .try_fold(claims, |value, part| value.get(part))
+}
+
+/// 声明转为请求头的值，数组用 `,` 连接，对象等其它类型忽略
+pub(super) fn claim_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().filter_map(claim_value).collect();
+            Some(items.join(","))
+        }
+        _ => None,
+    }
+}
+
+fn claim_matches(actual: Option<&Value>, expected: &Value) -> bool {
+    let Some(actual) = actual else {
+        return false;
+    };
+    let matches = |expected: &Value| match actual {
+        Value::Array(items) => items.contains(expected),
+        _ => actual == expected,
+    };
+    match expected {
+        Value::Array(options) => options.iter().any(matches),
+        _ => matches(expected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn rule(path: Option<&str>, method: AuthMethod, forward: &[(&str, &str)]) -> AuthRule {
+        AuthRule {
+            path: path.map(|p| PathPattern::new(p).unwrap()),
+            method,
+            require: BTreeMap::new(),
+            forward: forward
+                .iter()
+                .map(|(header, claim)| (header.to_string(), claim.to_string()))
+                .collect(),
+        }
+    }
+
+    fn api_key() -> AuthMethod {
+        AuthMethod::ApiKey {
+            header: default_api_key_header(),
+            query: Some("api_key".to_string()),
+            keys: vec![ApiKey {
+                name: "ci".to_string(),
+                key: "k-123".to_string(),
+            }],
+        }
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    async fn store(rules: Vec<AuthRule>) -> AuthStore {
+        let store = AuthStore::default();
+        store.set("example.com".to_string(), rules).await.unwrap();
+        store
+    }
+
+    const NAMES: [Option<&str>; 2] = [Some("example.com"), None];
+
+    #[tokio::test]
+    async fn forward_headers_are_stripped_on_every_path() {
+        let auth = store(vec![
+            rule(Some("^/api"), api_key(), &[("x-user", "sub")]),
+            rule(None, AuthMethod::None, &[("x-team", "team")]),
+        ])
+        .await;
+        let result = auth
+            .check(NAMES, &request("/public", &[("x-user", "admin")]))
+            .await
+            .unwrap();
+        assert_eq!(result.rule, Some(1));
+        assert!(result.failure.is_none());
+        // 没有声明的请求头只删除，其它规则的请求头同样删除
+        assert!(result.forward.iter().all(|(_, value)| value.is_none()));
+        assert!(result.forward.iter().any(|(name, _)| name == "x-user"));
+
+        let result = auth
+            .check(NAMES, &request("/api/v1?api_key=k-123&a=1", &[]))
+            .await
+            .unwrap();
+        assert_eq!(result.subject.as_deref(), Some("ci"));
+        assert_eq!(result.strip_query.as_deref(), Some("api_key"));
+        assert_eq!(
+            result.forward.last(),
+            Some(&("x-user".to_string(), Some("ci".to_string())))
+        );
+        assert!(auth
+            .check([Some("other.com"), None], &request("/", &[]))
+            .await
+            .is_none());
+
+        let auth = store(vec![rule(Some("^/api"), api_key(), &[("x-user", "sub")])]).await;
+        let unmatched = auth
+            .check(NAMES, &request("/public", &[("x-user", "admin")]))
+            .await
+            .unwrap();
+        assert_eq!(unmatched.rule, None);
+        assert!(unmatched.failure.is_none());
+        assert_eq!(unmatched.forward, vec![("x-user".to_string(), None)]);
+    }
+
+    #[tokio::test]
+    async fn api_key_from_header_or_query() {
+        let store = store(vec![rule(None, api_key(), &[])]).await;
+        let check = |req: RequestHeader| {
+            let store = &store;
+            async move { store.check(NAMES, &req).await.unwrap().failure }
+        };
+        assert!(check(request("/", &[("x-api-key", "k-123")]))
+            .await
+            .is_none());
+        assert!(check(request("/?api_key=k-123", &[])).await.is_none());
+        let failure = check(request("/?api_key=wrong", &[])).await.unwrap();
+        assert_eq!(
+            (failure.status, failure.reason.as_str()),
+            (401, "Invalid API key")
+        );
+        let failure = check(request("/", &[])).await.unwrap();
+        assert_eq!(failure.reason, "Missing API key");
+    }
+
+    #[tokio::test]
+    async fn basic_rejects_unknown_users() {
+        let path = std::env::temp_dir().join(format!("htpasswd-{}", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap()),
+        )
+        .unwrap();
+        let method = AuthMethod::Basic {
+            htpasswd: path.clone(),
+            realm: default_realm(),
+        };
+        let store = store(vec![rule(None, method, &[("x-user", "sub")])]).await;
+        let basic = |credentials: &str| {
+            let value = format!("Basic {}", STANDARD.encode(credentials));
+            request("/", &[("authorization", value.as_str())])
+        };
+
+        let result = store.check(NAMES, &basic("alice:secret")).await.unwrap();
+        assert!(result.failure.is_none());
+        assert_eq!(result.subject.as_deref(), Some("alice"));
+        for credentials in ["alice:wrong", "mallory:secret"] {
+            let failure = store
+                .check(NAMES, &basic(credentials))
+                .await
+                .unwrap()
+                .failure;
+            let failure = failure.unwrap();
+            assert_eq!(failure.reason, "Invalid credentials");
+            assert_eq!(
+                failure.challenge.as_deref(),
+                Some("Basic realm=\"Restricted\"")
+            );
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn api_key_query_is_removed() {
+        assert_eq!(remove_query_param("/a?api_key=1&b=2", "api_key"), "/a?b=2");
+        assert_eq!(remove_query_param("/a?b=2&api_key", "api_key"), "/a?b=2");
+        assert_eq!(remove_query_param("/a?api_key=1", "api_key"), "/a");
+        assert_eq!(
+            remove_query_param("/a?x_api_key=1", "api_key"),
+            "/a?x_api_key=1"
+        );
+        assert_eq!(remove_query_param("/a", "api_key"), "/a");
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let redacted = rule(None, api_key(), &[]).redacted();
+        let AuthMethod::ApiKey { keys, .. } = &redacted.method else {
+            unreachable!();
+        };
+        assert_eq!(keys[0].name, "ci");
+        assert_eq!(keys[0].key, REDACTED);
+
+        let jwt = AuthMethod::Jwt(
+            serde_json::from_value(json!({ "secret": "s3cret", "issuer": "iss" })).unwrap(),
+        );
+        let AuthMethod::Jwt(config) = rule(None, jwt, &[]).redacted().method else {
+            unreachable!();
+        };
+        assert_eq!(config.secret.as_deref(), Some(REDACTED));
+        assert_eq!(config.issuer.as_deref(), Some("iss"));
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use pingora::Result;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, HistogramVec,
+    IntCounterVec, IntGaugeVec,
+};
+use serde::Serialize;
+use tokio::{sync::oneshot, time::timeout};
+
+use super::ErrorCode;
+use crate::svcs::{AdaptiveAlgorithm, ConcurrencyLimit};
+
+/// 清理已删除的域名的限制的间隔
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Gradient 算法每隔多少个样本用平滑后的延迟重置最小延迟，以适应后端的变化
+const MIN_RTT_RESET: u32 = 1000;
+
+static IN_FLIGHT: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "proxy_concurrency_in_flight",
+        "Requests being proxied to the domain",
+        &["domain"]
+    )
+    .unwrap()
+});
+
+static LIMIT: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "proxy_concurrency_limit",
+        "Current concurrency limit of the domain",
+        &["domain"]
+    )
+    .unwrap()
+});
+
+static QUEUE_DEPTH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "proxy_concurrency_queue_depth",
+        "Requests waiting for a concurrency slot",
+        &["domain"]
+    )
+    .unwrap()
+});
+
+static QUEUE_WAIT: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "proxy_concurrency_queue_wait_seconds",
+        "Time requests waited in the concurrency queue",
+        &["domain"]
+    )
+    .unwrap()
+});
+
+static REJECTED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "proxy_concurrency_rejected_total",
+        "Requests rejected by the concurrency limit",
+        &["domain", "reason"]
+    )
+    .unwrap()
+});
+
+/// 限制的当前状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ConcurrencyStatus {
+    pub limit: u32,
+    pub in_flight: u32,
+    pub queued: u32,
+}
+
+struct State {
+    config: ConcurrencyLimit,
+    /// 当前的限制，自适应模式下会调整
+    limit: f64,
+    in_flight: u32,
+    /// 排队的请求，位置空出时直接转交给队首的请求
+    waiters: VecDeque<oneshot::Sender<()>>,
+    /// Gradient 算法观察到的最小延迟及平滑后的延迟，单位秒
+    min_rtt: Option<f64>,
+    smoothed_rtt: Option<f64>,
+    samples: u32,
+}
+
+impl State {
+    fn new(config: ConcurrencyLimit) -> Self {
+        Self {
+            config,
+            limit: config.max_in_flight as f64,
+            in_flight: 0,
+            waiters: VecDeque::new(),
+            min_rtt: None,
+            smoothed_rtt: None,
+            samples: 0,
+        }
+    }
+
+    fn limit(&self) -> u32 {
+        (self.limit as u32).max(1)
+    }
+
+    fn status(&self) -> ConcurrencyStatus {
+        ConcurrencyStatus {
+            limit: self.limit(),
+            in_flight: self.in_flight,
+            queued: self.waiters.len() as u32,
+        }
+    }
+
+    /// 根据请求的延迟及结果调整限制
+    fn adjust(&mut self, latency: Duration, ok: bool) {
+        let Some(adaptive) = self.config.adaptive else {
+            return;
+        };
+        let (min, max) = (adaptive.min_limit as f64, adaptive.max_limit as f64);
+        match adaptive.algorithm {
+            AdaptiveAlgorithm::Aimd => {
+                let overloaded = !ok
+                    || adaptive
+                        .latency_ms
+                        .is_some_and(|ms| latency.as_millis() > ms as u128);
+                if overloaded {
+                    self.limit *= adaptive.backoff;
+                } else if self.in_flight as f64 * 2.0 >= self.limit {
+                    // 只在限制被充分使用时增大，每个限制的请求数增加 1
+                    self.limit += 1.0 / self.limit;
+                }
+            }
+            AdaptiveAlgorithm::Gradient => {
+                let rtt = latency.as_secs_f64();
+                if !ok || rtt <= 0.0 {
+                    return;
+                }
+                let smoothed = self.smoothed_rtt.map_or(rtt, |s| s * 0.9 + rtt * 0.1);
+                self.smoothed_rtt = Some(smoothed);
+                self.samples += 1;
+                let min_rtt = if self.samples % MIN_RTT_RESET == 0 {
+                    smoothed
+                } else {
+                    self.min_rtt.map_or(rtt, |m| m.min(rtt))
+                };
+                self.min_rtt = Some(min_rtt);
+                let gradient = (min_rtt / smoothed).clamp(0.5, 1.0);
+                let target = self.limit * gradient + self.limit.sqrt();
+                self.limit = self.limit * 0.8 + target * 0.2;
+            }
+        }
+        self.limit = self.limit.clamp(min, max.max(min));
+    }
+
+    /// 把空出的位置转交给排队的请求，跳过已超时的请求
+    fn wake(&mut self) {
+        while self.in_flight < self.limit() {
+            let Some(waiter) = self.waiters.pop_front() else {
+                break;
+            };
+            if waiter.send(()).is_ok() {
+                self.in_flight += 1;
+            }
+        }
+    }
+}
+
+/// 一个域名同时处理的请求数的限制
+pub struct Limiter {
+    domain: String,
+    state: Mutex<State>,
+}
+
+impl Limiter {
+    fn new(domain: String, config: ConcurrencyLimit) -> Self {
+        let limiter = Self {
+            domain,
+            state: Mutex::new(State::new(config)),
+        };
+        limiter.report(&limiter.state.lock().unwrap());
+        limiter
+    }
+
+    /// 配置变更时重新开始调整，已处理中的请求仍然计数
+    fn reconfigure(&self, config: ConcurrencyLimit) {
+        let mut state = self.state.lock().unwrap();
+        if state.config == config {
+            return;
+        }
+        let previous = std::mem::replace(&mut *state, State::new(config));
+        state.in_flight = previous.in_flight;
+        state.waiters = previous.waiters;
+        state.wake();
+        self.report(&state);
+    }
+
+    pub fn status(&self) -> ConcurrencyStatus {
+        self.state.lock().unwrap().status()
+    }
+
+    fn report(&self, state: &State) {
+        let labels = [self.domain.as_str()];
+        IN_FLIGHT
+            .with_label_values(&labels)
+            .set(state.in_flight as i64);
+        LIMIT.with_label_values(&labels).set(state.limit() as i64);
+        QUEUE_DEPTH
+            .with_label_values(&labels)
+            .set(state.waiters.len() as i64);
+    }
+
+    fn reject(&self, reason: &str, context: String) -> Box<pingora::Error> {
+        REJECTED
+            .with_label_values(&[self.domain.as_str(), reason])
+            .inc();
+        ErrorCode::Overloaded.error(context)
+    }
+
+    /// 取得处理请求的位置，超过限制时排队，队列已满或等待超时时返回 `Overloaded` 错误
+    pub async fn acquire(self: &Arc<Self>) -> Result<ConcurrencyPermit> {
+        let (mut receiver, wait) = {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight < state.limit() {
+                state.in_flight += 1;
+                self.report(&state);
+                return Ok(ConcurrencyPermit::new(self.clone()));
+            }
+            if state.waiters.len() >= state.config.queue as usize {
+                return Err(self.reject(
+                    "queue_full",
+                    format!("{} requests in flight to {}", state.in_flight, self.domain),
+                ));
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiters.push_back(sender);
+            self.report(&state);
+            (receiver, state.config.queue_timeout())
+        };
+
+        let start = Instant::now();
+        let admitted = match timeout(wait, &mut receiver).await {
+            Ok(result) => result.is_ok(),
+            Err(_) => {
+                // 超时的同时可能已被转交位置，需要在锁内确认
+                let mut state = self.state.lock().unwrap();
+                let admitted = receiver.try_recv().is_ok();
+                if !admitted {
+                    receiver.close();
+                    state.waiters.retain(|waiter| !waiter.is_closed());
+                    self.report(&state);
+                }
+                admitted
+            }
+        };
+        QUEUE_WAIT
+            .with_label_values(&[self.domain.as_str()])
+            .observe(start.elapsed().as_secs_f64());
+        if !admitted {
+            return Err(self.reject(
+                "queue_timeout",
+                format!("Waited {wait:?} for a slot to {}", self.domain),
+            ));
+        }
+        self.report(&self.state.lock().unwrap());
+        Ok(ConcurrencyPermit::new(self.clone()))
+    }
+
+    fn release(&self, sample: Option<(Duration, bool)>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((latency, ok)) = sample {
+            state.adjust(latency, ok);
+        }
+        state.in_flight = state.in_flight.saturating_sub(1);
+        state.wake();
+        self.report(&state);
+    }
+}
+
+/// 处理请求的位置，请求结束时释放
+pub struct ConcurrencyPermit {
+    limiter: Arc<Limiter>,
+    start: Instant,
+    released: bool,
+}
+
+impl ConcurrencyPermit {
+    fn new(limiter: Arc<Limiter>) -> Self {
+        Self {
+            limiter,
+            start: Instant::now(),
+            released: false,
+        }
+    }
+
+    /// 释放位置，请求的延迟及结果用于调整自适应的限制
+    pub fn finish(mut self, ok: bool) {
+        self.released = true;
+        self.limiter.release(Some((self.start.elapsed(), ok)));
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        if !self.released {
+            self.limiter.release(None);
+        }
+    }
+}
+
+/// 所有域名的限制，请求时按域名的配置创建或更新
+#[derive(Default)]
+pub struct ConcurrencyLimiters {
+    limiters: Mutex<HashMap<String, Arc<Limiter>>>,
+    swept: Mutex<Option<Instant>>,
+}
+
+impl ConcurrencyLimiters {
+    /// 域名的限制，域名没有配置时返回空
+    pub fn limiter(&self, domain: &str, config: Option<ConcurrencyLimit>) -> Option<Arc<Limiter>> {
+        let Some(config) = config else {
+            self.remove(domain);
+            return None;
+        };
+        let mut limiters = self.limiters.lock().unwrap();
+        let limiter = limiters
+            .entry(domain.to_string())
+            .or_insert_with(|| Arc::new(Limiter::new(domain.to_string(), config)));
+        limiter.reconfigure(config);
+        Some(limiter.clone())
+    }
+
+    /// 删除域名的限制，已取得位置的请求结束时仍释放到原来的限制
+    pub fn remove(&self, domain: &str) -> bool {
+        let removed = self.limiters.lock().unwrap().remove(domain).is_some();
+        if removed {
+            for gauge in [&IN_FLIGHT, &LIMIT, &QUEUE_DEPTH] {
+                let _ = gauge.remove_label_values(&[domain]);
+            }
+        }
+        removed
+    }
+
+    /// 距离上次清理超过间隔时返回是，并记录本次清理的时间
+    pub fn sweep_due(&self) -> bool {
+        let now = Instant::now();
+        let mut swept = self.swept.lock().unwrap();
+        if swept.is_some_and(|at| now.duration_since(at) < SWEEP_INTERVAL) {
+            return false;
+        }
+        *swept = Some(now);
+        true
+    }
+
+    /// 删除 `live` 返回否的域名的限制，用于清理已删除的域名
+    pub fn retain(&self, live: impl Fn(&str) -> bool) {
+        let removed: Vec<String> = self
+            .limiters
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|domain| !live(domain))
+            .cloned()
+            .collect();
+        for domain in removed {
+            self.remove(&domain);
+        }
+    }
+
+    /// 域名按 `config` 处理下一个请求时的状态，不创建或更新限制
+    pub fn status(
+        &self,
+        domain: &str,
+        config: Option<ConcurrencyLimit>,
+    ) -> Option<ConcurrencyStatus> {
+        let config = config?;
+        let initial = State::new(config).status();
+        let limiters = self.limiters.lock().unwrap();
+        let Some(limiter) = limiters.get(domain) else {
+            return Some(initial);
+        };
+        let state = limiter.state.lock().unwrap();
+        if state.config == config {
+            return Some(state.status());
+        }
+        // 配置变化后重新开始调整，处理中及排队的请求保留
+        Some(ConcurrencyStatus {
+            limit: initial.limit,
+            ..state.status()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svcs::AdaptiveLimit;
+
+    fn config(max_in_flight: u32, queue: u32, queue_timeout_ms: u64) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            max_in_flight,
+            queue,
+            queue_timeout_ms,
+            adaptive: None,
+        }
+    }
+
+    fn adaptive(algorithm: AdaptiveAlgorithm, latency_ms: Option<u64>) -> State {
+        State::new(ConcurrencyLimit {
+            adaptive: Some(AdaptiveLimit {
+                algorithm,
+                min_limit: 2,
+                max_limit: 100,
+                latency_ms,
+                backoff: 0.5,
+            }),
+            ..config(10, 0, 1000)
+        })
+    }
+
+    fn rejected(result: Result<ConcurrencyPermit>) -> ErrorCode {
+        match result {
+            Ok(_) => panic!("expected the request to be rejected"),
+            Err(e) => ErrorCode::of(&e, false).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_is_bounded() {
+        let limiter = Arc::new(Limiter::new("queue.test".to_string(), config(1, 1, 5000)));
+        let permit = limiter.acquire().await.unwrap();
+
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.is_ok() }
+        });
+        while limiter.status().queued == 0 {
+            tokio::task::yield_now().await;
+        }
+        let code = rejected(limiter.acquire().await);
+        assert_eq!(code, ErrorCode::Overloaded);
+        assert_eq!(code.status(), 503);
+
+        // 位置空出时直接转交给排队的请求
+        drop(permit);
+        assert!(waiter.await.unwrap());
+        let status = limiter.status();
+        assert_eq!((status.in_flight, status.queued), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn queue_timeout_returns_overloaded() {
+        let limiter = Arc::new(Limiter::new("timeout.test".to_string(), config(1, 1, 20)));
+        let _permit = limiter.acquire().await.unwrap();
+
+        let start = Instant::now();
+        assert_eq!(rejected(limiter.acquire().await), ErrorCode::Overloaded);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        let status = limiter.status();
+        assert_eq!((status.in_flight, status.queued), (1, 0));
+    }
+
+    #[test]
+    fn aimd_backs_off_and_grows_under_load() {
+        let mut state = adaptive(AdaptiveAlgorithm::Aimd, Some(100));
+        state.adjust(Duration::from_millis(10), false);
+        assert_eq!(state.limit, 5.0);
+        state.adjust(Duration::from_millis(200), true);
+        assert_eq!(state.limit, 2.5);
+        // 不低于最小限制
+        state.adjust(Duration::from_millis(10), false);
+        assert_eq!(state.limit, 2.0);
+
+        // 限制未被充分使用时不增大
+        state.adjust(Duration::from_millis(10), true);
+        assert_eq!(state.limit, 2.0);
+        state.in_flight = 1;
+        state.adjust(Duration::from_millis(10), true);
+        assert_eq!(state.limit, 2.5);
+    }
+
+    #[test]
+    fn gradient_follows_latency() {
+        let mut state = adaptive(AdaptiveAlgorithm::Gradient, None);
+        for _ in 0..10 {
+            state.adjust(Duration::from_millis(10), true);
+        }
+        let grown = state.limit;
+        assert!(grown > 10.0);
+        // 失败的请求不计入延迟
+        state.adjust(Duration::from_secs(1), false);
+        assert_eq!(state.limit, grown);
+
+        for _ in 0..20 {
+            state.adjust(Duration::from_secs(1), true);
+        }
+        assert!(state.limit < grown);
+        assert!(state.limit >= 2.0);
+    }
+}
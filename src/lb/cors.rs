@@ -0,0 +1,409 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use hyper::{header, Method};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    Result,
+};
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::RwLock;
+
+/// 匹配来源的正则表达式，总是匹配整个来源，例如 `https://[a-z0-9-]+\.example\.com`
+/// 不会匹配 `https://app.example.com.evil.net`
+#[derive(Clone)]
+pub struct OriginPattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl OriginPattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        let regex = Regex::new(&format!("^(?:{pattern})$"))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            regex,
+        })
+    }
+
+    pub fn is_match(&self, origin: &str) -> bool {
+        self.regex.is_match(origin)
+    }
+}
+
+impl fmt::Debug for OriginPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.pattern, f)
+    }
+}
+
+impl PartialEq for OriginPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl Eq for OriginPattern {}
+
+impl Serialize for OriginPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.pattern)
+    }
+}
+
+impl<'de> Deserialize<'de> for OriginPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Self::new(&pattern).map_err(de::Error::custom)
+    }
+}
+
+/// 跨域请求的策略
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CorsPolicy {
+    /// 允许的来源，例如 `https://app.example.com`，`*` 允许所有来源
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub origins: Vec<String>,
+    /// 匹配整个来源的正则表达式，例如 `https://[a-z0-9-]+\.example\.com`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub origin_patterns: Vec<OriginPattern>,
+    /// 允许的方法，`*` 允许所有方法
+    #[serde(default = "default_methods")]
+    pub methods: Vec<String>,
+    /// 允许的请求头，`*` 允许所有请求头
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<String>,
+    /// 允许携带 Cookie 等凭证，不能与来源 `*` 同时使用
+    #[serde(default)]
+    pub credentials: bool,
+    /// 允许浏览器读取的响应头
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expose_headers: Vec<String>,
+    /// 浏览器缓存预检结果的秒数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+}
+
+fn default_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST"].map(str::to_string).to_vec()
+}
+
+/// 跨域的响应头
+pub type CorsHeaders = Vec<(&'static str, String)>;
+
+impl CorsPolicy {
+    fn any_origin(&self) -> bool {
+        self.origins.iter().any(|origin| origin == "*")
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin()
+            || self
+                .origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            || self.origin_patterns.iter().any(|p| p.is_match(origin))
+    }
+
+    /// 允许所有来源且不携带凭证时响应 `*`，否则响应请求的来源并添加 `Vary: Origin`
+    fn origin_headers(&self, origin: &str) -> CorsHeaders {
+        if self.any_origin() && !self.credentials {
+            return vec![("access-control-allow-origin", "*".to_string())];
+        }
+        let mut headers = vec![
+            ("access-control-allow-origin", origin.to_string()),
+            ("vary", "Origin".to_string()),
+        ];
+        if self.credentials {
+            headers.push(("access-control-allow-credentials", "true".to_string()));
+        }
+        headers
+    }
+
+    /// 预检请求的响应头，不允许时返回原因
+    pub fn preflight(&self, req: &RequestHeader, origin: &str) -> Result<CorsHeaders, String> {
+        if !self.allows_origin(origin) {
+            return Err(format!("Origin {origin} is not allowed"));
+        }
+        let method = request_header(req, "access-control-request-method").unwrap_or_default();
+        let any_method = self.methods.iter().any(|m| m == "*");
+        if !any_method && !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+            return Err(format!("Method {method} is not allowed"));
+        }
+        let requested: Vec<&str> = request_header(req, "access-control-request-headers")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        let any_header = self.headers.iter().any(|h| h == "*");
+        if let Some(name) = requested
+            .iter()
+            .find(|name| !any_header && !self.headers.iter().any(|h| h.eq_ignore_ascii_case(name)))
+        {
+            return Err(format!("Header {name} is not allowed"));
+        }
+
+        let mut headers = self.origin_headers(origin);
+        let methods = if any_method {
+            method.to_string()
+        } else {
+            self.methods.join(", ")
+        };
+        headers.push(("access-control-allow-methods", methods));
+        if !requested.is_empty() {
+            let allowed = if any_header {
+                requested.join(", ")
+            } else {
+                self.headers.join(", ")
+            };
+            headers.push(("access-control-allow-headers", allowed));
+        }
+        if let Some(max_age) = self.max_age_secs {
+            headers.push(("access-control-max-age", max_age.to_string()));
+        }
+        headers.push((
+            "vary",
+            "Access-Control-Request-Method, Access-Control-Request-Headers".to_string(),
+        ));
+        Ok(headers)
+    }
+
+    /// 实际请求的响应头，没有来源或来源不允许时只有 `Vary`
+    pub fn response_headers(&self, origin: Option<&str>) -> CorsHeaders {
+        let Some(origin) = origin.filter(|origin| self.allows_origin(origin)) else {
+            if self.any_origin() && !self.credentials {
+                return Vec::new();
+            }
+            return vec![("vary", "Origin".to_string())];
+        };
+        let mut headers = self.origin_headers(origin);
+        if !self.expose_headers.is_empty() {
+            headers.push((
+                "access-control-expose-headers",
+                self.expose_headers.join(", "),
+            ));
+        }
+        headers
+    }
+}
+
+fn request_header<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// 请求的 `Origin`
+pub fn origin(req: &RequestHeader) -> Option<&str> {
+    request_header(req, header::ORIGIN.as_str())
+}
+
+/// 带有 `Origin` 及 `Access-Control-Request-Method` 的 `OPTIONS` 请求为预检请求
+pub fn is_preflight(req: &RequestHeader) -> bool {
+    req.method == Method::OPTIONS
+        && origin(req).is_some()
+        && request_header(req, "access-control-request-method").is_some()
+}
+
+/// 设置跨域的响应头，替换后端返回的跨域响应头，`Vary` 追加到已有的值之后
+pub fn apply(resp: &mut ResponseHeader, headers: &CorsHeaders) -> Result<()> {
+    for name in [
+        "access-control-allow-origin",
+        "access-control-allow-credentials",
+        "access-control-expose-headers",
+    ] {
+        resp.remove_header(name);
+    }
+    for (name, value) in headers {
+        if *name == "vary" {
+            resp.append_header(*name, value)?;
+        } else {
+            resp.insert_header(*name, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// 各域名的跨域策略
+#[derive(Default)]
+pub struct CorsStore {
+    /// 键为请求的 Host 或域名
+    policies: RwLock<HashMap<String, Arc<CorsPolicy>>>,
+}
+
+impl CorsStore {
+    pub async fn policies(&self) -> HashMap<String, CorsPolicy> {
+        self.policies
+            .read()
+            .await
+            .iter()
+            .map(|(domain, policy)| (domain.clone(), CorsPolicy::clone(policy)))
+            .collect()
+    }
+
+    pub async fn set(&self, domain: String, policy: CorsPolicy) {
+        self.policies.write().await.insert(domain, Arc::new(policy));
+    }
+
+    /// 删除域名的策略，返回是否存在
+    pub async fn remove(&self, domain: &str) -> bool {
+        self.policies.write().await.remove(domain).is_some()
+    }
+
+    /// 请求的 Host 的策略，没有时使用处理请求的域名的策略
+    pub async fn policy(&self, names: [Option<&str>; 2]) -> Option<Arc<CorsPolicy>> {
+        let policies = self.policies.read().await;
+        names
+            .into_iter()
+            .flatten()
+            .find_map(|name| policies.get(name))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn policy(value: serde_json::Value) -> CorsPolicy {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn preflight(method: &str, headers: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("OPTIONS", b"/", None).unwrap();
+        req.insert_header("origin", "https://app.example.com")
+            .unwrap();
+        req.insert_header("access-control-request-method", method)
+            .unwrap();
+        if let Some(headers) = headers {
+            req.insert_header("access-control-request-headers", headers)
+                .unwrap();
+        }
+        req
+    }
+
+    fn header<'a>(headers: &'a CorsHeaders, name: &str) -> Vec<&'a str> {
+        headers
+            .iter()
+            .filter(|(n, _)| *n == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn origin_patterns_are_anchored() {
+        let policy = policy(json!({ "origin_patterns": [r"https://[a-z0-9-]+\.example\.com"] }));
+        assert!(policy.allows_origin("https://app.example.com"));
+        assert!(!policy.allows_origin("https://app.example.com.evil.net"));
+        assert!(!policy.allows_origin("https://evil.net/?https://app.example.com"));
+        assert_eq!(
+            serde_json::to_value(&policy.origin_patterns).unwrap(),
+            json!([r"https://[a-z0-9-]+\.example\.com"])
+        );
+    }
+
+    #[test]
+    fn preflight_allowed() {
+        let policy = policy(json!({
+            "origins": ["https://app.example.com"],
+            "methods": ["GET", "PUT"],
+            "headers": ["content-type", "authorization"],
+            "credentials": true,
+            "max_age_secs": 600,
+        }));
+        let req = preflight("PUT", Some("Content-Type"));
+        assert!(is_preflight(&req));
+        let headers = policy.preflight(&req, "https://app.example.com").unwrap();
+        assert_eq!(
+            header(&headers, "access-control-allow-origin"),
+            ["https://app.example.com"]
+        );
+        assert_eq!(
+            header(&headers, "access-control-allow-credentials"),
+            ["true"]
+        );
+        assert_eq!(
+            header(&headers, "access-control-allow-methods"),
+            ["GET, PUT"]
+        );
+        assert_eq!(
+            header(&headers, "access-control-allow-headers"),
+            ["content-type, authorization"]
+        );
+        assert_eq!(header(&headers, "access-control-max-age"), ["600"]);
+        assert_eq!(header(&headers, "vary").len(), 2);
+    }
+
+    #[test]
+    fn preflight_denied() {
+        let policy = policy(json!({
+            "origins": ["https://app.example.com"],
+            "headers": ["content-type"],
+        }));
+        let req = preflight("GET", None);
+        assert_eq!(
+            policy.preflight(&req, "https://evil.net").unwrap_err(),
+            "Origin https://evil.net is not allowed"
+        );
+        let req = preflight("DELETE", None);
+        assert_eq!(
+            policy
+                .preflight(&req, "https://app.example.com")
+                .unwrap_err(),
+            "Method DELETE is not allowed"
+        );
+        let req = preflight("POST", Some("content-type, x-secret"));
+        assert_eq!(
+            policy
+                .preflight(&req, "https://app.example.com")
+                .unwrap_err(),
+            "Header x-secret is not allowed"
+        );
+    }
+
+    #[test]
+    fn preflight_any_method_and_header() {
+        let policy = policy(json!({ "origins": ["*"], "methods": ["*"], "headers": ["*"] }));
+        let req = preflight("PATCH", Some("x-a, x-b"));
+        let headers = policy.preflight(&req, "https://app.example.com").unwrap();
+        assert_eq!(header(&headers, "access-control-allow-origin"), ["*"]);
+        assert_eq!(header(&headers, "access-control-allow-methods"), ["PATCH"]);
+        assert_eq!(
+            header(&headers, "access-control-allow-headers"),
+            ["x-a, x-b"]
+        );
+    }
+
+    #[test]
+    fn response_headers() {
+        let any = policy(json!({ "origins": ["*"] }));
+        let policy = policy(json!({
+            "origins": ["https://app.example.com"],
+            "expose_headers": ["x-request-id"],
+        }));
+        let headers = policy.response_headers(Some("https://app.example.com"));
+        assert_eq!(
+            header(&headers, "access-control-allow-origin"),
+            ["https://app.example.com"]
+        );
+        assert_eq!(
+            header(&headers, "access-control-expose-headers"),
+            ["x-request-id"]
+        );
+        assert_eq!(
+            policy.response_headers(Some("https://evil.net")),
+            [("vary", "Origin".to_string())]
+        );
+        assert_eq!(
+            policy.response_headers(None),
+            [("vary", "Origin".to_string())]
+        );
+
+        assert!(any.response_headers(None).is_empty());
+        assert_eq!(
+            any.response_headers(Some("https://app.example.com")),
+            [("access-control-allow-origin", "*".to_string())]
+        );
+    }
+}
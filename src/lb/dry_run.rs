@@ -0,0 +1,296 @@
+use std::net::IpAddr;
+
+use pingora::{http::RequestHeader, Error};
+use serde::Serialize;
+
+use super::{prepare_upstream, ConcurrencyStatus, ErrorCode, Fallback, Reply, RequestCtx, LB};
+use crate::svcs::{BackendStatus, ResponseRewrite};
+
+/// 访问控制的一项检查
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessCheck {
+    pub name: &'static str,
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+/// 代理返回的错误
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunError {
+    pub code: &'static str,
+    pub status: u16,
+    pub message: String,
+}
+
+impl DryRunError {
+    fn new(e: &Error) -> Self {
+        let code = ErrorCode::of(e, false).unwrap_or(ErrorCode::BadRequest);
+        Self {
+            code: code.code(),
+            status: code.status(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// 发送给后端的请求头相对客户端请求的变化，修改的头表示为删除后添加
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum HeaderChange {
+    Added { name: String, value: String },
+    Removed { name: String, value: String },
+}
+
+/// 发送给后端的请求
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    pub changes: Vec<HeaderChange>,
+    /// 头改写规则中匹配的路由
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_route: Option<usize>,
+}
+
+/// 模拟请求的结果，按代理处理请求的顺序依次填写，遇到直接响应或错误时停止
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunReport {
+    pub request_id: String,
+    /// 客户端的地址，来自可信的负载均衡时取自 `X-Forwarded-For`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<IpAddr>,
+    /// 规范化后的 Host
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// 处理请求的域名，别名为目标域名
+    pub domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias_of: Option<String>,
+    /// 域名未添加或请求没有 Host 时的处理方式
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<Fallback>,
+    /// 匹配的地址规则的序号
+    pub url_rules: Vec<usize>,
+    /// 访问控制是否允许请求
+    pub allowed: bool,
+    pub access: Vec<AccessCheck>,
+    /// 由代理直接返回的响应
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply: Option<Reply>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<DryRunError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<UpstreamRequest>,
+    /// 响应改写的配置
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<ResponseRewrite>,
+    /// 同时处理的请求数的限制，超过限制时请求将排队或被拒绝
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<ConcurrencyStatus>,
+    /// 处理请求的域名的所有后端
+    pub backends: Vec<BackendStatus>,
+    /// 将要参与选择的后端，实际请求从中按加权轮询选择一个
+    pub candidates: Vec<String>,
+}
+
+impl LB {
+    /// 模拟处理请求，与代理使用相同的逻辑，但不访问后端
+    pub async fn dry_run(
+        &self,
+        req: &RequestHeader,
+        client_ip: Option<IpAddr>,
+        scheme: &'static str,
+    ) -> DryRunReport {
+        let mut ctx = RequestCtx::new();
+        ctx.peer_ip = client_ip;
+        ctx.scheme = scheme;
+        ctx.dry_run = true;
+        let routed = self.route(req, &mut ctx).await;
+        let mut report = DryRunReport {
+            request_id: ctx.request_id.clone(),
+            client_ip: ctx.client_ip,
+            host: ctx.host.clone(),
+            port: ctx.port,
+            domain: ctx.domain.clone(),
+            alias_of: None,
+            fallback: None,
+            url_rules: ctx.url_rules.clone(),
+            allowed: ctx.access.iter().all(|check| check.allowed),
+            access: ctx.access.clone(),
+            reply: None,
+            error: None,
+            upstream: None,
+            rewrite: ctx.rewrite.clone(),
+            concurrency: None,
+            backends: Vec::new(),
+            candidates: Vec::new(),
+        };
+        match routed {
+            Ok(reply) => report.reply = reply,
+            Err(e) => report.error = Some(DryRunError::new(&e)),
+        }
+        let invalid_host = report
+            .error
+            .as_ref()
+            .is_some_and(|e| e.code == ErrorCode::InvalidHost.code());
+        if !invalid_host {
+            let config = match &ctx.host {
+                Some(host) => self.domains.read().await.get(host).cloned(),
+                None => None,
+            };
+            match config {
+                Some(config) => report.alias_of = config.alias_of.clone(),
+                None => {
+                    let fallbacks = self.policies.fallbacks.read().await;
+                    report.fallback = Some(match &ctx.host {
+                        Some(_) => fallbacks.unknown.clone(),
+                        None => fallbacks.missing.clone(),
+                    });
+                }
+            }
+        }
+        if report.reply.is_some() || report.error.is_some() {
+            return report;
+        }
+
+        let mut upstream = req.clone();
+        if let Err(e) = prepare_upstream(&mut ctx, req, scheme, &mut upstream) {
+            report.error = Some(DryRunError::new(&e));
+            return report;
+        }
+        let before = header_list(req);
+        let after = header_list(&upstream);
+        report.upstream = Some(UpstreamRequest {
+            method: upstream.method.to_string(),
+            uri: upstream.uri.to_string(),
+            changes: header_changes(&before, &after),
+            headers: after,
+            header_route: ctx.header_route,
+        });
+
+        let Some(domain) = ctx.domain.as_deref() else {
+            return report;
+        };
+        if let Some(config) = &ctx.config {
+            report.concurrency = self.policies.concurrency.status(domain, config.concurrency);
+        }
+        let Some(upstreams) = self.backgrounds.read().await.get(domain).cloned() else {
+            let e = ErrorCode::DomainNotFound.error(format!(
+                "Domain {domain} not found in backgrounds, Did you add it?"
+            ));
+            report.error = Some(DryRunError::new(&e));
+            return report;
+        };
+        report.backends = upstreams.statuses();
+        report.candidates = upstreams
+            .preview()
+            .iter()
+            .map(|backend| backend.addr.to_string())
+            .collect();
+        if report.candidates.is_empty() {
+            let e = ErrorCode::NoHealthyUpstream
+                .error(format!("Select upstream failed when request {domain}"));
+            report.error = Some(DryRunError::new(&e));
+        }
+        report
+    }
+}
+
+fn header_list(req: &RequestHeader) -> Vec<(String, String)> {
+    req.headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+fn header_changes(before: &[(String, String)], after: &[(String, String)]) -> Vec<HeaderChange> {
+    let mut added = after.to_vec();
+    let mut changes = Vec::new();
+    for (name, value) in before {
+        match added.iter().position(|(n, v)| n == name && v == value) {
+            Some(i) => {
+                added.remove(i);
+            }
+            None => changes.push(HeaderChange::Removed {
+                name: name.clone(),
+                value: value.clone(),
+            }),
+        }
+    }
+    changes.extend(
+        added
+            .into_iter()
+            .map(|(name, value)| HeaderChange::Added { name, value }),
+    );
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::svcs::DomainConfig;
+
+    async fn rate_limited_lb() -> LB {
+        let lb = LB {
+            backgrounds: Arc::default(),
+            domains: Arc::default(),
+            policies: Default::default(),
+        };
+        let config = DomainConfig {
+            domain: "example.com".to_string(),
+            ..Default::default()
+        };
+        lb.domains
+            .write()
+            .await
+            .insert(config.domain.clone(), Arc::new(config));
+        let rules = serde_json::from_value(json!([{ "limit": 1, "window_secs": 60 }])).unwrap();
+        lb.policies
+            .rate_limits
+            .set("example.com".to_string(), rules)
+            .await;
+        lb
+    }
+
+    /// 实际处理请求，消耗限流的配额
+    async fn route(lb: &LB, req: &RequestHeader, client_ip: IpAddr) -> Option<Reply> {
+        let mut ctx = RequestCtx::new();
+        ctx.peer_ip = Some(client_ip);
+        lb.route(req, &mut ctx).await.unwrap()
+    }
+
+    fn is_rate_limited(reply: &Option<Reply>) -> bool {
+        matches!(reply, Some(Reply::RateLimited(_)))
+    }
+
+    #[tokio::test]
+    async fn dry_run_does_not_consume_rate_limits() {
+        let lb = rate_limited_lb().await;
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("host", "example.com").unwrap();
+        let client_ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        for _ in 0..3 {
+            let report = lb.dry_run(&req, Some(client_ip), "http").await;
+            assert!(!is_rate_limited(&report.reply));
+            let check = report.access.iter().find(|c| c.name == "rate_limit");
+            assert_eq!(check.unwrap().detail, "0 of 1 remaining");
+        }
+
+        assert!(!is_rate_limited(&route(&lb, &req, client_ip).await));
+        assert!(is_rate_limited(&route(&lb, &req, client_ip).await));
+
+        // 配额用尽后模拟请求同样报告限流
+        let report = lb.dry_run(&req, Some(client_ip), "http").await;
+        assert!(is_rate_limited(&report.reply));
+    }
+}
@@ -0,0 +1,425 @@
+use bytes::Bytes;
+use hyper::{header, StatusCode};
+use pingora::{
+    http::ResponseHeader,
+    proxy::Session,
+    Error, ErrorSource,
+    ErrorType::{self, *},
+    Result,
+};
+use serde_json::json;
+
+use super::{pages::escape_html, respond::respond, PageTemplate, TimeoutKind};
+use crate::svcs::ErrorDetail;
+
+/// 代理错误的原因，每种原因有稳定的错误码，返回给客户端并写入日志
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// 请求没有 Host
+    MissingHost,
+    /// 请求的 Host 不合法
+    InvalidHost,
+    /// 请求的域名未添加
+    DomainNotFound,
+    /// 请求未通过认证
+    Unauthorized,
+    /// 客户端的地址或身份不允许访问
+    Forbidden,
+    /// 域名没有可用的后端
+    NoHealthyUpstream,
+    /// 无法与后端建立连接
+    UpstreamConnectFailed,
+    /// 与后端的 TLS 握手失败
+    UpstreamTlsFailed,
+    /// 后端的响应不是合法的 HTTP
+    UpstreamProtocolError,
+    /// 与后端的连接读写失败或被关闭
+    UpstreamConnectionClosed,
+    Timeout(TimeoutKind),
+    /// 客户端的请求不合法
+    BadRequest,
+    /// 由过滤器指定的 HTTP 状态码
+    Status(u16),
+    /// 域名处于维护模式
+    Maintenance,
+    /// 超过限流规则的限制
+    RateLimited,
+    /// 域名同时处理的请求数超过限制
+    Overloaded,
+    Internal,
+}
+
+impl ErrorCode {
+    /// 由代理主动产生的错误，通过 `ErrorType::Custom` 携带错误码
+    const CUSTOM: [ErrorCode; 6] = [
+        ErrorCode::MissingHost,
+        ErrorCode::InvalidHost,
+        ErrorCode::DomainNotFound,
+        ErrorCode::Forbidden,
+        ErrorCode::NoHealthyUpstream,
+        ErrorCode::Overloaded,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorCode::MissingHost => "MissingHost",
+            ErrorCode::InvalidHost => "InvalidHost",
+            ErrorCode::DomainNotFound => "DomainNotFound",
+            ErrorCode::Unauthorized => "Unauthorized",
+            ErrorCode::Forbidden => "Forbidden",
+            ErrorCode::NoHealthyUpstream => "NoHealthyUpstream",
+            ErrorCode::UpstreamConnectFailed => "UpstreamConnectFailed",
+            ErrorCode::UpstreamTlsFailed => "UpstreamTlsFailed",
+            ErrorCode::UpstreamProtocolError => "UpstreamProtocolError",
+            ErrorCode::UpstreamConnectionClosed => "UpstreamConnectionClosed",
+            ErrorCode::Timeout(kind) => kind.code(),
+            ErrorCode::BadRequest => "BadRequest",
+            ErrorCode::Status(_) => "HttpStatus",
+            ErrorCode::Maintenance => "Maintenance",
+            ErrorCode::RateLimited => "RateLimited",
+            ErrorCode::Overloaded => "Overloaded",
+            ErrorCode::Internal => "InternalError",
+        }
+    }
+
+    pub fn status(self) -> u16 {
+        match self {
+            ErrorCode::MissingHost | ErrorCode::InvalidHost | ErrorCode::BadRequest => 400,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::Forbidden => 403,
+            ErrorCode::DomainNotFound => 404,
+            ErrorCode::RateLimited => 429,
+            ErrorCode::NoHealthyUpstream | ErrorCode::Maintenance | ErrorCode::Overloaded => 503,
+            ErrorCode::UpstreamConnectFailed
+            | ErrorCode::UpstreamTlsFailed
+            | ErrorCode::UpstreamProtocolError
+            | ErrorCode::UpstreamConnectionClosed => 502,
+            ErrorCode::Timeout(kind) => kind.status(),
+            ErrorCode::Status(status) => status,
+            ErrorCode::Internal => 500,
+        }
+    }
+
+    /// 面向客户端的说明，不包含后端地址等内部信息
+    pub fn message(self) -> &'static str {
+        match self {
+            ErrorCode::MissingHost => "The request has no Host header",
+            ErrorCode::InvalidHost => "The request Host is invalid",
+            ErrorCode::DomainNotFound => "The requested domain is not served by this proxy",
+            ErrorCode::Unauthorized => "Authentication is required",
+            ErrorCode::Forbidden => "Access to the domain is not allowed",
+            ErrorCode::NoHealthyUpstream => "No healthy upstream is available for the domain",
+            ErrorCode::UpstreamConnectFailed => "Failed to connect to the upstream",
+            ErrorCode::UpstreamTlsFailed => "TLS handshake with the upstream failed",
+            ErrorCode::UpstreamProtocolError => "The upstream returned an invalid response",
+            ErrorCode::UpstreamConnectionClosed => "The upstream connection was closed",
+            ErrorCode::Timeout(TimeoutKind::Connect) => "Timed out connecting to the upstream",
+            ErrorCode::Timeout(TimeoutKind::TlsHandshake) => {
+                "Timed out in TLS handshake with the upstream"
+            }
+            ErrorCode::Timeout(TimeoutKind::FirstByte) => {
+                "Timed out waiting for the upstream response"
+            }
+            ErrorCode::Timeout(TimeoutKind::ReadIdle) => "Timed out reading from the upstream",
+            ErrorCode::Timeout(TimeoutKind::WriteIdle) => "Timed out writing to the upstream",
+            ErrorCode::Timeout(TimeoutKind::Total) => "The request exceeded its time limit",
+            ErrorCode::BadRequest => "The request is invalid",
+            ErrorCode::Status(_) => "The request could not be completed",
+            ErrorCode::Maintenance => "The service is under maintenance, please retry later",
+            ErrorCode::RateLimited => "Too many requests, please retry later",
+            ErrorCode::Overloaded => "Too many concurrent requests to the upstream",
+            ErrorCode::Internal => "Internal proxy error",
+        }
+    }
+
+    /// 产生携带错误码的错误，`context` 为内部信息
+    pub fn error(self, context: String) -> Box<Error> {
+        let mut err = Error::explain(ErrorType::Custom(self.code()), context);
+        match self {
+            ErrorCode::MissingHost
+            | ErrorCode::InvalidHost
+            | ErrorCode::BadRequest
+            | ErrorCode::Unauthorized
+            | ErrorCode::Forbidden => err.as_down(),
+            _ => err.as_in(),
+        }
+        err
+    }
+
+    /// 错误对应的原因，客户端断开等无需响应的错误返回空
+    pub fn of(e: &Error, response_started: bool) -> Option<Self> {
+        if let Some(kind) = TimeoutKind::of(e, response_started) {
+            return Some(ErrorCode::Timeout(kind));
+        }
+        let etype = e.etype();
+        if let Custom(code) = etype {
+            if let Some(error_code) = Self::CUSTOM.into_iter().find(|c| c.code() == *code) {
+                return Some(error_code);
+            }
+        }
+        if let HTTPStatus(status) = etype {
+            return Some(ErrorCode::Status(*status));
+        }
+        match e.esource() {
+            ErrorSource::Upstream => Some(match etype {
+                ConnectRefused | ConnectNoRoute | ConnectError | SocketError | BindError
+                | ConnectProxyFailure => ErrorCode::UpstreamConnectFailed,
+                TLSHandshakeFailure | TLSWantX509Lookup | InvalidCert | HandshakeError => {
+                    ErrorCode::UpstreamTlsFailed
+                }
+                InvalidHTTPHeader | H1Error | H2Error | InvalidH2 | H2Downgrade => {
+                    ErrorCode::UpstreamProtocolError
+                }
+                _ => ErrorCode::UpstreamConnectionClosed,
+            }),
+            ErrorSource::Downstream => match etype {
+                WriteError | ReadError | ConnectionClosed => None,
+                _ => Some(ErrorCode::BadRequest),
+            },
+            ErrorSource::Internal | ErrorSource::Unset => Some(ErrorCode::Internal),
+        }
+    }
+}
+
+/// 错误响应的格式，根据 Accept 协商
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Json,
+    Html,
+}
+
+impl Format {
+    /// 比较 Accept 中 JSON 与 HTML 的权重，HTML 权重更高时使用 HTML，否则使用 JSON
+    fn negotiate(accept: &str) -> Self {
+        let (mut json, mut html) = (0.0_f32, 0.0_f32);
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let media = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match media.as_str() {
+                "application/json" | "application/*" => json = json.max(q),
+                "text/html" | "text/*" => html = html.max(q),
+                "*/*" => {
+                    json = json.max(q);
+                    html = html.max(q);
+                }
+                _ => {}
+            }
+        }
+        if html > json {
+            Format::Html
+        } else {
+            Format::Json
+        }
+    }
+}
+
+/// 错误响应的内容
+pub struct ErrorResponse<'a> {
+    pub code: ErrorCode,
+    /// 响应的状态码，通常为错误码对应的状态码
+    pub status: u16,
+    pub request_id: &'a str,
+    pub host: Option<&'a str>,
+    pub detail: ErrorDetail,
+    /// 内部错误信息，`ErrorDetail::Internal` 时展示
+    pub internal: String,
+    /// 自定义的错误页面，为空时根据 Accept 返回 JSON 或 HTML
+    pub page: Option<PageTemplate>,
+    /// `Retry-After` 响应头，单位秒
+    pub retry_after: Option<u64>,
+    /// 其它响应头
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl<'a> ErrorResponse<'a> {
+    pub fn new(code: ErrorCode, request_id: &'a str) -> Self {
+        Self {
+            code,
+            status: code.status(),
+            request_id,
+            host: None,
+            detail: ErrorDetail::default(),
+            internal: String::new(),
+            page: None,
+            retry_after: None,
+            headers: Vec::new(),
+        }
+    }
+
+    /// 使用自定义的错误页面，替换页面中的变量
+    fn render_page(&self, page: &PageTemplate) -> (String, Bytes) {
+        let status = self.status.to_string();
+        let reason = StatusCode::from_u16(self.status)
+            .ok()
+            .and_then(|s| s.canonical_reason())
+            .unwrap_or_default();
+        let message = match self.detail {
+            ErrorDetail::Code => "",
+            _ => self.code.message(),
+        };
+        let vars = [
+            ("status", status.as_str()),
+            ("reason", reason),
+            ("code", self.code.code()),
+            ("message", message),
+            ("request_id", self.request_id),
+            ("host", self.host.unwrap_or_default()),
+        ];
+        (page.content_type.clone(), Bytes::from(page.render(&vars)))
+    }
+
+    fn render(&self, format: Format) -> (String, Bytes) {
+        if let Some(page) = &self.page {
+            return self.render_page(page);
+        }
+        let message = (self.detail != ErrorDetail::Code).then(|| self.code.message());
+        let internal = (self.detail == ErrorDetail::Internal).then_some(self.internal.as_str());
+        match format {
+            Format::Json => {
+                let mut error = json!({
+                    "code": self.code.code(),
+                    "status": self.status,
+                    "request_id": self.request_id,
+                });
+                if let Some(message) = message {
+                    error["message"] = json!(message);
+                }
+                if let Some(internal) = internal {
+                    error["detail"] = json!(internal);
+                }
+                let body = json!({ "error": error }).to_string();
+                ("application/json".to_string(), Bytes::from(body))
+            }
+            Format::Html => {
+                let status = self.status;
+                let mut body = format!(
+                    "<!DOCTYPE html>\n<html><head><title>{status} {code}</title></head><body>\n<h1>{status} {code}</h1>\n",
+                    code = self.code.code()
+                );
+                if let Some(message) = message {
+                    body.push_str(&format!("<p>{}</p>\n", escape_html(message)));
+                }
+                if let Some(internal) = internal {
+                    body.push_str(&format!("<pre>{}</pre>\n", escape_html(internal)));
+                }
+                body.push_str(&format!(
+                    "<p>Request ID: {}</p>\n</body></html>\n",
+                    escape_html(self.request_id)
+                ));
+                ("text/html; charset=utf-8".to_string(), Bytes::from(body))
+            }
+        }
+    }
+
+    /// 向客户端发送错误响应，响应已开始发送时无法再发送
+    pub async fn send(&self, session: &mut Session) -> Result<()> {
+        if session.response_written().is_some() {
+            return Ok(());
+        }
+        let accept = session
+            .req_header()
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let (content_type, body) = self.render(Format::negotiate(accept));
+
+        let mut resp = ResponseHeader::build(self.status, Some(5))?;
+        resp.insert_header(header::CONTENT_TYPE, content_type)?;
+        resp.insert_header(header::CACHE_CONTROL, "private, no-store")?;
+        if let Some(retry_after) = self.retry_after {
+            resp.insert_header(header::RETRY_AFTER, retry_after)?;
+        }
+        for (name, value) in &self.headers {
+            resp.insert_header(*name, value)?;
+        }
+        respond(session, resp, body, self.request_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn render(detail: ErrorDetail, format: Format) -> (String, String) {
+        let response = ErrorResponse {
+            detail,
+            internal: "connect to 10.0.0.1:80 <refused>".to_string(),
+            ..ErrorResponse::new(ErrorCode::UpstreamConnectFailed, "req-1")
+        };
+        let (content_type, body) = response.render(format);
+        (content_type, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn negotiate_accept() {
+        assert_eq!(Format::negotiate(""), Format::Json);
+        assert_eq!(Format::negotiate("*/*"), Format::Json);
+        assert_eq!(Format::negotiate("application/json"), Format::Json);
+        assert_eq!(
+            Format::negotiate("text/html,application/xhtml+xml,*/*;q=0.8"),
+            Format::Html
+        );
+        assert_eq!(
+            Format::negotiate("text/html;q=0.5, application/json"),
+            Format::Json
+        );
+        assert_eq!(
+            Format::negotiate("application/json;q=0.2, TEXT/*;q=0.9"),
+            Format::Html
+        );
+        assert_eq!(Format::negotiate("text/html;q=0"), Format::Json);
+    }
+
+    #[test]
+    fn json_detail_levels() {
+        let (content_type, body) = render(ErrorDetail::Code, Format::Json);
+        assert_eq!(content_type, "application/json");
+        let error = &serde_json::from_str::<Value>(&body).unwrap()["error"];
+        assert_eq!(error["code"], "UpstreamConnectFailed");
+        assert_eq!(error["status"], 502);
+        assert_eq!(error["request_id"], "req-1");
+        assert!(error.get("message").is_none());
+        assert!(error.get("detail").is_none());
+
+        let (_, body) = render(ErrorDetail::Message, Format::Json);
+        let error = &serde_json::from_str::<Value>(&body).unwrap()["error"];
+        assert_eq!(error["message"], "Failed to connect to the upstream");
+        assert!(error.get("detail").is_none());
+
+        let (_, body) = render(ErrorDetail::Internal, Format::Json);
+        let error = &serde_json::from_str::<Value>(&body).unwrap()["error"];
+        assert_eq!(error["message"], "Failed to connect to the upstream");
+        assert_eq!(error["detail"], "connect to 10.0.0.1:80 <refused>");
+    }
+
+    #[test]
+    fn html_detail_levels() {
+        let (content_type, body) = render(ErrorDetail::Code, Format::Html);
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert!(body.contains("<h1>502 UpstreamConnectFailed</h1>"));
+        assert!(body.contains("Request ID: req-1"));
+        assert!(!body.contains("Failed to connect"));
+
+        let (_, body) = render(ErrorDetail::Message, Format::Html);
+        assert!(body.contains("<p>Failed to connect to the upstream</p>"));
+        assert!(!body.contains("<pre>"));
+
+        let (_, body) = render(ErrorDetail::Internal, Format::Html);
+        assert!(body.contains("<pre>connect to 10.0.0.1:80 &lt;refused&gt;</pre>"));
+    }
+
+    #[test]
+    fn custom_codes_round_trip() {
+        for code in ErrorCode::CUSTOM {
+            let err = code.error("context".to_string());
+            assert_eq!(ErrorCode::of(&err, false), Some(code));
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+use super::PageTemplate;
+
+/// 请求的域名未添加或请求没有 Host 时的处理方式
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Fallback {
+    /// 返回错误
+    #[default]
+    Error,
+    /// 使用一个已添加域名的后端及配置
+    Upstream { domain: String },
+    /// 重定向到规范的地址，`location` 为完整地址，例如 `https://www.example.com`
+    Redirect {
+        location: String,
+        #[serde(default = "default_redirect_status")]
+        status: u16,
+        /// 是否在 `location` 后追加请求的路径及查询参数
+        #[serde(default = "default_keep_path")]
+        keep_path: bool,
+    },
+    /// 返回固定的响应，页面中的变量与错误页面相同
+    Static {
+        #[serde(default = "default_static_status")]
+        status: u16,
+        #[serde(flatten)]
+        page: PageTemplate,
+    },
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn default_keep_path() -> bool {
+    true
+}
+
+fn default_static_status() -> u16 {
+    200
+}
+
+/// 未知域名及缺少 Host 的请求分别的处理方式
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Fallbacks {
+    /// 域名未添加
+    #[serde(default)]
+    pub unknown: Fallback,
+    /// 请求没有 Host，absolute-form 的请求地址及 HTTP/2 的 `:authority` 视为 Host
+    #[serde(default)]
+    pub missing: Fallback,
+}
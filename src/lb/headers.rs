@@ -0,0 +1,414 @@
+use std::net::IpAddr;
+
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap,
+};
+use log::warn;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    Result,
+};
+
+use super::pages::render;
+use crate::svcs::{HeaderAction, HeaderPreset, HeaderRules};
+
+/// 逐跳头，`Transfer-Encoding` 由代理处理，不在其中
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+];
+
+/// 暴露后端实现的响应头
+const SERVER_HEADERS: [&str; 5] = [
+    "server",
+    "x-powered-by",
+    "x-aspnet-version",
+    "x-aspnetmvc-version",
+    "x-generator",
+];
+
+/// `RequestHeader` 与 `ResponseHeader` 共同的头操作
+pub trait Headers {
+    fn map(&self) -> &HeaderMap;
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()>;
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()>;
+    fn remove(&mut self, name: &HeaderName);
+}
+
+impl Headers for RequestHeader {
+    fn map(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+impl Headers for ResponseHeader {
+    fn map(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.insert_header(name, value)
+    }
+
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+/// 请求的信息，用于预设及模板中的变量
+pub struct RequestInfo<'a> {
+    /// 与代理直接连接的地址，追加到转发链中
+    pub peer_ip: Option<IpAddr>,
+    pub client_ip: Option<IpAddr>,
+    pub request_id: &'a str,
+    /// 客户端请求的 authority
+    pub host: Option<String>,
+    pub scheme: &'static str,
+    pub method: &'a str,
+    pub path: &'a str,
+}
+
+impl RequestInfo<'_> {
+    /// 模板中的变量，路由的命名捕获组在最后
+    pub fn vars(&self, route: &[(String, String)]) -> Vec<(String, String)> {
+        let client_ip = self.client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let mut vars = vec![
+            ("client_ip".to_string(), client_ip),
+            ("request_id".to_string(), self.request_id.to_string()),
+            ("host".to_string(), self.host.clone().unwrap_or_default()),
+            ("scheme".to_string(), self.scheme.to_string()),
+            ("method".to_string(), self.method.to_string()),
+            ("path".to_string(), self.path.to_string()),
+        ];
+        vars.extend(route.iter().cloned());
+        vars
+    }
+}
+
+/// 第一个路径匹配的路由及其命名捕获组
+pub fn match_route(rules: &HeaderRules, path: &str) -> Option<(usize, Vec<(String, String)>)> {
+    rules
+        .routes
+        .iter()
+        .enumerate()
+        .find_map(|(i, route)| route.path.captures(path).map(|captures| (i, captures)))
+}
+
+/// 改写发送给后端的请求头
+pub fn apply_request(
+    rules: &HeaderRules,
+    route: Option<usize>,
+    req: &mut RequestHeader,
+    info: &RequestInfo,
+    vars: &[(String, String)],
+) -> Result<()> {
+    for preset in &rules.presets {
+        match preset {
+            HeaderPreset::XForwarded => x_forwarded(req, info)?,
+            HeaderPreset::Forwarded => forwarded(req, info)?,
+            HeaderPreset::StripHopByHop => strip_hop_by_hop(req),
+            HeaderPreset::StripServer => {}
+        }
+    }
+    let route = route.and_then(|i| rules.routes.get(i));
+    let actions = rules
+        .request
+        .iter()
+        .chain(route.into_iter().flat_map(|r| &r.request));
+    apply(req, actions, vars)
+}
+
+/// 改写返回给客户端的响应头
+pub fn apply_response(
+    rules: &HeaderRules,
+    route: Option<usize>,
+    resp: &mut ResponseHeader,
+    vars: &[(String, String)],
+) -> Result<()> {
+    for preset in &rules.presets {
+        match preset {
+            HeaderPreset::StripHopByHop => strip_hop_by_hop(resp),
+            HeaderPreset::StripServer => {
+                for name in SERVER_HEADERS {
+                    resp.remove_header(name);
+                }
+            }
+            HeaderPreset::XForwarded | HeaderPreset::Forwarded => {}
+        }
+    }
+    let route = route.and_then(|i| rules.routes.get(i));
+    let actions = rules
+        .response
+        .iter()
+        .chain(route.into_iter().flat_map(|r| &r.response));
+    apply(resp, actions, vars)
+}
+
+/// 依次执行操作，名称或替换后的值不合法时跳过
+fn apply<'a, H: Headers>(
+    headers: &mut H,
+    actions: impl Iterator<Item = &'a HeaderAction>,
+    vars: &[(String, String)],
+) -> Result<()> {
+    let vars: Vec<(&str, &str)> = vars.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let value_of = |value: &str| {
+        let value = render(value, &vars, false);
+        match HeaderValue::from_str(&value) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!("Header value {value:?} is invalid, skipped");
+                None
+            }
+        }
+    };
+    for action in actions {
+        let names: Option<Vec<HeaderName>> = action
+            .names()
+            .into_iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .collect();
+        let Some(names) = names else {
+            warn!("Header name in {action:?} is invalid, skipped");
+            continue;
+        };
+        match action {
+            HeaderAction::Add { value, .. } => {
+                if let Some(value) = value_of(value) {
+                    headers.append(names[0].clone(), value)?;
+                }
+            }
+            HeaderAction::Set { value, .. } => {
+                if let Some(value) = value_of(value) {
+                    headers.insert(names[0].clone(), value)?;
+                }
+            }
+            HeaderAction::Remove { .. } => headers.remove(&names[0]),
+            HeaderAction::Rename { .. } => {
+                let (from, to) = (&names[0], &names[1]);
+                let values: Vec<HeaderValue> =
+                    headers.map().get_all(from).iter().cloned().collect();
+                if values.is_empty() {
+                    continue;
+                }
+                headers.remove(from);
+                headers.remove(to);
+                for value in values {
+                    headers.append(to.clone(), value)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn x_forwarded(req: &mut RequestHeader, info: &RequestInfo) -> Result<()> {
+    if let Some(ip) = info.peer_ip {
+        let prior: Vec<&str> = req
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        let value = if prior.is_empty() {
+            ip.to_string()
+        } else {
+            format!("{}, {ip}", prior.join(", "))
+        };
+        req.insert_header("x-forwarded-for", value)?;
+    }
+    req.insert_header("x-forwarded-proto", info.scheme)?;
+    if let Some(host) = &info.host {
+        req.insert_header("x-forwarded-host", host)?;
+    }
+    Ok(())
+}
+
+/// RFC 7239，追加一个元素，IPv6 地址及 host 需要加引号
+fn forwarded(req: &mut RequestHeader, info: &RequestInfo) -> Result<()> {
+    let mut pairs = Vec::new();
+    match info.peer_ip {
+        Some(IpAddr::V4(ip)) => pairs.push(format!("for={ip}")),
+        Some(IpAddr::V6(ip)) => pairs.push(format!("for=\"[{ip}]\"")),
+        None => pairs.push("for=unknown".to_string()),
+    }
+    if let Some(host) = &info.host {
+        pairs.push(format!("host=\"{host}\""));
+    }
+    pairs.push(format!("proto={}", info.scheme));
+    req.append_header(header::FORWARDED, pairs.join(";"))?;
+    Ok(())
+}
+
+/// 去掉逐跳头及 `Connection` 中列出的头，升级协议时保留 `Connection` 及 `Upgrade`
+fn strip_hop_by_hop<H: Headers>(headers: &mut H) {
+    if headers.map().contains_key(header::UPGRADE) {
+        return;
+    }
+    let listed: Vec<HeaderName> = headers
+        .map()
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(&name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(&HeaderName::from_static(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rules(value: serde_json::Value) -> HeaderRules {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+
+    fn info(path: &str) -> RequestInfo<'_> {
+        RequestInfo {
+            peer_ip: Some("10.0.0.2".parse().unwrap()),
+            client_ip: Some("203.0.113.7".parse().unwrap()),
+            request_id: "req-1",
+            host: Some("example.com".to_string()),
+            scheme: "https",
+            method: "GET",
+            path,
+        }
+    }
+
+    #[test]
+    fn actions_apply_in_order() {
+        let rules = rules(json!({
+            "response": [
+                { "action": "add", "name": "x-a", "value": "1" },
+                { "action": "add", "name": "x-a", "value": "2" },
+                { "action": "set", "name": "x-b", "value": "b" },
+                { "action": "remove", "name": "x-a" },
+                { "action": "add", "name": "x-a", "value": "3" },
+                { "action": "add", "name": "x-c", "value": "domain" }
+            ],
+            "routes": [{
+                "path": "^/api/",
+                "response": [
+                    { "action": "set", "name": "x-c", "value": "route" },
+                    { "action": "add", "name": "x-b", "value": "route" }
+                ]
+            }]
+        }));
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("x-b", "upstream").unwrap();
+
+        let (route, vars) = match_route(&rules, "/api/users").unwrap();
+        apply_response(&rules, Some(route), &mut resp, &vars).unwrap();
+        assert_eq!(values(&resp.headers, "x-a"), ["3"]);
+        assert_eq!(values(&resp.headers, "x-b"), ["b", "route"]);
+        assert_eq!(values(&resp.headers, "x-c"), ["route"]);
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        assert!(match_route(&rules, "/static/app.js").is_none());
+        apply_response(&rules, None, &mut resp, &[]).unwrap();
+        assert_eq!(values(&resp.headers, "x-c"), ["domain"]);
+    }
+
+    #[test]
+    fn rename_moves_all_values() {
+        let rules = rules(json!({
+            "request": [
+                { "action": "rename", "from": "x-old", "to": "x-new" },
+                { "action": "rename", "from": "x-missing", "to": "x-kept" }
+            ]
+        }));
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("x-old", "1").unwrap();
+        req.append_header("x-old", "2").unwrap();
+        req.insert_header("x-new", "replaced").unwrap();
+        req.insert_header("x-kept", "kept").unwrap();
+
+        apply_request(&rules, None, &mut req, &info("/"), &[]).unwrap();
+        assert!(values(&req.headers, "x-old").is_empty());
+        assert_eq!(values(&req.headers, "x-new"), ["1", "2"]);
+        assert_eq!(values(&req.headers, "x-kept"), ["kept"]);
+    }
+
+    #[test]
+    fn templates_and_invalid_actions() {
+        let rules = rules(json!({
+            "request": [
+                { "action": "set", "name": "x-client", "value": "{{client_ip}} {{request_id}}" },
+                { "action": "set", "name": "x-user", "value": "{{user}}" },
+                { "action": "set", "name": "bad name", "value": "skipped" },
+                { "action": "set", "name": "x-path", "value": "{{path}}\n" }
+            ],
+            "routes": [{ "path": "^/users/(?P<user>[^/]+)" }]
+        }));
+        let mut req = RequestHeader::build("GET", b"/users/alice", None).unwrap();
+        let info = info("/users/alice");
+
+        let (route, captures) = match_route(&rules, info.path).unwrap();
+        let vars = info.vars(&captures);
+        apply_request(&rules, Some(route), &mut req, &info, &vars).unwrap();
+        assert_eq!(values(&req.headers, "x-client"), ["203.0.113.7 req-1"]);
+        assert_eq!(values(&req.headers, "x-user"), ["alice"]);
+        assert!(values(&req.headers, "x-path").is_empty());
+        assert_eq!(req.headers.len(), 2);
+    }
+
+    #[test]
+    fn presets_run_before_actions() {
+        let rules = rules(json!({
+            "presets": ["x_forwarded", "strip_hop_by_hop"],
+            "request": [{ "action": "set", "name": "x-forwarded-proto", "value": "http" }]
+        }));
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-forwarded-for", "198.51.100.1")
+            .unwrap();
+        req.insert_header("connection", "keep-alive, x-secret")
+            .unwrap();
+        req.insert_header("x-secret", "1").unwrap();
+
+        apply_request(&rules, None, &mut req, &info("/"), &[]).unwrap();
+        assert_eq!(
+            values(&req.headers, "x-forwarded-for"),
+            ["198.51.100.1, 10.0.0.2"]
+        );
+        assert_eq!(values(&req.headers, "x-forwarded-proto"), ["http"]);
+        assert_eq!(values(&req.headers, "x-forwarded-host"), ["example.com"]);
+        assert!(values(&req.headers, "x-secret").is_empty());
+    }
+}
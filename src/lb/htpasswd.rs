@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{LazyLock, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use log::{info, warn};
+
+/// 检查文件是否修改的最小间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 文件中没有用户时代替未知用户的密码哈希
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| bcrypt::hash("dummy", bcrypt::DEFAULT_COST).unwrap_or_default());
+
+struct Users {
+    /// 用户名到密码哈希的映射
+    hashes: HashMap<String, String>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+/// htpasswd 文件，只支持 bcrypt（`$2y$` 等）及 argon2（`$argon2id$` 等）的哈希
+/// 文件修改后自动重新加载，加载失败时保留上一次的内容
+pub struct Htpasswd {
+    path: PathBuf,
+    users: RwLock<Users>,
+}
+
+impl Htpasswd {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let (hashes, modified) = read(&path)?;
+        Ok(Self {
+            path,
+            users: RwLock::new(Users {
+                hashes,
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    /// 文件修改时重新加载
+    fn refresh(&self) {
+        {
+            let users = self.users.read().unwrap();
+            if users.checked.elapsed() < CHECK_INTERVAL {
+                return;
+            }
+        }
+        let mut users = self.users.write().unwrap();
+        users.checked = Instant::now();
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == users.modified {
+            return;
+        }
+        match read(&self.path) {
+            Ok((hashes, modified)) => {
+                info!("Htpasswd reloaded {}", self.path.display());
+                users.hashes = hashes;
+                users.modified = modified;
+            }
+            Err(e) => warn!("Htpasswd {e}"),
+        }
+    }
+
+    /// 用户的密码哈希
+    pub fn hash_of(&self, user: &str) -> Option<String> {
+        self.refresh();
+        self.users.read().unwrap().hashes.get(user).cloned()
+    }
+
+    /// 代替未知用户校验的哈希，使用文件中的哈希以保持相同的计算量
+    pub fn dummy_hash(&self) -> String {
+        let users = self.users.read().unwrap();
+        users
+            .hashes
+            .values()
+            .min()
+            .map_or_else(|| DUMMY_HASH.clone(), String::clone)
+    }
+}
+
+/// 校验密码，计算量较大，需要在阻塞线程中执行
+pub fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        return PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+    }
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+fn read(path: &Path) -> Result<(HashMap<String, String>, Option<SystemTime>), String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Read {} failed: {e}", path.display()))?;
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut hashes = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((user, hash)) = line.split_once(':') else {
+            warn!("Htpasswd {} line {} is invalid", path.display(), i + 1);
+            continue;
+        };
+        let supported = ["$2a$", "$2b$", "$2y$", "$argon2"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix));
+        if !supported {
+            warn!(
+                "Htpasswd {} line {}: only bcrypt and argon2 hashes are supported",
+                path.display(),
+                i + 1
+            );
+            continue;
+        }
+        hashes.insert(user.to_string(), hash.to_string());
+    }
+    Ok((hashes, modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    use super::*;
+
+    fn write_file(content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("htpasswd-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn verify_bcrypt_and_argon2() {
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify("secret", &bcrypt_hash));
+        assert!(!verify("wrong", &bcrypt_hash));
+
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let argon2_hash = Argon2::default()
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify("secret", &argon2_hash));
+        assert!(!verify("wrong", &argon2_hash));
+        assert!(!verify("secret", "$argon2id$invalid"));
+        assert!(!verify("secret", "plain"));
+    }
+
+    #[test]
+    fn load_skips_unsupported_lines() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let path = write_file(&format!(
+            "# comment\n\nalice:{hash}\nbob:{{SHA}}abc\ninvalid\n"
+        ));
+        let users = Htpasswd::load(&path).unwrap();
+        assert_eq!(users.hash_of("alice"), Some(hash.clone()));
+        assert_eq!(users.hash_of("bob"), None);
+        assert_eq!(users.hash_of("invalid"), None);
+        // 未知用户使用文件中的哈希校验，计算量相同
+        assert_eq!(users.dummy_hash(), hash);
+        fs::remove_file(&path).unwrap();
+        assert!(Htpasswd::load(&path).is_err());
+    }
+
+    #[test]
+    fn dummy_hash_without_users() {
+        let path = write_file("");
+        let users = Htpasswd::load(&path).unwrap();
+        let dummy = users.dummy_hash();
+        assert!(dummy.starts_with("$2"));
+        assert!(!verify("", &dummy));
+        fs::remove_file(&path).unwrap();
+    }
+}
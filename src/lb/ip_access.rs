@@ -0,0 +1,337 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::{error, info, warn};
+use notify::{PollWatcher, RecursiveMode, Watcher};
+use pingora::{
+    http::RequestHeader, server::ShutdownWatch, services::background::BackgroundService,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, RwLock},
+    time::sleep,
+};
+
+/// 不支持 inotify 等文件事件时，轮询文件变化的间隔
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// 合并短时间内连续的文件事件
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// 地址列表，`deny` 优先，`allow` 不为空时只允许其中的地址
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IpRules {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<IpNet>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<IpNet>,
+}
+
+impl IpRules {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// 拒绝时返回原因
+    fn check(&self, ip: Option<IpAddr>) -> Result<(), String> {
+        let Some(ip) = ip else {
+            if self.allow.is_empty() {
+                return Ok(());
+            }
+            return Err("client address unknown".to_string());
+        };
+        if let Some(net) = self.deny.iter().find(|net| net.contains(&ip)) {
+            return Err(format!("{ip} denied by {net}"));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(&ip)) {
+            return Err(format!("{ip} not in allow list"));
+        }
+        Ok(())
+    }
+}
+
+/// 全局及各域名的地址列表
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IpAccessList {
+    #[serde(default, skip_serializing_if = "IpRules::is_empty")]
+    pub global: IpRules,
+    /// 键为请求的 Host 或域名
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub domains: HashMap<String, IpRules>,
+    /// 可信的负载均衡地址，来自这些地址的请求使用 `X-Forwarded-For` 中的客户端地址
+    /// 尚未支持 PROXY 协议，监听器不解析 PROXY 协议头
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// 地址访问控制，文件中的列表与管理 API 设置的列表同时生效
+#[derive(Default)]
+pub struct IpAccess {
+    /// 通过管理 API 设置
+    pub runtime: RwLock<IpAccessList>,
+    /// 从文件加载，文件变化时整体替换
+    pub file: RwLock<IpAccessList>,
+}
+
+impl IpAccess {
+    /// 客户端的地址，直接连接的地址可信时取 `X-Forwarded-For` 中从右往左第一个不可信的地址
+    /// IPv4 映射的 IPv6 地址转换为 IPv4 地址
+    pub async fn client_ip(&self, peer: Option<IpAddr>, req: &RequestHeader) -> Option<IpAddr> {
+        let mut ip = peer?.to_canonical();
+        let (runtime, file) = (self.runtime.read().await, self.file.read().await);
+        let trusted = |ip: &IpAddr| {
+            runtime
+                .trusted_proxies
+                .iter()
+                .chain(&file.trusted_proxies)
+                .any(|net| net.contains(ip))
+        };
+        if !trusted(&ip) {
+            return Some(ip);
+        }
+        let forwarded: Vec<&str> = req
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+        for addr in forwarded.into_iter().rev() {
+            let Ok(addr) = addr.trim().parse::<IpAddr>() else {
+                break;
+            };
+            ip = addr.to_canonical();
+            if !trusted(&ip) {
+                break;
+            }
+        }
+        Some(ip)
+    }
+
+    /// 依次检查全局及请求的 Host 或处理请求的域名的列表，拒绝时返回原因
+    pub async fn check(&self, names: [Option<&str>; 2], ip: Option<IpAddr>) -> Result<(), String> {
+        let (runtime, file) = (self.runtime.read().await, self.file.read().await);
+        for list in [&*runtime, &*file] {
+            list.global.check(ip).map_err(|e| format!("global: {e}"))?;
+        }
+        for list in [&*runtime, &*file] {
+            let found = names
+                .into_iter()
+                .flatten()
+                .find_map(|name| list.domains.get_key_value(name));
+            if let Some((name, rules)) = found {
+                rules.check(ip).map_err(|e| format!("{name}: {e}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 是否有需要检查的列表
+    pub async fn is_empty(&self) -> bool {
+        let (runtime, file) = (self.runtime.read().await, self.file.read().await);
+        [&*runtime, &*file]
+            .iter()
+            .all(|list| list.global.is_empty() && list.domains.is_empty())
+    }
+}
+
+/// 从 JSON 或 YAML 文件加载地址列表，文件变化时自动重新加载
+/// 文件解析失败时保留上一次成功加载的内容
+pub struct IpAccessFile {
+    path: PathBuf,
+    access: Arc<IpAccess>,
+}
+
+impl IpAccessFile {
+    pub fn new(path: impl Into<PathBuf>, access: Arc<IpAccess>) -> Self {
+        Self {
+            path: path.into(),
+            access,
+        }
+    }
+
+    /// 重新加载文件
+    pub async fn reload(&self) {
+        match load(&self.path) {
+            Ok(list) => {
+                *self.access.file.write().await = list;
+                info!("IpAccessFile loaded {}", self.path.display());
+            }
+            Err(e) => warn!("IpAccessFile {e}"),
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<IpAccessList, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Read {} failed: {e}", path.display()))?;
+    let list = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        _ => serde_json::from_str(&content).map_err(|e| e.to_string()),
+    };
+    list.map_err(|e| format!("Parse {} failed: {e}", path.display()))
+}
+
+#[async_trait]
+impl BackgroundService for IpAccessFile {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        self.reload().await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let handler = move |event: notify::Result<notify::Event>| {
+            let _ = tx.send(event);
+        };
+        let mut watcher: Box<dyn Watcher + Send> =
+            match notify::recommended_watcher(handler.clone()) {
+                Ok(watcher) => Box::new(watcher),
+                Err(e) => {
+                    warn!("IpAccessFile file events unavailable, fallback to polling: {e}");
+                    let config = notify::Config::default().with_poll_interval(POLL_INTERVAL);
+                    match PollWatcher::new(handler, config) {
+                        Ok(watcher) => Box::new(watcher),
+                        Err(e) => {
+                            error!("IpAccessFile watch {} failed: {e}", self.path.display());
+                            return;
+                        }
+                    }
+                }
+            };
+        // 监听文件所在的目录，文件被重命名替换后仍能收到事件
+        let target = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if let Err(e) = watcher.watch(target, RecursiveMode::NonRecursive) {
+            error!("IpAccessFile watch {} failed: {e}", target.display());
+            return;
+        }
+
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                event = rx.recv() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    match event {
+                        Ok(event)
+                            if !event.paths.iter().any(|p| p.file_name() == self.path.file_name()) =>
+                        {
+                            continue;
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("IpAccessFile watch event error: {e}");
+                            continue;
+                        }
+                    }
+                    sleep(DEBOUNCE).await;
+                    while rx.try_recv().is_ok() {}
+                    self.reload().await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(allow: &[&str], deny: &[&str]) -> IpRules {
+        let nets = |list: &[&str]| list.iter().map(|net| net.parse().unwrap()).collect();
+        IpRules {
+            allow: nets(allow),
+            deny: nets(deny),
+        }
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn deny_takes_precedence() {
+        let rules = rules(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert!(rules.check(ip("10.2.3.4")).is_ok());
+        assert_eq!(
+            rules.check(ip("10.1.2.3")).unwrap_err(),
+            "10.1.2.3 denied by 10.1.0.0/16"
+        );
+        assert_eq!(
+            rules.check(ip("192.168.1.1")).unwrap_err(),
+            "192.168.1.1 not in allow list"
+        );
+    }
+
+    #[test]
+    fn empty_allow_list_allows_others() {
+        let rules = rules(&[], &["2001:db8::/32"]);
+        assert!(rules.check(ip("2001:db8::1")).is_err());
+        assert!(rules.check(ip("2001:db9::1")).is_ok());
+        assert!(rules.check(ip("127.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn unknown_address() {
+        assert!(rules(&[], &["10.0.0.0/8"]).check(None).is_ok());
+        assert_eq!(
+            rules(&["10.0.0.0/8"], &[]).check(None).unwrap_err(),
+            "client address unknown"
+        );
+    }
+
+    #[tokio::test]
+    async fn client_ip_from_trusted_proxies() {
+        let access = IpAccess::default();
+        access.runtime.write().await.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("x-forwarded-for", "203.0.113.9, 198.51.100.7")
+            .unwrap();
+        req.append_header("x-forwarded-for", "10.0.0.2").unwrap();
+
+        let untrusted = ip("192.0.2.1");
+        assert_eq!(access.client_ip(untrusted, &req).await, untrusted);
+        // 从右往左跳过可信的地址，取第一个不可信的地址
+        assert_eq!(
+            access.client_ip(ip("10.0.0.1"), &req).await,
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            access.client_ip(ip("::ffff:192.0.2.1"), &req).await,
+            untrusted
+        );
+    }
+
+    #[tokio::test]
+    async fn check_global_then_domain() {
+        let access = IpAccess::default();
+        {
+            let mut runtime = access.runtime.write().await;
+            runtime.global = rules(&[], &["192.0.2.0/24"]);
+            runtime
+                .domains
+                .insert("example.com".to_string(), rules(&["10.0.0.0/8"], &[]));
+        }
+        let names = [Some("example.com"), None];
+        assert!(access.check(names, ip("10.0.0.1")).await.is_ok());
+        assert!(access
+            .check(names, ip("192.0.2.1"))
+            .await
+            .unwrap_err()
+            .starts_with("global:"));
+        assert!(access
+            .check(names, ip("172.16.0.1"))
+            .await
+            .unwrap_err()
+            .starts_with("example.com:"));
+        assert!(access.check([None, None], ip("172.16.0.1")).await.is_ok());
+    }
+}
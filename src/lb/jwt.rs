@@ -0,0 +1,375 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// JWKS 地址的密钥定期重新获取的间隔
+const JWKS_TTL: Duration = Duration::from_secs(600);
+/// 遇到未知的 `kid` 时重新获取 JWKS 的最小间隔
+const JWKS_MIN_INTERVAL: Duration = Duration::from_secs(30);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// JWT 的校验方式，密钥可以同时配置多个来源
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct JwtAuth {
+    /// HS 算法的密钥
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// RS、PS 或 ES 算法的 PEM 公钥文件
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_file: Option<PathBuf>,
+    /// 定期重新获取，遇到未知的 `kid` 时立即重新获取
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_url: Option<String>,
+    /// 允许的算法，为空时接受与密钥类型相符的算法
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub algorithms: Vec<Algorithm>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    /// 为空时不检查 `aud`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audience: Vec<String>,
+    /// 检查 `exp` 及 `nbf` 时允许的时钟误差，单位秒
+    #[serde(default = "default_leeway_secs")]
+    pub leeway_secs: u64,
+}
+
+fn default_leeway_secs() -> u64 {
+    60
+}
+
+struct Jwks {
+    keys: JwkSet,
+    fetched: Option<Instant>,
+}
+
+/// 加载好密钥的校验器
+pub struct JwtVerifier {
+    config: JwtAuth,
+    /// 由 `secret` 及 `public_key` 得到的密钥
+    keys: Vec<DecodingKey>,
+    jwks: Arc<RwLock<Jwks>>,
+    /// 同一时间只获取一次 JWKS
+    fetching: Arc<AtomicBool>,
+    client: reqwest::Client,
+}
+
+impl JwtVerifier {
+    /// 读取密钥文件并获取 JWKS，任一来源失败时返回错误
+    pub async fn new(config: JwtAuth) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        if let Some(secret) = &config.secret {
+            keys.push(DecodingKey::from_secret(secret.as_bytes()));
+        }
+        if let Some(path) = &config.public_key {
+            let pem = fs::read(path).map_err(|e| format!("Read {} failed: {e}", path.display()))?;
+            let key = DecodingKey::from_rsa_pem(&pem)
+                .or_else(|_| DecodingKey::from_ec_pem(&pem))
+                .map_err(|e| format!("Invalid public key {}: {e}", path.display()))?;
+            keys.push(key);
+        }
+        let mut jwks = match &config.jwks_file {
+            Some(path) => read_jwks(path)?,
+            None => JwkSet { keys: Vec::new() },
+        };
+        if keys.is_empty() && jwks.keys.is_empty() && config.jwks_url.is_none() {
+            return Err("JWT needs one of secret, public_key, jwks_file and jwks_url".to_string());
+        }
+        let client = reqwest::Client::builder()
+            .timeout(JWKS_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut fetched = None;
+        if let Some(url) = &config.jwks_url {
+            jwks.keys.extend(fetch(&client, url).await?.keys);
+            fetched = Some(Instant::now());
+        }
+        Ok(Self {
+            config,
+            keys,
+            jwks: Arc::new(RwLock::new(Jwks {
+                keys: jwks,
+                fetched,
+            })),
+            fetching: Arc::new(AtomicBool::new(false)),
+            client,
+        })
+    }
+
+    /// 密钥过期时在后台重新获取 JWKS 地址的密钥，请求不等待获取完成
+    /// 保留文件中的密钥，获取失败时保留上一次的密钥
+    fn refresh(&self, force: bool) {
+        let Some(url) = &self.config.jwks_url else {
+            return;
+        };
+        let interval = if force { JWKS_MIN_INTERVAL } else { JWKS_TTL };
+        let fetched = self.jwks.read().unwrap().fetched;
+        if fetched.is_some_and(|at| at.elapsed() < interval) {
+            return;
+        }
+        if self.fetching.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let (url, file) = (url.clone(), self.config.jwks_file.clone());
+        let (jwks, fetching, client) = (
+            self.jwks.clone(),
+            self.fetching.clone(),
+            self.client.clone(),
+        );
+        tokio::spawn(async move {
+            let result = fetch(&client, &url).await;
+            {
+                let mut jwks = jwks.write().unwrap();
+                jwks.fetched = Some(Instant::now());
+                match result {
+                    Ok(set) => {
+                        let mut keys = file
+                            .and_then(|path| read_jwks(&path).ok())
+                            .map_or_else(Vec::new, |set| set.keys);
+                        keys.extend(set.keys);
+                        info!("JwtVerifier fetched {} keys from {url}", keys.len());
+                        jwks.keys = JwkSet { keys };
+                    }
+                    Err(e) => warn!("JwtVerifier {e}"),
+                }
+            }
+            fetching.store(false, Ordering::Release);
+        });
+    }
+
+    fn validation(&self, alg: Algorithm) -> Validation {
+        let mut validation = Validation::new(alg);
+        if !self.config.algorithms.is_empty() {
+            validation.algorithms = self.config.algorithms.clone();
+        }
+        validation.leeway = self.config.leeway_secs;
+        validation.validate_nbf = true;
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if self.config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.config.audience);
+        }
+        validation
+    }
+
+    /// 校验令牌，返回其中的声明
+    /// 遇到未知的 `kid` 时在后台重新获取 JWKS，获取完成前使用该密钥的令牌校验失败
+    pub fn verify(&self, token: &str) -> Result<Value, String> {
+        let header = decode_header(token).map_err(|e| format!("Invalid token: {e}"))?;
+        if !self.config.algorithms.is_empty() && !self.config.algorithms.contains(&header.alg) {
+            return Err(format!("Algorithm {:?} is not allowed", header.alg));
+        }
+        let validation = self.validation(header.alg);
+        self.refresh(false);
+        let result = self.try_keys(token, header.kid.as_deref(), &validation);
+        if let Err(Failure::UnknownKey) = result {
+            self.refresh(true);
+        }
+        result.map_err(|failure| match failure {
+            Failure::UnknownKey => match header.kid {
+                Some(kid) => format!("Unknown key id {kid}"),
+                None => "No key matches the token".to_string(),
+            },
+            Failure::Invalid(e) => format!("Invalid token: {e}"),
+        })
+    }
+
+    /// 依次尝试 JWKS 中的密钥及其它密钥，JWKS 中的密钥按令牌的 `kid` 筛选
+    /// JWKS 中没有对应的密钥且配置了 JWKS 地址时返回 `UnknownKey`，以便重新获取
+    fn try_keys(
+        &self,
+        token: &str,
+        kid: Option<&str>,
+        validation: &Validation,
+    ) -> Result<Value, Failure> {
+        let jwks = self.jwks.read().unwrap();
+        let matched: Vec<DecodingKey> = jwks
+            .keys
+            .keys
+            .iter()
+            .filter(|jwk| kid.is_none() || jwk.common.key_id.as_deref() == kid)
+            .filter_map(|jwk: &Jwk| DecodingKey::from_jwk(jwk).ok())
+            .collect();
+        let unknown = kid.is_some() && matched.is_empty();
+        let mut failure = None;
+        for key in matched.iter().chain(&self.keys) {
+            match decode::<Value>(token, key, validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => failure = Some(e),
+            }
+        }
+        match failure {
+            Some(e) if !unknown || self.config.jwks_url.is_none() => Err(Failure::Invalid(e)),
+            _ => Err(Failure::UnknownKey),
+        }
+    }
+}
+
+enum Failure {
+    UnknownKey,
+    Invalid(jsonwebtoken::errors::Error),
+}
+
+fn read_jwks(path: &Path) -> Result<JwkSet, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Read {} failed: {e}", path.display()))?;
+    serde_json::from_str(&content).map_err(|e| format!("Invalid JWKS {}: {e}", path.display()))
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<JwkSet, String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Fetch JWKS {url} failed: {e}"))?;
+    response
+        .json()
+        .await
+        .map_err(|e| format!("Invalid JWKS {url}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Mutex,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use axum::{extract::State, routing::get, Json, Router};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn config() -> JwtAuth {
+        JwtAuth {
+            secret: None,
+            public_key: None,
+            jwks_file: None,
+            jwks_url: None,
+            algorithms: Vec::new(),
+            issuer: Some("https://issuer.example.com".to_string()),
+            audience: vec!["api".to_string()],
+            leeway_secs: 0,
+        }
+    }
+
+    fn token(kid: Option<&str>, secret: &[u8], exp_offset: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let claims = json!({
+            "sub": "alice",
+            "iss": "https://issuer.example.com",
+            "aud": "api",
+            "exp": now + exp_offset,
+        });
+        let mut header = Header::default();
+        header.kid = kid.map(str::to_string);
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn jwk(kid: &str, secret: &[u8]) -> Value {
+        json!({ "kty": "oct", "kid": kid, "alg": "HS256", "k": URL_SAFE_NO_PAD.encode(secret) })
+    }
+
+    #[tokio::test]
+    async fn verify_with_secret() {
+        let verifier = JwtVerifier::new(JwtAuth {
+            secret: Some(String::from_utf8(SECRET.to_vec()).unwrap()),
+            ..config()
+        })
+        .await
+        .unwrap();
+        let claims = verifier.verify(&token(None, SECRET, 60)).unwrap();
+        assert_eq!(claims["sub"], "alice");
+        assert!(verifier.verify(&token(None, SECRET, -60)).is_err());
+        assert!(verifier.verify(&token(None, b"other", 60)).is_err());
+        assert!(verifier.verify("not a token").is_err());
+
+        let restricted = JwtVerifier::new(JwtAuth {
+            secret: Some(String::from_utf8(SECRET.to_vec()).unwrap()),
+            algorithms: vec![Algorithm::HS512],
+            ..config()
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            restricted.verify(&token(None, SECRET, 60)).unwrap_err(),
+            "Algorithm HS256 is not allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn needs_a_key_source() {
+        assert!(JwtVerifier::new(config()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn jwks_refreshes_in_background() {
+        let keys = Arc::new(Mutex::new(json!({ "keys": [jwk("k1", SECRET)] })));
+        let app = Router::new()
+            .route(
+                "/jwks",
+                get(|State(keys): State<Arc<Mutex<Value>>>| async move {
+                    Json(keys.lock().unwrap().clone())
+                }),
+            )
+            .with_state(keys.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let verifier = JwtVerifier::new(JwtAuth {
+            jwks_url: Some(format!("http://{addr}/jwks")),
+            ..config()
+        })
+        .await
+        .unwrap();
+        assert!(verifier.verify(&token(Some("k1"), SECRET, 60)).is_ok());
+
+        let rotated = b"fedcba9876543210fedcba9876543210";
+        *keys.lock().unwrap() = json!({ "keys": [jwk("k1", SECRET), jwk("k2", rotated)] });
+        verifier.jwks.write().unwrap().fetched = None;
+        let new_token = token(Some("k2"), rotated, 60);
+        // 不等待获取完成，未知的密钥先返回错误
+        assert_eq!(
+            verifier.verify(&new_token).unwrap_err(),
+            "Unknown key id k2"
+        );
+        let mut verified = false;
+        for _ in 0..50 {
+            if verifier.verify(&new_token).is_ok() {
+                verified = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(verified);
+    }
+}
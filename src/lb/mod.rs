@@ -0,0 +1,1239 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::{header, Method, Uri};
+use log::warn;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    prelude::HttpPeer,
+    proxy::{ProxyHttp, Session},
+    Error, Result,
+};
+use serde::Serialize;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::svcs::{
+    parse_authority, Authority, DomainConfig, ErrorDetail, ResponseRewrite, Timeouts,
+    UpstreamsHealthCheck,
+};
+
+pub use auth::{ApiKey, AuthFailure, AuthMethod, AuthRule, AuthStore};
+pub use concurrency::{ConcurrencyLimiters, ConcurrencyStatus};
+pub use cors::{CorsHeaders, CorsPolicy, CorsStore, OriginPattern};
+pub use dry_run::{AccessCheck, DryRunError, DryRunReport, HeaderChange, UpstreamRequest};
+pub use error::{ErrorCode, ErrorResponse};
+pub use fallback::{Fallback, Fallbacks};
+pub use ip_access::{IpAccess, IpAccessFile, IpAccessList, IpRules};
+pub use jwt::JwtAuth;
+pub use oidc::{OidcConfig, OidcStore, ProviderMetadata};
+pub use pages::{Maintenance, PageStore, PageTemplate, SitePages, DEFAULT_SITE};
+pub use rate_limit::{
+    RateLimit, RateLimitAlgorithm, RateLimitKey, RateLimitStatus, RateLimiter, MAX_WINDOW_SECS,
+};
+pub use timeout::TimeoutKind;
+pub use url_rules::{
+    evaluate_url_rules, UrlAction, UrlOutcome, UrlRedirect, UrlRule, UrlRuleStore,
+};
+
+use concurrency::ConcurrencyPermit;
+use headers::RequestInfo;
+use oidc::OidcAction;
+use rewrite::{BodyRewriter, Rewriter};
+
+mod auth;
+mod concurrency;
+mod cors;
+mod dry_run;
+mod error;
+mod fallback;
+mod headers;
+mod htpasswd;
+mod ip_access;
+mod jwt;
+mod oidc;
+mod pages;
+mod rate_limit;
+mod respond;
+mod rewrite;
+mod timeout;
+mod url_rules;
+
+/// 请求 ID 所在的请求头及响应头
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// 接受客户端传入的请求 ID 的最大长度
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// 代理与管理 API 共享的运行时配置，通过管理 API 修改后立即生效
+#[derive(Clone, Default)]
+pub struct Policies {
+    /// 错误页面及维护模式
+    pub pages: PageStore,
+    /// 未知域名及缺少 Host 的请求的处理方式
+    pub fallbacks: Arc<RwLock<Fallbacks>>,
+    /// 地址改写及重定向规则
+    pub url_rules: UrlRuleStore,
+    /// 限流规则及计数器
+    pub rate_limits: Arc<RateLimiter>,
+    /// 各域名同时处理的请求数
+    pub concurrency: Arc<ConcurrencyLimiters>,
+    /// 全局及各域名的地址访问控制
+    pub ip_access: Arc<IpAccess>,
+    /// 各域名的认证规则
+    pub auth: Arc<AuthStore>,
+    /// 各域名的 OpenID Connect 登录
+    pub oidc: Arc<OidcStore>,
+    /// 各域名的跨域策略
+    pub cors: Arc<CorsStore>,
+}
+
+pub struct LB {
+    pub backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+    /// 域名的配置，用于超时等请求相关的设置
+    pub domains: Arc<RwLock<HashMap<String, Arc<DomainConfig>>>>,
+    pub policies: Policies,
+}
+
+/// 由代理直接返回的响应，不访问后端
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Reply {
+    /// 重定向，`location` 为完整的地址，`cookies` 为 `Set-Cookie` 响应头
+    Redirect {
+        status: u16,
+        location: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        cookies: Vec<String>,
+    },
+    /// 未知域名或缺少 Host 时的固定响应
+    Static {
+        status: u16,
+        #[serde(flatten)]
+        page: PageTemplate,
+    },
+    Maintenance {
+        status: u16,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        page: Option<PageTemplate>,
+    },
+    /// 超过限流规则的限制
+    RateLimited(RateLimitStatus),
+    /// 认证失败
+    AuthFailed(AuthFailure),
+    /// 跨域的预检请求，返回 204
+    Preflight { headers: CorsHeaders },
+}
+
+/// 请求的上下文
+pub struct RequestCtx {
+    /// 收到请求的时间
+    start: Instant,
+    /// 请求 ID，客户端传入合法的 `X-Request-Id` 时沿用，否则生成
+    request_id: String,
+    /// 客户端请求使用的协议
+    scheme: &'static str,
+    /// 请求的 Host
+    host: Option<String>,
+    /// 请求的 Host 中的端口
+    port: Option<u16>,
+    /// 与代理直接连接的地址
+    peer_ip: Option<IpAddr>,
+    /// 客户端的地址，来自可信的负载均衡时取自 `X-Forwarded-For`
+    client_ip: Option<IpAddr>,
+    /// 处理请求的域名，通常与 Host 相同，使用默认后端时为默认后端的域名
+    domain: Option<String>,
+    /// 处理请求的域名的配置，域名未添加时为空
+    config: Option<Arc<DomainConfig>>,
+    /// 发送给后端的 Host，为空时沿用请求的 Host
+    upstream_host: Option<String>,
+    /// 响应改写的配置，别名优先使用自己的配置
+    rewrite: Option<ResponseRewrite>,
+    /// 地址规则改写后发送给后端的路径及查询参数，没有匹配的规则时为空
+    upstream_uri: Option<String>,
+    /// 匹配的地址规则的序号
+    url_rules: Vec<usize>,
+    /// 访问控制的检查结果
+    access: Vec<AccessCheck>,
+    /// 最严格的限流规则的状态，添加到响应头中
+    rate_limit: Option<RateLimitStatus>,
+    /// 认证规则转发给后端的请求头，值为空时只删除客户端传入的请求头
+    auth_forward: Vec<(String, Option<String>)>,
+    /// 跨域策略的响应头，域名没有跨域策略时为空
+    cors: Option<CorsHeaders>,
+    /// 不转发给后端的 Cookie
+    strip_cookies: Vec<String>,
+    /// 不转发给后端的查询参数，例如认证使用的 API key
+    strip_query: Option<String>,
+    /// 添加到响应中的 `Set-Cookie`
+    set_cookies: Vec<String>,
+    /// 模拟请求，不消耗限流的配额
+    dry_run: bool,
+    /// 同时处理的请求数的限制中占用的位置，请求结束时释放
+    permit: Option<ConcurrencyPermit>,
+    /// 开始与后端建立连接的时间，用于区分连接超时
+    connect_start: Option<Instant>,
+    /// 已收到后端的响应头
+    response_started: bool,
+    /// 后端响应的状态码，5xx 视为失败，用于调整自适应的限制
+    upstream_status: Option<u16>,
+    /// 改写响应体中的地址，不需要改写时为空
+    body_rewriter: Option<BodyRewriter>,
+    /// 头改写规则中匹配的路由
+    header_route: Option<usize>,
+    /// 头改写规则模板中的变量，发送请求头时确定
+    header_vars: Vec<(String, String)>,
+}
+
+impl RequestCtx {
+    fn new() -> Self {
+        RequestCtx {
+            start: Instant::now(),
+            request_id: Uuid::new_v4().to_string(),
+            scheme: "http",
+            host: None,
+            port: None,
+            peer_ip: None,
+            client_ip: None,
+            domain: None,
+            config: None,
+            upstream_host: None,
+            rewrite: None,
+            upstream_uri: None,
+            url_rules: Vec::new(),
+            access: Vec::new(),
+            rate_limit: None,
+            auth_forward: Vec::new(),
+            cors: None,
+            strip_cookies: Vec::new(),
+            strip_query: None,
+            set_cookies: Vec::new(),
+            dry_run: false,
+            permit: None,
+            connect_start: None,
+            response_started: false,
+            upstream_status: None,
+            body_rewriter: None,
+            header_route: None,
+            header_vars: Vec::new(),
+        }
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        self.config
+            .as_ref()
+            .map(|config| config.timeouts)
+            .unwrap_or_default()
+    }
+
+    fn error_detail(&self) -> ErrorDetail {
+        self.config
+            .as_ref()
+            .map(|config| config.error_detail)
+            .unwrap_or_default()
+    }
+}
+
+/// 客户端传入的请求 ID 只接受长度有限的可见 ASCII 字符
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// 请求的 Host，已规范化，不合法时返回 `InvalidHost` 错误
+/// absolute-form 的请求地址优先于 Host 请求头，HTTP/2 的 `:authority` 同样在请求地址中
+fn request_host(req: &RequestHeader) -> Result<Option<Authority>> {
+    let host = match req.uri.authority() {
+        Some(authority) => authority.as_str(),
+        None => match req.headers.get(header::HOST) {
+            Some(value) => value.to_str().map_err(|_| {
+                ErrorCode::InvalidHost.error("Host header is not visible ASCII".to_string())
+            })?,
+            None => return Ok(None),
+        },
+    };
+    if host.is_empty() {
+        return Ok(None);
+    }
+    let authority =
+        parse_authority(host).map_err(|e| ErrorCode::InvalidHost.error(e.to_string()))?;
+    Ok(Some(authority))
+}
+
+/// 客户端请求使用的协议
+fn scheme(session: &Session) -> &'static str {
+    let tls = session
+        .digest()
+        .is_some_and(|digest| digest.ssl_digest.is_some());
+    if tls {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+impl LB {
+    /// 确定请求 ID 及处理请求的域名，返回由代理直接返回的响应
+    /// 只读取请求头，模拟请求使用相同的逻辑
+    async fn route(&self, req: &RequestHeader, ctx: &mut RequestCtx) -> Result<Option<Reply>> {
+        if let Some(id) = req
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+        {
+            if valid_request_id(id) {
+                ctx.request_id = id.to_string();
+            }
+        }
+        ctx.client_ip = self.policies.ip_access.client_ip(ctx.peer_ip, req).await;
+        let authority = request_host(req)?;
+        ctx.port = authority.as_ref().and_then(|authority| authority.port);
+        ctx.host = authority.map(|authority| authority.host);
+        let config = match &ctx.host {
+            Some(host) => self.domains.read().await.get(host).cloned(),
+            None => None,
+        };
+        let reply = if let Some(config) = config {
+            self.route_to(ctx, config).await;
+            Ok(None)
+        } else {
+            let fallbacks = self.policies.fallbacks.read().await;
+            let fallback = match &ctx.host {
+                Some(_) => fallbacks.unknown.clone(),
+                None => fallbacks.missing.clone(),
+            };
+            drop(fallbacks);
+            self.fallback(req, ctx, fallback).await
+        };
+        // 回退返回的错误响应同样需要跨域响应头
+        let preflight = self.cors(req, ctx).await;
+        let reply = reply?;
+        self.ip_access(ctx).await?;
+        if reply.is_some() {
+            return Ok(reply);
+        }
+        if let Some(reply) = self.maintenance(ctx).await {
+            return Ok(Some(reply));
+        }
+        if let Some(policy) = preflight {
+            return Self::preflight(req, ctx, &policy).map(Some);
+        }
+        if let Some(reply) = self.rate_limit(req, ctx).await {
+            return Ok(Some(reply));
+        }
+        if let Some(reply) = self.auth(req, ctx).await {
+            return Ok(Some(reply));
+        }
+        if let Some(reply) = self.oidc(req, ctx).await {
+            return Ok(Some(reply));
+        }
+        Ok(self.url_rules(req, ctx).await)
+    }
+
+    /// 域名处于维护模式时返回维护页面
+    async fn maintenance(&self, ctx: &RequestCtx) -> Option<Reply> {
+        let pages = self.policies.pages.read().await;
+        let maintenance = pages::find_maintenance(&pages, ctx.domain.as_deref())?;
+        let page = maintenance
+            .page
+            .or_else(|| pages::find_page(&pages, ctx.domain.as_deref(), maintenance.status));
+        Some(Reply::Maintenance {
+            status: maintenance.status,
+            retry_after: maintenance.retry_after,
+            page,
+        })
+    }
+
+    /// 查找请求的 Host 或处理请求的域名的跨域策略，预检请求返回策略，
+    /// 其它请求记录需要添加到响应中的响应头
+    async fn cors(&self, req: &RequestHeader, ctx: &mut RequestCtx) -> Option<Arc<CorsPolicy>> {
+        let policy = self
+            .policies
+            .cors
+            .policy([ctx.host.as_deref(), ctx.domain.as_deref()])
+            .await?;
+        if cors::is_preflight(req) {
+            return Some(policy);
+        }
+        ctx.cors = Some(policy.response_headers(cors::origin(req)));
+        None
+    }
+
+    /// 回应预检请求，不允许时返回 `Forbidden` 错误
+    fn preflight(req: &RequestHeader, ctx: &mut RequestCtx, policy: &CorsPolicy) -> Result<Reply> {
+        let origin = cors::origin(req).unwrap_or_default();
+        let result = policy.preflight(req, origin);
+        ctx.access.push(AccessCheck {
+            name: "cors",
+            allowed: result.is_ok(),
+            detail: match &result {
+                Ok(_) => format!("preflight from {origin}"),
+                Err(reason) => reason.clone(),
+            },
+        });
+        let headers = result.map_err(|reason| ErrorCode::Forbidden.error(reason))?;
+        Ok(Reply::Preflight { headers })
+    }
+
+    /// 检查全局及请求的 Host 或处理请求的域名的地址列表，拒绝时返回 `Forbidden` 错误
+    async fn ip_access(&self, ctx: &mut RequestCtx) -> Result<()> {
+        let access = &self.policies.ip_access;
+        if access.is_empty().await {
+            return Ok(());
+        }
+        let result = access
+            .check([ctx.host.as_deref(), ctx.domain.as_deref()], ctx.client_ip)
+            .await;
+        ctx.access.push(AccessCheck {
+            name: "ip_access",
+            allowed: result.is_ok(),
+            detail: result.clone().err().unwrap_or_default(),
+        });
+        result.map_err(|reason| ErrorCode::Forbidden.error(reason))
+    }
+
+    /// 域名限制同时处理的请求数时取得位置，超过限制时排队等待
+    async fn admit(&self, ctx: &mut RequestCtx) -> Result<()> {
+        let (Some(domain), Some(config)) = (&ctx.domain, &ctx.config) else {
+            return Ok(());
+        };
+        let limiters = &self.policies.concurrency;
+        if limiters.sweep_due() {
+            let domains = self.domains.read().await;
+            limiters.retain(|name| domains.contains_key(name));
+        }
+        if let Some(limiter) = limiters.limiter(domain, config.concurrency) {
+            ctx.permit = Some(limiter.acquire().await?);
+        }
+        Ok(())
+    }
+
+    /// 检查请求的 Host 或处理请求的域名的限流规则，超过限制时返回 429
+    async fn rate_limit(&self, req: &RequestHeader, ctx: &mut RequestCtx) -> Option<Reply> {
+        let status = self
+            .policies
+            .rate_limits
+            .check(
+                [ctx.host.as_deref(), ctx.domain.as_deref()],
+                req,
+                ctx.client_ip,
+                !ctx.dry_run,
+            )
+            .await?;
+        let detail = match status.retry_after {
+            Some(retry_after) => format!("retry after {retry_after}s"),
+            None => format!("{} of {} remaining", status.remaining, status.limit),
+        };
+        ctx.access.push(AccessCheck {
+            name: "rate_limit",
+            allowed: status.allowed(),
+            detail,
+        });
+        ctx.rate_limit = Some(status);
+        (!status.allowed()).then_some(Reply::RateLimited(status))
+    }
+
+    /// 按请求的 Host 或处理请求的域名的认证规则认证，失败时返回 401 或 403
+    async fn auth(&self, req: &RequestHeader, ctx: &mut RequestCtx) -> Option<Reply> {
+        let result = self
+            .policies
+            .auth
+            .check([ctx.host.as_deref(), ctx.domain.as_deref()], req)
+            .await?;
+        ctx.auth_forward = result.forward;
+        ctx.strip_query = result.strip_query;
+        let rule = result.rule?;
+        let detail = match (&result.failure, &result.subject) {
+            (Some(failure), _) => format!("rule {rule}: {}", failure.reason),
+            (None, Some(subject)) => format!("rule {rule}: {subject}"),
+            (None, None) => format!("rule {rule}"),
+        };
+        ctx.access.push(AccessCheck {
+            name: "auth",
+            allowed: result.failure.is_none(),
+            detail,
+        });
+        result.failure.map(Reply::AuthFailed)
+    }
+
+    /// 域名使用 OpenID Connect 登录时检查会话，未登录时跳转到 IdP
+    async fn oidc(&self, req: &RequestHeader, ctx: &mut RequestCtx) -> Option<Reply> {
+        let client = self
+            .policies
+            .oidc
+            .client([ctx.host.as_deref(), ctx.domain.as_deref()])
+            .await?;
+        // 不需要登录的路径同样不转发会话 Cookie 及客户端传入的转发请求头
+        ctx.strip_cookies.extend(client.cookie_names());
+        let config = &client.config;
+        ctx.auth_forward
+            .extend(config.forward.keys().map(|name| (name.clone(), None)));
+        let host = ctx.host.clone().map(|host| {
+            Authority {
+                host,
+                port: ctx.port,
+            }
+            .to_string()
+        });
+        let action = client
+            .handle(req, ctx.scheme, host.as_deref(), ctx.dry_run)
+            .await?;
+        let (allowed, detail, reply) = match action {
+            OidcAction::Proxy { claims, cookie } => {
+                ctx.set_cookies.extend(cookie);
+                match auth::authorize(&claims, &config.require, &config.forward) {
+                    Ok(forward) => {
+                        ctx.auth_forward.extend(forward);
+                        let subject = auth::claim(&claims, "sub").and_then(auth::claim_value);
+                        (true, subject.unwrap_or_default(), None)
+                    }
+                    Err(failure) => (
+                        false,
+                        failure.reason.clone(),
+                        Some(Reply::AuthFailed(failure)),
+                    ),
+                }
+            }
+            OidcAction::Redirect {
+                location,
+                cookies,
+                reason,
+            } => {
+                let reply = Reply::Redirect {
+                    status: 302,
+                    location,
+                    cookies,
+                };
+                (false, reason.to_string(), Some(reply))
+            }
+            OidcAction::Deny(failure) => (
+                false,
+                failure.reason.clone(),
+                Some(Reply::AuthFailed(failure)),
+            ),
+        };
+        ctx.access.push(AccessCheck {
+            name: "oidc",
+            allowed,
+            detail,
+        });
+        reply
+    }
+
+    /// 执行请求的 Host 的地址规则，没有时执行处理请求的域名的规则，匹配重定向规则时返回重定向
+    async fn url_rules(&self, req: &RequestHeader, ctx: &mut RequestCtx) -> Option<Reply> {
+        let store = self.policies.url_rules.read().await;
+        let rules = [&ctx.host, &ctx.domain]
+            .into_iter()
+            .flatten()
+            .find_map(|name| store.get(name))?;
+        let host = ctx.host.as_deref().unwrap_or_default();
+        let outcome = url_rules::evaluate_url_rules(rules, host, req.uri.path(), req.uri.query());
+        drop(store);
+        ctx.url_rules = outcome.matched.clone();
+        if let Some(redirect) = outcome.redirect {
+            return Some(Reply::Redirect {
+                status: redirect.status,
+                location: redirect.location,
+                cookies: Vec::new(),
+            });
+        }
+        if !outcome.matched.is_empty() {
+            ctx.upstream_uri = Some(outcome.uri());
+        }
+        None
+    }
+
+    /// 使用已添加的域名处理请求，别名使用目标域名的后端集合及配置
+    async fn route_to(&self, ctx: &mut RequestCtx, config: Arc<DomainConfig>) {
+        let Some(target) = &config.alias_of else {
+            ctx.upstream_host = config.upstream_host.clone();
+            ctx.rewrite = config.rewrite.clone();
+            ctx.domain = Some(config.domain.clone());
+            ctx.config = Some(config);
+            return;
+        };
+        ctx.config = self.domains.read().await.get(target).cloned();
+        ctx.upstream_host = config
+            .upstream_host
+            .clone()
+            .or_else(|| ctx.config.as_ref()?.upstream_host.clone())
+            .or_else(|| Some(target.clone()));
+        ctx.rewrite = config
+            .rewrite
+            .clone()
+            .or_else(|| ctx.config.as_ref()?.rewrite.clone());
+        ctx.domain = Some(target.clone());
+    }
+
+    /// 处理请求的域名及其后端集合
+    async fn upstreams<'a>(
+        &self,
+        ctx: &'a RequestCtx,
+    ) -> Result<(&'a str, Arc<UpstreamsHealthCheck>)> {
+        let domain = ctx
+            .domain
+            .as_deref()
+            .ok_or_else(|| ErrorCode::MissingHost.error("Host not found".to_string()))?;
+        let upstreams = self
+            .backgrounds
+            .read()
+            .await
+            .get(domain)
+            .cloned()
+            .ok_or_else(|| {
+                ErrorCode::DomainNotFound.error(format!(
+                    "Domain {domain} not found in backgrounds, Did you add it?"
+                ))
+            })?;
+        Ok((domain, upstreams))
+    }
+
+    /// 按未知域名或缺少 Host 的处理方式处理请求，返回由代理直接返回的响应
+    async fn fallback(
+        &self,
+        req: &RequestHeader,
+        ctx: &mut RequestCtx,
+        fallback: Fallback,
+    ) -> Result<Option<Reply>> {
+        match fallback {
+            Fallback::Error => match &ctx.host {
+                Some(host) => Err(ErrorCode::DomainNotFound
+                    .error(format!("Domain {host} not found, Did you add it?"))),
+                None => Err(ErrorCode::MissingHost.error("Host not found".to_string())),
+            },
+            Fallback::Upstream { domain } => {
+                let config = self.domains.read().await.get(&domain).cloned();
+                match config {
+                    Some(config) => self.route_to(ctx, config).await,
+                    None => ctx.domain = Some(domain),
+                }
+                Ok(None)
+            }
+            Fallback::Redirect {
+                location,
+                status,
+                keep_path,
+            } => {
+                let location = if keep_path {
+                    let path = req.uri.path_and_query().map_or("/", |p| p.as_str());
+                    format!("{}{path}", location.trim_end_matches('/'))
+                } else {
+                    location
+                };
+                Ok(Some(Reply::Redirect {
+                    status,
+                    location,
+                    cookies: Vec::new(),
+                }))
+            }
+            Fallback::Static { status, page } => Ok(Some(Reply::Static { status, page })),
+        }
+    }
+
+    /// 发送由代理直接返回的响应
+    async fn reply(&self, session: &mut Session, ctx: &RequestCtx, reply: Reply) -> Result<()> {
+        match reply {
+            Reply::Redirect {
+                status,
+                location,
+                cookies,
+            } => respond::redirect(session, status, &location, &cookies, &ctx.request_id).await,
+            Reply::Static { status, page } => {
+                let response = ErrorResponse {
+                    status,
+                    host: ctx.host.as_deref(),
+                    page: Some(page),
+                    headers: ctx.cors.clone().unwrap_or_default(),
+                    ..ErrorResponse::new(ErrorCode::Status(status), &ctx.request_id)
+                };
+                response.send(session).await
+            }
+            Reply::Maintenance {
+                status,
+                retry_after,
+                page,
+            } => {
+                let response = ErrorResponse {
+                    status,
+                    host: ctx.host.as_deref(),
+                    detail: ctx.error_detail(),
+                    page,
+                    retry_after,
+                    headers: ctx.cors.clone().unwrap_or_default(),
+                    ..ErrorResponse::new(ErrorCode::Maintenance, &ctx.request_id)
+                };
+                response.send(session).await
+            }
+            Reply::RateLimited(status) => {
+                let page = pages::find_page(
+                    &*self.policies.pages.read().await,
+                    ctx.domain.as_deref(),
+                    ErrorCode::RateLimited.status(),
+                );
+                let response = ErrorResponse {
+                    host: ctx.host.as_deref(),
+                    detail: ctx.error_detail(),
+                    page,
+                    retry_after: status.retry_after,
+                    headers: [
+                        status.headers().to_vec(),
+                        ctx.cors.clone().unwrap_or_default(),
+                    ]
+                    .concat(),
+                    ..ErrorResponse::new(ErrorCode::RateLimited, &ctx.request_id)
+                };
+                response.send(session).await
+            }
+            Reply::Preflight { headers } => {
+                let mut resp = ResponseHeader::build(204, Some(headers.len()))?;
+                cors::apply(&mut resp, &headers)?;
+                respond::respond(session, resp, Bytes::new(), &ctx.request_id).await
+            }
+            Reply::AuthFailed(failure) => {
+                let code = if failure.status == 403 {
+                    ErrorCode::Forbidden
+                } else {
+                    ErrorCode::Unauthorized
+                };
+                let page = pages::find_page(
+                    &*self.policies.pages.read().await,
+                    ctx.domain.as_deref(),
+                    code.status(),
+                );
+                let response = ErrorResponse {
+                    host: ctx.host.as_deref(),
+                    detail: ctx.error_detail(),
+                    internal: failure.reason,
+                    page,
+                    headers: failure
+                        .challenge
+                        .map(|challenge| ("www-authenticate", challenge))
+                        .into_iter()
+                        .chain(ctx.cors.clone().unwrap_or_default())
+                        .collect(),
+                    ..ErrorResponse::new(code, &ctx.request_id)
+                };
+                response.send(session).await
+            }
+        }
+    }
+}
+
+/// 改写发送给后端的请求头，`req` 为客户端的请求
+fn prepare_upstream(
+    ctx: &mut RequestCtx,
+    req: &RequestHeader,
+    scheme: &'static str,
+    upstream_request: &mut RequestHeader,
+) -> Result<()> {
+    upstream_request.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+    if let Some(config) = ctx.config.clone().filter(|c| !c.headers.is_empty()) {
+        let path = req.uri.path();
+        let (route, captures) = match headers::match_route(&config.headers, path) {
+            Some((route, captures)) => (Some(route), captures),
+            None => (None, Vec::new()),
+        };
+        let host = ctx.host.clone().map(|host| {
+            Authority {
+                host,
+                port: ctx.port,
+            }
+            .to_string()
+        });
+        let info = RequestInfo {
+            peer_ip: ctx.peer_ip,
+            client_ip: ctx.client_ip,
+            request_id: &ctx.request_id,
+            host,
+            scheme,
+            method: req.method.as_str(),
+            path,
+        };
+        ctx.header_vars = info.vars(&captures);
+        ctx.header_route = route;
+        headers::apply_request(
+            &config.headers,
+            route,
+            upstream_request,
+            &info,
+            &ctx.header_vars,
+        )?;
+    }
+    // 认证规则转发的请求头在头改写规则之后设置，不会被客户端或头改写规则覆盖
+    for (name, value) in &ctx.auth_forward {
+        upstream_request.remove_header(name.as_str());
+        if let Some(value) = value {
+            upstream_request.insert_header(name.clone(), value)?;
+        }
+    }
+    if !ctx.strip_cookies.is_empty() {
+        oidc::remove_cookies(upstream_request, &ctx.strip_cookies)?;
+    }
+    if ctx.rewrite.as_ref().is_some_and(|rewrite| rewrite.body) {
+        rewrite::restrict_encoding(upstream_request)?;
+    }
+    if let Some(host) = &ctx.upstream_host {
+        upstream_request.insert_header(header::HOST, host)?;
+    }
+    // 改写 Host 时 absolute-form 的请求地址中的 authority 优先于 Host，改为 origin-form
+    let uri = match &ctx.upstream_uri {
+        Some(uri) => Some(uri.clone()),
+        None if ctx.upstream_host.is_some() && upstream_request.uri.authority().is_some() => {
+            let path = upstream_request.uri.path_and_query();
+            Some(path.map_or("/", |p| p.as_str()).to_string())
+        }
+        None => None,
+    };
+    let uri = match &ctx.strip_query {
+        Some(name) => {
+            let current = uri
+                .as_deref()
+                .or_else(|| upstream_request.uri.path_and_query().map(|p| p.as_str()));
+            current.map(|uri| auth::remove_query_param(uri, name))
+        }
+        None => uri,
+    };
+    if let Some(uri) = uri {
+        let uri = uri
+            .parse::<Uri>()
+            .map_err(|e| ErrorCode::BadRequest.error(format!("Invalid upstream uri: {e}")))?;
+        upstream_request.set_uri(uri);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl ProxyHttp for LB {
+    type CTX = RequestCtx;
+    fn new_ctx(&self) -> Self::CTX {
+        RequestCtx::new()
+    }
+
+    /// 确定请求 ID 及处理请求的域名，域名处于维护模式等情况时直接响应
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.peer_ip = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip());
+        ctx.scheme = scheme(session);
+        let Some(reply) = self.route(session.req_header(), ctx).await? else {
+            self.admit(ctx).await?;
+            return Ok(false);
+        };
+        self.reply(session, ctx, reply).await?;
+        Ok(true)
+    }
+
+    async fn upstream_peer(
+        &self,
+        _session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        let (domain, upstreams) = self.upstreams(ctx).await?;
+        let upstream = upstreams.select_racing().ok_or_else(|| {
+            ErrorCode::NoHealthyUpstream
+                .error(format!("Select upstream failed when request {domain}"))
+        })?;
+        // SNI 不带端口
+        let sni = ctx
+            .upstream_host
+            .as_deref()
+            .and_then(|host| parse_authority(host).ok())
+            .map_or_else(|| domain.to_string(), |authority| authority.host);
+        let mut peer = Box::new(HttpPeer::new(upstream, true, sni));
+        timeout::apply(&ctx.timeouts(), &mut peer, ctx.start)?;
+        ctx.connect_start = Some(Instant::now());
+        Ok(peer)
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        e: Box<Error>,
+    ) -> Box<Error> {
+        match ctx.connect_start {
+            Some(started) => timeout::classify_connect(e, peer, started),
+            None => e,
+        }
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        prepare_upstream(ctx, session.req_header(), scheme(session), upstream_request)
+    }
+
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        timeout::remaining(&ctx.timeouts(), ctx.start)?;
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        timeout::remaining(&ctx.timeouts(), ctx.start)?;
+        ctx.response_started = true;
+        ctx.upstream_status = Some(upstream_response.status.as_u16());
+        upstream_response.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+        if let Some(status) = ctx.rate_limit {
+            for (name, value) in status.headers() {
+                upstream_response.insert_header(name, value)?;
+            }
+        }
+        if let Some(headers) = &ctx.cors {
+            cors::apply(upstream_response, headers)?;
+        }
+        for cookie in &ctx.set_cookies {
+            upstream_response.append_header(header::SET_COOKIE, cookie)?;
+        }
+        if let Some(config) = &ctx.config {
+            headers::apply_response(
+                &config.headers,
+                ctx.header_route,
+                upstream_response,
+                &ctx.header_vars,
+            )?;
+        }
+
+        let (Some(config), Some(host)) = (ctx.rewrite.clone(), ctx.host.clone()) else {
+            return Ok(());
+        };
+        let client = Authority {
+            host,
+            port: ctx.port,
+        };
+        let Some(rewriter) = Rewriter::new(
+            &config,
+            ctx.upstream_host.as_deref(),
+            scheme(session),
+            &client,
+        ) else {
+            return Ok(());
+        };
+        rewriter.rewrite_headers(upstream_response)?;
+        if session.req_header().method != Method::HEAD {
+            ctx.body_rewriter = BodyRewriter::new(&rewriter, &config, upstream_response)?;
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        timeout::remaining(&ctx.timeouts(), ctx.start)?;
+        if let Some(rewriter) = &mut ctx.body_rewriter {
+            rewriter.filter(body, end_of_stream)?;
+        }
+        Ok(None)
+    }
+
+    async fn logging(&self, _session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX)
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(permit) = ctx.permit.take() {
+            let failed = ctx.upstream_status.is_some_and(|status| status >= 500);
+            permit.finish(e.is_none() && !failed);
+        }
+    }
+
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16
+    where
+        Self::CTX: Send + Sync,
+    {
+        let Some(mut code) = ErrorCode::of(e, ctx.response_started) else {
+            return 0;
+        };
+        // 其它超时被限制在剩余的总时间内，超过总时间时按总超时处理
+        if matches!(code, ErrorCode::Timeout(_))
+            && timeout::remaining(&ctx.timeouts(), ctx.start).is_err()
+        {
+            code = ErrorCode::Timeout(TimeoutKind::Total);
+        }
+        warn!(
+            "Request {} to {} failed with {}: {e}",
+            ctx.request_id,
+            ctx.host.as_deref().unwrap_or("-"),
+            code.code()
+        );
+        let page = pages::find_page(
+            &*self.policies.pages.read().await,
+            ctx.domain.as_deref(),
+            code.status(),
+        );
+        let response = ErrorResponse {
+            host: ctx.host.as_deref(),
+            detail: ctx.error_detail(),
+            internal: e.to_string(),
+            page,
+            headers: ctx.cors.clone().unwrap_or_default(),
+            ..ErrorResponse::new(code, &ctx.request_id)
+        };
+        if let Err(e) = response.send(session).await {
+            warn!("Request {} send error response failed: {e}", ctx.request_id);
+        }
+        code.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn lb() -> LB {
+        LB {
+            backgrounds: Arc::default(),
+            domains: Arc::default(),
+            policies: Policies::default(),
+        }
+    }
+
+    fn request(uri: &str, host: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        if let Some(host) = host {
+            req.insert_header(header::HOST, host).unwrap();
+        }
+        req
+    }
+
+    async fn route(lb: &LB, req: &RequestHeader) -> (Result<Option<Reply>>, RequestCtx) {
+        let mut ctx = RequestCtx::new();
+        let reply = lb.route(req, &mut ctx).await;
+        (reply, ctx)
+    }
+
+    fn error_code(reply: Result<Option<Reply>>) -> Option<ErrorCode> {
+        ErrorCode::of(&reply.unwrap_err(), false)
+    }
+
+    async fn add_domain(lb: &LB, config: DomainConfig) {
+        let domain = config.domain.clone();
+        lb.domains.write().await.insert(domain, Arc::new(config));
+    }
+
+    async fn set_fallbacks(lb: &LB, fallbacks: serde_json::Value) {
+        *lb.policies.fallbacks.write().await = serde_json::from_value(fallbacks).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fallback_errors_have_cors_headers() {
+        let lb = lb();
+        let policy = serde_json::from_value(json!({ "origins": ["https://app.example.com"] }));
+        lb.policies
+            .cors
+            .set("unknown.example.com".to_string(), policy.unwrap())
+            .await;
+        let mut req = request("/", Some("unknown.example.com"));
+        req.insert_header(header::ORIGIN, "https://app.example.com")
+            .unwrap();
+
+        let (reply, ctx) = route(&lb, &req).await;
+        assert_eq!(error_code(reply), Some(ErrorCode::DomainNotFound));
+        let cors = ctx.cors.unwrap();
+        assert!(cors.contains(&(
+            "access-control-allow-origin",
+            "https://app.example.com".to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn unknown_and_missing_hosts_return_errors_by_default() {
+        let lb = lb();
+        let (reply, ctx) = route(&lb, &request("/", Some("unknown.example.com"))).await;
+        assert_eq!(error_code(reply), Some(ErrorCode::DomainNotFound));
+        assert_eq!(ctx.domain, None);
+
+        let (reply, _) = route(&lb, &request("/", None)).await;
+        assert_eq!(error_code(reply), Some(ErrorCode::MissingHost));
+    }
+
+    #[tokio::test]
+    async fn unknown_host_fallbacks() {
+        let lb = lb();
+        set_fallbacks(
+            &lb,
+            json!({
+                "unknown": {
+                    "action": "redirect",
+                    "location": "https://www.example.com/"
+                },
+                "missing": {
+                    "action": "static",
+                    "status": 421,
+                    "content_type": "text/plain",
+                    "body": "Host required"
+                }
+            }),
+        )
+        .await;
+
+        let (reply, _) = route(&lb, &request("/a/b?c=d", Some("old.example.com"))).await;
+        let Some(Reply::Redirect {
+            status, location, ..
+        }) = reply.unwrap()
+        else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(status, 301);
+        assert_eq!(location, "https://www.example.com/a/b?c=d");
+
+        let (reply, _) = route(&lb, &request("/", None)).await;
+        let Some(Reply::Static { status, page }) = reply.unwrap() else {
+            panic!("expected a static response");
+        };
+        assert_eq!(status, 421);
+        assert_eq!(page.body, "Host required");
+    }
+
+    #[tokio::test]
+    async fn redirect_fallback_without_path() {
+        let lb = lb();
+        set_fallbacks(
+            &lb,
+            json!({
+                "unknown": {
+                    "action": "redirect",
+                    "location": "https://www.example.com",
+                    "status": 302,
+                    "keep_path": false
+                }
+            }),
+        )
+        .await;
+
+        let (reply, _) = route(&lb, &request("/a/b", Some("old.example.com"))).await;
+        let Some(Reply::Redirect {
+            status, location, ..
+        }) = reply.unwrap()
+        else {
+            panic!("expected a redirect");
+        };
+        assert_eq!(status, 302);
+        assert_eq!(location, "https://www.example.com");
+
+        // 缺少 Host 仍使用默认的处理方式
+        let (reply, _) = route(&lb, &request("/", None)).await;
+        assert_eq!(error_code(reply), Some(ErrorCode::MissingHost));
+    }
+
+    #[tokio::test]
+    async fn upstream_fallback_uses_the_default_domain() {
+        let lb = lb();
+        add_domain(
+            &lb,
+            DomainConfig {
+                domain: "default.example.com".to_string(),
+                ..Default::default()
+            },
+        )
+        .await;
+        set_fallbacks(
+            &lb,
+            json!({ "unknown": { "action": "upstream", "domain": "default.example.com" } }),
+        )
+        .await;
+
+        let (reply, ctx) = route(&lb, &request("/", Some("unknown.example.com"))).await;
+        assert!(reply.unwrap().is_none());
+        assert_eq!(ctx.host.as_deref(), Some("unknown.example.com"));
+        assert_eq!(ctx.domain.as_deref(), Some("default.example.com"));
+        assert!(ctx.config.is_some());
+    }
+
+    #[tokio::test]
+    async fn alias_uses_the_target_domain() {
+        let lb = lb();
+        add_domain(
+            &lb,
+            DomainConfig {
+                domain: "google.com".to_string(),
+                ..Default::default()
+            },
+        )
+        .await;
+        add_domain(
+            &lb,
+            DomainConfig {
+                domain: "google.local".to_string(),
+                alias_of: Some("google.com".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let (reply, ctx) = route(&lb, &request("/", Some("google.local"))).await;
+        assert!(reply.unwrap().is_none());
+        assert_eq!(ctx.host.as_deref(), Some("google.local"));
+        assert_eq!(ctx.domain.as_deref(), Some("google.com"));
+        assert_eq!(ctx.upstream_host.as_deref(), Some("google.com"));
+        assert_eq!(ctx.config.unwrap().domain, "google.com");
+    }
+
+    #[tokio::test]
+    async fn alias_whose_target_was_deleted() {
+        let lb = lb();
+        add_domain(
+            &lb,
+            DomainConfig {
+                domain: "google.local".to_string(),
+                alias_of: Some("google.com".to_string()),
+                upstream_host: Some("www.google.com".to_string()),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // 目标域名已删除时仍按目标域名处理，选择后端时返回 DomainNotFound
+        let (reply, ctx) = route(&lb, &request("/", Some("google.local"))).await;
+        assert!(reply.unwrap().is_none());
+        assert_eq!(ctx.domain.as_deref(), Some("google.com"));
+        assert_eq!(ctx.upstream_host.as_deref(), Some("www.google.com"));
+        assert!(ctx.config.is_none());
+        let Err(err) = lb.upstreams(&ctx).await else {
+            panic!("expected the target domain to be missing");
+        };
+        assert_eq!(ErrorCode::of(&err, false), Some(ErrorCode::DomainNotFound));
+    }
+}
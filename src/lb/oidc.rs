@@ -0,0 +1,836 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::{header, Method};
+use log::warn;
+use pingora::{http::RequestHeader, Result};
+use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use super::{
+    auth::{AuthFailure, REDACTED},
+    jwt::{JwtAuth, JwtVerifier},
+};
+use crate::svcs::PathPattern;
+
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../../examples/stub_idp.rs"]
+mod stub_idp;
+
+/// 登录过程中保存 state 等参数的 Cookie 的有效期
+const LOGIN_TTL: Duration = Duration::from_secs(600);
+/// 浏览器通常不保存超过 4096 字节的 Cookie
+const MAX_COOKIE_LEN: usize = 4096;
+const IDP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// OpenID Connect 登录的配置，使用授权码流程及 PKCE
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct OidcConfig {
+    /// IdP 的地址，从 `{issuer}/.well-known/openid-configuration` 获取端点
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// 登录完成后 IdP 跳转的完整地址，为空时使用请求的协议、Host 及 `callback_path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_uri: Option<String>,
+    #[serde(default = "default_callback_path")]
+    pub callback_path: String,
+    #[serde(default = "default_logout_path")]
+    pub logout_path: String,
+    /// 退出后跳转的地址，IdP 支持 RP-initiated logout 时先跳转到 IdP
+    #[serde(default = "default_post_logout_redirect")]
+    pub post_logout_redirect: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default = "default_cookie_name")]
+    pub cookie_name: String,
+    /// 加密会话 Cookie 的密钥，使用它的 SHA-256 作为 AES-256-GCM 的密钥
+    pub cookie_secret: String,
+    /// 登录后会话的最长时间，单位秒，超过后需要重新登录
+    #[serde(default = "default_session_ttl_secs")]
+    pub session_ttl_secs: u64,
+    /// 不需要登录的路径
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<PathPattern>,
+    /// 登录后还需满足的 ID token 声明，规则同认证规则的 `require`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub require: BTreeMap<String, Value>,
+    /// 转发给后端的请求头名称到声明名称
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub forward: BTreeMap<String, String>,
+}
+
+impl OidcConfig {
+    /// 隐藏 client secret 及 Cookie 密钥后的配置，用于管理 API 返回配置
+    pub fn redacted(&self) -> Self {
+        Self {
+            client_secret: REDACTED.to_string(),
+            cookie_secret: REDACTED.to_string(),
+            ..self.clone()
+        }
+    }
+}
+
+fn default_callback_path() -> String {
+    "/oauth2/callback".to_string()
+}
+
+fn default_logout_path() -> String {
+    "/oauth2/logout".to_string()
+}
+
+fn default_post_logout_redirect() -> String {
+    "/".to_string()
+}
+
+fn default_scopes() -> Vec<String> {
+    vec![
+        "openid".to_string(),
+        "profile".to_string(),
+        "email".to_string(),
+    ]
+}
+
+fn default_cookie_name() -> String {
+    "proxy_session".to_string()
+}
+
+fn default_session_ttl_secs() -> u64 {
+    86400
+}
+
+/// IdP 的端点
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwks_uri: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_session_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    id_token: Option<String>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+/// 加密在会话 Cookie 中的登录信息
+#[derive(Debug, Serialize, Deserialize)]
+struct LoginSession {
+    /// ID token 的声明
+    claims: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    /// 令牌过期的时间，之后需要刷新，Unix 时间戳
+    expires_at: u64,
+    /// 登录的时间，刷新不改变
+    created_at: u64,
+}
+
+/// 加密在登录过程的 Cookie 中的参数
+#[derive(Debug, Serialize, Deserialize)]
+struct LoginState {
+    state: String,
+    nonce: String,
+    verifier: String,
+    /// 登录完成后跳转回的路径
+    return_to: String,
+    expires_at: u64,
+}
+
+/// 登录检查的结果
+#[derive(Debug)]
+pub enum OidcAction {
+    /// 会话有效，`cookie` 为刷新后的会话 Cookie
+    Proxy {
+        claims: Value,
+        cookie: Option<String>,
+    },
+    /// 跳转到 IdP 登录、登录完成后跳转回原地址或退出
+    Redirect {
+        location: String,
+        cookies: Vec<String>,
+        reason: &'static str,
+    },
+    /// 未登录的非 GET 请求、登录失败或不允许的退出请求
+    Deny(AuthFailure),
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn random_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+fn unauthorized(reason: impl Into<String>) -> OidcAction {
+    OidcAction::Deny(AuthFailure {
+        status: 401,
+        challenge: None,
+        reason: reason.into(),
+    })
+}
+
+fn forbidden(reason: impl Into<String>) -> OidcAction {
+    OidcAction::Deny(AuthFailure {
+        status: 403,
+        challenge: None,
+        reason: reason.into(),
+    })
+}
+
+/// 请求中的 Cookie
+fn cookie<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req.headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+fn query_param(req: &RequestHeader, name: &str) -> Option<String> {
+    let query = req.uri.query()?;
+    Url::parse(&format!("http://localhost/?{query}"))
+        .ok()?
+        .query_pairs()
+        .find_map(|(key, value)| (key == name).then(|| value.into_owned()))
+}
+
+/// 删除发送给后端的请求中的 Cookie，不把会话交给后端
+pub fn remove_cookies(upstream_request: &mut RequestHeader, names: &[String]) -> Result<()> {
+    let cookies: Vec<String> = upstream_request
+        .headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .map(str::trim)
+        .filter(|pair| {
+            let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+            !pair.is_empty() && !names.iter().any(|n| n == name)
+        })
+        .map(str::to_string)
+        .collect();
+    upstream_request.remove_header(&header::COOKIE);
+    if !cookies.is_empty() {
+        upstream_request.insert_header(header::COOKIE, cookies.join("; "))?;
+    }
+    Ok(())
+}
+
+/// 加密 Cookie，值为 base64 编码的随机 nonce 及密文，Cookie 名称作为附加数据，不能互换
+struct CookieCipher(Aes256Gcm);
+
+impl CookieCipher {
+    fn new(secret: &str) -> Self {
+        let key = Sha256::digest(secret.as_bytes());
+        Self(Aes256Gcm::new(&key))
+    }
+
+    fn seal(&self, name: &str, value: &impl Serialize) -> Option<String> {
+        let plaintext = serde_json::to_vec(value).ok()?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &plaintext,
+            aad: name.as_bytes(),
+        };
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.0.encrypt(&nonce, payload).ok()?);
+        Some(URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    fn open<T: DeserializeOwned>(&self, name: &str, value: &str) -> Option<T> {
+        let sealed = URL_SAFE_NO_PAD.decode(value).ok()?;
+        if sealed.len() < 12 {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: name.as_bytes(),
+        };
+        let plaintext = self
+            .0
+            .decrypt(GenericArray::from_slice(nonce), payload)
+            .ok()?;
+        serde_json::from_slice(&plaintext).ok()
+    }
+}
+
+/// 一个域名的 OpenID Connect 客户端
+pub struct OidcClient {
+    pub config: OidcConfig,
+    pub metadata: ProviderMetadata,
+    verifier: JwtVerifier,
+    cipher: CookieCipher,
+    http: reqwest::Client,
+}
+
+impl OidcClient {
+    /// 获取 IdP 的端点及 JWKS
+    pub async fn discover(config: OidcConfig) -> Result<Self, String> {
+        let http = reqwest::Client::builder()
+            .timeout(IDP_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = http
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Fetch {url} failed: {e}"))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid provider metadata {url}: {e}"))?;
+        // HS 算法签名的 ID token 使用 client secret 作为密钥
+        let verifier = JwtVerifier::new(JwtAuth {
+            secret: Some(config.client_secret.clone()),
+            public_key: None,
+            jwks_file: None,
+            jwks_url: metadata.jwks_uri.clone(),
+            algorithms: Vec::new(),
+            issuer: Some(metadata.issuer.clone()),
+            audience: vec![config.client_id.clone()],
+            leeway_secs: 60,
+        })
+        .await?;
+        Ok(Self {
+            cipher: CookieCipher::new(&config.cookie_secret),
+            config,
+            metadata,
+            verifier,
+            http,
+        })
+    }
+
+    fn state_cookie_name(&self) -> String {
+        format!("{}_state", self.config.cookie_name)
+    }
+
+    /// 保存到后端的请求中需要删除的 Cookie
+    pub fn cookie_names(&self) -> [String; 2] {
+        [self.config.cookie_name.clone(), self.state_cookie_name()]
+    }
+
+    fn set_cookie(&self, name: &str, value: &str, max_age: u64, secure: bool) -> String {
+        let secure = if secure { "; Secure" } else { "" };
+        format!("{name}={value}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}")
+    }
+
+    fn redirect_uri(&self, scheme: &str, host: &str) -> String {
+        match &self.config.redirect_uri {
+            Some(uri) => uri.clone(),
+            None => format!("{scheme}://{host}{}", self.config.callback_path),
+        }
+    }
+
+    /// 处理请求，`host` 为带端口的请求的 Host，不需要登录的路径返回空
+    /// 请求没有 Host 时无法确定登录后跳转回的地址，返回 401
+    /// `dry_run` 时不访问 IdP
+    pub async fn handle(
+        &self,
+        req: &RequestHeader,
+        scheme: &str,
+        host: Option<&str>,
+        dry_run: bool,
+    ) -> Option<OidcAction> {
+        let path = req.uri.path();
+        let secure = scheme == "https";
+        let excluded = self
+            .config
+            .exclude
+            .as_ref()
+            .is_some_and(|p| p.is_match(path));
+        let Some(host) = host else {
+            return (!excluded).then(|| unauthorized("Login requires the request Host"));
+        };
+        if path == self.config.callback_path {
+            if dry_run {
+                return Some(unauthorized("Login callback is not evaluated in dry run"));
+            }
+            return Some(self.callback(req, scheme, host).await);
+        }
+        if path == self.config.logout_path {
+            return Some(self.logout(req, scheme, host, secure));
+        }
+        if excluded {
+            return None;
+        }
+        let name = &self.config.cookie_name;
+        let session = cookie(req, name)
+            .and_then(|value| self.cipher.open::<LoginSession>(name, value))
+            .filter(|session| now() < session.created_at + self.config.session_ttl_secs);
+        if let Some(session) = session {
+            if now() < session.expires_at {
+                return Some(OidcAction::Proxy {
+                    claims: session.claims,
+                    cookie: None,
+                });
+            }
+            if session.refresh_token.is_some() && !dry_run {
+                match self.refresh(session).await {
+                    Ok(session) => {
+                        let cookie = self.session_cookie(&session, secure);
+                        return Some(OidcAction::Proxy {
+                            claims: session.claims,
+                            cookie,
+                        });
+                    }
+                    Err(e) => warn!("OidcClient refresh session failed: {e}"),
+                }
+            }
+        }
+        Some(self.login(req, scheme, host, secure))
+    }
+
+    /// 跳转到 IdP 登录，只有 GET 及 HEAD 请求跳转，其它请求返回 401
+    fn login(&self, req: &RequestHeader, scheme: &str, host: &str, secure: bool) -> OidcAction {
+        if req.method != Method::GET && req.method != Method::HEAD {
+            return unauthorized("Login required");
+        }
+        let state = LoginState {
+            state: random_token(),
+            nonce: random_token(),
+            verifier: random_token(),
+            return_to: req
+                .uri
+                .path_and_query()
+                .map_or("/", |p| p.as_str())
+                .to_string(),
+            expires_at: now() + LOGIN_TTL.as_secs(),
+        };
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(state.verifier.as_bytes()));
+        let params = [
+            ("response_type", "code".to_string()),
+            ("client_id", self.config.client_id.clone()),
+            ("redirect_uri", self.redirect_uri(scheme, host)),
+            ("scope", self.config.scopes.join(" ")),
+            ("state", state.state.clone()),
+            ("nonce", state.nonce.clone()),
+            ("code_challenge", challenge),
+            ("code_challenge_method", "S256".to_string()),
+        ];
+        let location = match Url::parse_with_params(&self.metadata.authorization_endpoint, &params)
+        {
+            Ok(url) => url.to_string(),
+            Err(e) => return unauthorized(format!("Invalid authorization endpoint: {e}")),
+        };
+        let name = self.state_cookie_name();
+        let Some(value) = self.cipher.seal(&name, &state) else {
+            return unauthorized("Seal login state failed");
+        };
+        OidcAction::Redirect {
+            location,
+            cookies: vec![self.set_cookie(&name, &value, LOGIN_TTL.as_secs(), secure)],
+            reason: "login required",
+        }
+    }
+
+    /// 校验 state，用授权码换取令牌，创建会话后跳转回登录前的地址
+    async fn callback(&self, req: &RequestHeader, scheme: &str, host: &str) -> OidcAction {
+        if let Some(error) = query_param(req, "error") {
+            return unauthorized(format!("IdP returned error {error}"));
+        }
+        let name = self.state_cookie_name();
+        let Some(login) = cookie(req, &name)
+            .and_then(|value| self.cipher.open::<LoginState>(&name, value))
+            .filter(|login| now() < login.expires_at)
+        else {
+            return unauthorized("Login state missing or expired");
+        };
+        if query_param(req, "state").as_deref() != Some(login.state.as_str()) {
+            return unauthorized("Login state mismatch");
+        }
+        let Some(code) = query_param(req, "code") else {
+            return unauthorized("Authorization code missing");
+        };
+        let redirect_uri = self.redirect_uri(scheme, host);
+        let form = [
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("code_verifier", login.verifier.as_str()),
+        ];
+        let tokens = match self.token(&form).await {
+            Ok(tokens) => tokens,
+            Err(e) => return unauthorized(e),
+        };
+        let Some(id_token) = &tokens.id_token else {
+            return unauthorized("Token response has no id_token");
+        };
+        let claims = match self.verifier.verify(id_token) {
+            Ok(claims) => claims,
+            Err(e) => return unauthorized(e),
+        };
+        if claims.get("nonce").and_then(Value::as_str) != Some(login.nonce.as_str()) {
+            return unauthorized("ID token nonce mismatch");
+        }
+        let session = LoginSession {
+            expires_at: expires_at(&tokens, &claims),
+            claims,
+            refresh_token: tokens.refresh_token,
+            created_at: now(),
+        };
+        let secure = scheme == "https";
+        let Some(cookie) = self.session_cookie(&session, secure) else {
+            return unauthorized("Seal session failed");
+        };
+        // 只跳转到本站的路径
+        let return_to = Some(login.return_to)
+            .filter(|path| path.starts_with('/') && !path.starts_with("//"))
+            .unwrap_or_else(|| "/".to_string());
+        OidcAction::Redirect {
+            location: return_to,
+            cookies: vec![cookie, self.set_cookie(&name, "", 0, secure)],
+            reason: "login completed",
+        }
+    }
+
+    /// 使用刷新令牌换取新的令牌，没有新的 ID token 时保留原来的声明
+    async fn refresh(&self, session: LoginSession) -> Result<LoginSession, String> {
+        let refresh_token = session.refresh_token.clone().unwrap_or_default();
+        let form = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+        ];
+        let tokens = self.token(&form).await?;
+        let claims = match &tokens.id_token {
+            Some(id_token) => self.verifier.verify(id_token)?,
+            None => session.claims,
+        };
+        Ok(LoginSession {
+            expires_at: expires_at(&tokens, &claims),
+            claims,
+            refresh_token: tokens.refresh_token.or(session.refresh_token),
+            created_at: session.created_at,
+        })
+    }
+
+    async fn token(&self, form: &[(&str, &str)]) -> Result<TokenResponse, String> {
+        let endpoint = &self.metadata.token_endpoint;
+        self.http
+            .post(endpoint)
+            .basic_auth(&self.config.client_id, Some(&self.config.client_secret))
+            .form(form)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Token request to {endpoint} failed: {e}"))?
+            .json()
+            .await
+            .map_err(|e| format!("Invalid token response from {endpoint}: {e}"))
+    }
+
+    fn session_cookie(&self, session: &LoginSession, secure: bool) -> Option<String> {
+        let name = &self.config.cookie_name;
+        let value = self.cipher.seal(name, session)?;
+        let max_age = (session.created_at + self.config.session_ttl_secs).saturating_sub(now());
+        let cookie = self.set_cookie(name, &value, max_age, secure);
+        if cookie.len() > MAX_COOKIE_LEN {
+            warn!(
+                "OidcClient session cookie is {} bytes, browsers may drop it",
+                cookie.len()
+            );
+        }
+        Some(cookie)
+    }
+
+    /// 删除会话，IdP 支持时跳转到 IdP 退出
+    /// 只接受 POST，带 `Origin` 时必须是本站，防止其它站点让用户退出
+    fn logout(&self, req: &RequestHeader, scheme: &str, host: &str, secure: bool) -> OidcAction {
+        if req.method != Method::POST {
+            return forbidden("Logout requires POST");
+        }
+        let origin = format!("{scheme}://{host}");
+        let cross_site = req
+            .headers
+            .get(header::ORIGIN)
+            .is_some_and(|value| value.as_bytes() != origin.as_bytes());
+        if cross_site {
+            return forbidden("Cross-site logout is not allowed");
+        }
+        let post_logout = &self.config.post_logout_redirect;
+        let post_logout = if post_logout.starts_with('/') {
+            format!("{origin}{post_logout}")
+        } else {
+            post_logout.clone()
+        };
+        let location = self
+            .metadata
+            .end_session_endpoint
+            .as_deref()
+            .and_then(|endpoint| {
+                let params = [
+                    ("client_id", self.config.client_id.as_str()),
+                    ("post_logout_redirect_uri", post_logout.as_str()),
+                ];
+                Url::parse_with_params(endpoint, &params).ok()
+            })
+            .map_or(post_logout.clone(), |url| url.to_string());
+        OidcAction::Redirect {
+            location,
+            cookies: vec![self.set_cookie(&self.config.cookie_name, "", 0, secure)],
+            reason: "logged out",
+        }
+    }
+}
+
+/// 令牌过期的时间，优先使用 `expires_in`，没有时使用 ID token 的 `exp`
+fn expires_at(tokens: &TokenResponse, claims: &Value) -> u64 {
+    match tokens.expires_in {
+        Some(expires_in) => now() + expires_in,
+        None => claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .unwrap_or_else(now),
+    }
+}
+
+/// 各域名的 OpenID Connect 客户端
+#[derive(Default)]
+pub struct OidcStore {
+    /// 键为请求的 Host 或域名
+    clients: RwLock<HashMap<String, Arc<OidcClient>>>,
+}
+
+impl OidcStore {
+    pub async fn configs(&self) -> HashMap<String, OidcConfig> {
+        self.clients
+            .read()
+            .await
+            .iter()
+            .map(|(domain, client)| (domain.clone(), client.config.clone()))
+            .collect()
+    }
+
+    /// 获取 IdP 的端点后替换域名的配置，失败时不修改
+    pub async fn set(&self, domain: String, config: OidcConfig) -> Result<(), String> {
+        let client = OidcClient::discover(config).await?;
+        self.clients.write().await.insert(domain, Arc::new(client));
+        Ok(())
+    }
+
+    /// 删除域名的配置，返回是否存在
+    pub async fn remove(&self, domain: &str) -> bool {
+        self.clients.write().await.remove(domain).is_some()
+    }
+
+    /// 请求的 Host 的客户端，没有时使用处理请求的域名的客户端
+    pub async fn client(&self, names: [Option<&str>; 2]) -> Option<Arc<OidcClient>> {
+        let clients = self.clients.read().await;
+        names
+            .into_iter()
+            .flatten()
+            .find_map(|name| clients.get(name))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const HOST: &str = "app.example.com";
+
+    /// 启动示例中的 IdP，令牌立即过期，每个请求都会刷新
+    async fn start_idp() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let args = stub_idp::Args {
+            listen: String::new(),
+            client_id: "proxy".to_string(),
+            client_secret: "proxy-secret".to_string(),
+            user: "alice".to_string(),
+            groups: vec!["staff".to_string()],
+            expires_in: 0,
+        };
+        let app = stub_idp::app(args, issuer.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        issuer
+    }
+
+    fn config(issuer: &str) -> OidcConfig {
+        serde_json::from_value(json!({
+            "issuer": issuer,
+            "client_id": "proxy",
+            "client_secret": "proxy-secret",
+            "cookie_secret": "0123456789abcdef0123456789abcdef",
+            "exclude": "^/public",
+            "forward": { "x-user": "sub" },
+        }))
+        .unwrap()
+    }
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, uri.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            req.insert_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    /// `Set-Cookie` 中的名称及值
+    fn cookie_pair(set_cookie: &str) -> &str {
+        set_cookie.split(';').next().unwrap()
+    }
+
+    fn redirect(action: Option<OidcAction>) -> (String, Vec<String>) {
+        match action {
+            Some(OidcAction::Redirect {
+                location, cookies, ..
+            }) => (location, cookies),
+            other => panic!("expected redirect, got {other:?}"),
+        }
+    }
+
+    fn denied(action: Option<OidcAction>) -> AuthFailure {
+        match action {
+            Some(OidcAction::Deny(failure)) => failure,
+            other => panic!("expected deny, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn login_with_stub_idp() {
+        let issuer = start_idp().await;
+        let client = OidcClient::discover(config(&issuer)).await.unwrap();
+        let handle = |req: RequestHeader| {
+            let client = &client;
+            async move { client.handle(&req, "http", Some(HOST), false).await }
+        };
+
+        // 未登录时跳转到 IdP，state 等参数保存在加密的 Cookie 中
+        let (location, cookies) = redirect(handle(request("GET", "/app?x=1", &[])).await);
+        assert!(location.starts_with(&format!("{issuer}/authorize?")));
+        let state_cookie = cookie_pair(&cookies[0]).to_string();
+        assert!(state_cookie.starts_with("proxy_session_state="));
+
+        // IdP 直接同意授权，跳转回回调地址
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = http.get(&location).send().await.unwrap();
+        assert!(response.status().is_redirection());
+        let callback = Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+        assert_eq!(callback.host_str(), Some(HOST));
+        assert_eq!(callback.path(), "/oauth2/callback");
+        let callback_uri = format!("{}?{}", callback.path(), callback.query().unwrap());
+
+        // 没有 state Cookie 的回调不能完成登录
+        let failure = denied(handle(request("GET", &callback_uri, &[])).await);
+        assert_eq!(failure.reason, "Login state missing or expired");
+
+        let (location, cookies) = redirect(
+            handle(request(
+                "GET",
+                &callback_uri,
+                &[("cookie", state_cookie.as_str())],
+            ))
+            .await,
+        );
+        assert_eq!(location, "/app?x=1");
+        let session_cookie = cookie_pair(&cookies[0]).to_string();
+        assert!(session_cookie.starts_with("proxy_session="));
+        assert!(cookies[1].contains("Max-Age=0"));
+
+        // 令牌已过期，使用刷新令牌刷新后继续代理
+        match handle(request(
+            "GET",
+            "/app",
+            &[("cookie", session_cookie.as_str())],
+        ))
+        .await
+        {
+            Some(OidcAction::Proxy { claims, cookie }) => {
+                assert_eq!(claims["sub"], "alice");
+                assert_eq!(claims["groups"], json!(["staff"]));
+                assert!(cookie.is_some());
+            }
+            other => panic!("expected proxy, got {other:?}"),
+        }
+
+        // 退出只接受本站的 POST
+        let logout = "/oauth2/logout";
+        let cookie = ("cookie", session_cookie.as_str());
+        let failure = denied(handle(request("GET", logout, &[cookie])).await);
+        assert_eq!(
+            (failure.status, failure.reason.as_str()),
+            (403, "Logout requires POST")
+        );
+        let evil = ("origin", "https://evil.example.com");
+        let failure = denied(handle(request("POST", logout, &[cookie, evil])).await);
+        assert_eq!(failure.status, 403);
+        let origin = ("origin", "http://app.example.com");
+        let (location, cookies) =
+            redirect(handle(request("POST", logout, &[cookie, origin])).await);
+        assert!(location.starts_with(&format!("{issuer}/logout?")));
+        assert!(cookies[0].starts_with("proxy_session=;"));
+    }
+
+    #[tokio::test]
+    async fn missing_host_requires_login() {
+        let issuer = start_idp().await;
+        let client = OidcClient::discover(config(&issuer)).await.unwrap();
+        let failure = denied(
+            client
+                .handle(&request("GET", "/app", &[]), "http", None, false)
+                .await,
+        );
+        assert_eq!(failure.status, 401);
+        let public = client
+            .handle(&request("GET", "/public/a", &[]), "http", None, false)
+            .await;
+        assert!(public.is_none());
+        // 非 GET 请求不跳转
+        let failure = denied(
+            client
+                .handle(&request("POST", "/app", &[]), "http", Some(HOST), false)
+                .await,
+        );
+        assert_eq!(failure.reason, "Login required");
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let redacted = config("https://idp.example.com").redacted();
+        assert_eq!(redacted.client_secret, REDACTED);
+        assert_eq!(redacted.cookie_secret, REDACTED);
+        assert_eq!(redacted.client_id, "proxy");
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+/// 不属于任何域名的配置，对所有域名生效，例如未添加的域名的错误页面
+pub const DEFAULT_SITE: &str = "*";
+
+/// 域名到错误页面及维护模式的映射，`DEFAULT_SITE` 为所有域名的默认配置
+pub type PageStore = Arc<RwLock<HashMap<String, SitePages>>>;
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+/// 页面模板，`{{name}}` 会被替换为对应的变量，未知的变量保持原样
+/// 支持的变量：status、reason、code、message、request_id、host
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PageTemplate {
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    pub body: String,
+}
+
+impl PageTemplate {
+    /// 替换模板中的变量，HTML 页面中的变量会被转义
+    pub fn render(&self, vars: &[(&str, &str)]) -> String {
+        render(&self.body, vars, self.content_type.starts_with("text/html"))
+    }
+}
+
+/// 替换模板中的 `{{name}}`，未知的变量保持原样，`html` 时转义变量的值
+pub fn render(template: &str, vars: &[(&str, &str)], html: bool) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        rendered.push_str(&rest[..start]);
+        match vars.iter().find(|(var, _)| *var == name) {
+            Some((_, value)) if html => rendered.push_str(&escape_html(value)),
+            Some((_, value)) => rendered.push_str(value),
+            None => rendered.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// 维护模式，开启后直接返回维护页面，不访问后端
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Maintenance {
+    /// 响应的状态码，默认 503
+    #[serde(default = "default_maintenance_status")]
+    pub status: u16,
+    /// `Retry-After` 响应头，单位秒
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// 维护页面，为空时使用状态码对应的错误页面
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<PageTemplate>,
+}
+
+fn default_maintenance_status() -> u16 {
+    503
+}
+
+/// 一个域名的错误页面及维护模式
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SitePages {
+    /// 状态码到错误页面的映射
+    #[serde(default)]
+    pub errors: BTreeMap<u16, PageTemplate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<Maintenance>,
+}
+
+impl SitePages {
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.maintenance.is_none()
+    }
+}
+
+/// 域名状态码对应的错误页面，域名没有时使用默认配置
+pub fn find_page(
+    store: &HashMap<String, SitePages>,
+    domain: Option<&str>,
+    status: u16,
+) -> Option<PageTemplate> {
+    domain
+        .and_then(|domain| store.get(domain))
+        .and_then(|site| site.errors.get(&status))
+        .or_else(|| store.get(DEFAULT_SITE)?.errors.get(&status))
+        .cloned()
+}
+
+/// 域名的维护模式，域名没有开启时使用默认配置
+pub fn find_maintenance(
+    store: &HashMap<String, SitePages>,
+    domain: Option<&str>,
+) -> Option<Maintenance> {
+    domain
+        .and_then(|domain| store.get(domain))
+        .and_then(|site| site.maintenance.as_ref())
+        .or_else(|| store.get(DEFAULT_SITE)?.maintenance.as_ref())
+        .cloned()
+}
+
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn page(content_type: &str, body: &str) -> PageTemplate {
+        PageTemplate {
+            content_type: content_type.to_string(),
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn render_variables() {
+        let vars = [("status", "404"), ("host", "example.com")];
+        assert_eq!(
+            render("{{status}} at {{ host }}: {{unknown}}", &vars, false),
+            "404 at example.com: {{unknown}}"
+        );
+        assert_eq!(render("{{status}} {{host", &vars, false), "404 {{host");
+        assert_eq!(render("no variables", &vars, false), "no variables");
+    }
+
+    #[test]
+    fn html_pages_escape_variables() {
+        let vars = [("host", "<script>alert('x')</script>&\"")];
+        let html = page("text/html; charset=utf-8", "<p>{{host}}</p>");
+        assert_eq!(
+            html.render(&vars),
+            "<p>&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;&amp;&quot;</p>"
+        );
+        let text = page("text/plain", "<p>{{host}}</p>");
+        assert_eq!(text.render(&vars), "<p><script>alert('x')</script>&\"</p>");
+    }
+
+    #[test]
+    fn default_content_type_is_html() {
+        let page: PageTemplate = serde_json::from_value(json!({ "body": "{{code}}" })).unwrap();
+        assert_eq!(page.render(&[("code", "<b>")]), "&lt;b&gt;");
+    }
+
+    #[test]
+    fn site_pages_fall_back_to_default() {
+        let mut store = HashMap::new();
+        store.insert(
+            DEFAULT_SITE.to_string(),
+            SitePages {
+                errors: BTreeMap::from([
+                    (404, page("text/plain", "default 404")),
+                    (502, page("text/plain", "default 502")),
+                ]),
+                maintenance: None,
+            },
+        );
+        store.insert(
+            "example.com".to_string(),
+            SitePages {
+                errors: BTreeMap::from([(404, page("text/plain", "example 404"))]),
+                maintenance: Some(Maintenance {
+                    status: 503,
+                    retry_after: Some(60),
+                    page: None,
+                }),
+            },
+        );
+
+        let body = |domain, status| find_page(&store, domain, status).map(|page| page.body);
+        assert_eq!(body(Some("example.com"), 404).unwrap(), "example 404");
+        assert_eq!(body(Some("example.com"), 502).unwrap(), "default 502");
+        assert_eq!(body(Some("other.com"), 404).unwrap(), "default 404");
+        assert_eq!(body(None, 404).unwrap(), "default 404");
+        assert_eq!(body(None, 500), None);
+
+        assert!(find_maintenance(&store, Some("example.com")).is_some());
+        assert!(find_maintenance(&store, Some("other.com")).is_none());
+    }
+}
@@ -0,0 +1,608 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::header;
+use pingora::http::RequestHeader;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::svcs::PathPattern;
+
+/// 清理长时间未使用的计数器的间隔
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 计数器分片的数量，减少工作线程间的锁竞争
+const SHARDS: usize = 16;
+
+/// 每个分片最多保存的计数器，客户端可以控制计数的键，超过时淘汰最早可以清理的计数器
+const MAX_COUNTERS_PER_SHARD: usize = 8192;
+
+/// 窗口的最大长度，单位秒
+pub const MAX_WINDOW_SECS: u64 = 365 * 24 * 3600;
+
+/// 计数器最长保留的时间，令牌桶补满所需的时间更长时也在此之后清理
+const MAX_IDLE: Duration = Duration::from_secs(2 * MAX_WINDOW_SECS);
+
+/// 限流规则，按客户端分别计数
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateLimit {
+    /// 匹配路径的正则表达式，为空时匹配所有请求
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathPattern>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    #[serde(default)]
+    pub algorithm: RateLimitAlgorithm,
+    /// 每个窗口允许的请求数
+    pub limit: u32,
+    /// 窗口长度，单位秒，最长一年
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// 令牌桶的容量，为空时与 `limit` 相同
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u32>,
+}
+
+fn default_window_secs() -> u64 {
+    1
+}
+
+impl RateLimit {
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs.max(1))
+    }
+}
+
+/// 区分客户端的方式，请求中没有对应的值时按客户端地址计数
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    /// 请求头的值，例如区分租户
+    Header {
+        name: String,
+    },
+    /// 请求头中的 API key，没有时使用查询参数
+    ApiKey {
+        #[serde(default = "default_api_key_header")]
+        header: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        query: Option<String>,
+    },
+    Cookie {
+        name: String,
+    },
+}
+
+fn default_api_key_header() -> String {
+    "x-api-key".to_string()
+}
+
+impl RateLimitKey {
+    /// 请求的计数键，使用前缀区分客户端地址与其它值
+    fn of(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> String {
+        let header = |name: &str| {
+            req.headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let value = match self {
+            RateLimitKey::Ip => None,
+            RateLimitKey::Header { name } => header(name),
+            RateLimitKey::ApiKey {
+                header: name,
+                query,
+            } => header(name).or_else(|| {
+                let query = query.as_deref()?;
+                req.uri.query()?.split('&').find_map(|pair| {
+                    let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (name == query && !value.is_empty()).then(|| value.to_string())
+                })
+            }),
+            RateLimitKey::Cookie { name } => req
+                .headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    (key == name).then(|| value.to_string())
+                }),
+        };
+        match value {
+            Some(value) => format!("key:{value}"),
+            None => format!(
+                "ip:{}",
+                client_ip.map(|ip| ip.to_string()).unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    /// 按 `limit / window_secs` 的速率补充令牌，允许 `burst` 的突发
+    #[default]
+    TokenBucket,
+    /// 按上一个窗口的计数加权估计当前的请求数
+    SlidingWindow,
+}
+
+/// 限流的状态，用于 `RateLimit-*` 响应头
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    /// 配额恢复的秒数
+    pub reset: u64,
+    /// 请求被拒绝时可以重试的秒数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl RateLimitStatus {
+    pub fn allowed(&self) -> bool {
+        self.retry_after.is_none()
+    }
+
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", self.reset.to_string()),
+        ]
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Counter {
+    Bucket {
+        tokens: f64,
+        updated: Instant,
+    },
+    Window {
+        start: Instant,
+        previous: u32,
+        current: u32,
+    },
+}
+
+fn ceil_secs(secs: f64) -> u64 {
+    secs.max(0.0).ceil() as u64
+}
+
+impl Counter {
+    fn new(rule: &RateLimit, now: Instant) -> Self {
+        match rule.algorithm {
+            RateLimitAlgorithm::TokenBucket => Counter::Bucket {
+                tokens: rule.burst.unwrap_or(rule.limit) as f64,
+                updated: now,
+            },
+            RateLimitAlgorithm::SlidingWindow => Counter::Window {
+                start: now,
+                previous: 0,
+                current: 0,
+            },
+        }
+    }
+
+    /// 消耗一个请求的配额，配额不足时不消耗
+    fn take(&mut self, rule: &RateLimit, now: Instant) -> RateLimitStatus {
+        let window = rule.window().as_secs_f64();
+        match self {
+            Counter::Bucket { tokens, updated } => {
+                let capacity = rule.burst.unwrap_or(rule.limit) as f64;
+                let rate = rule.limit as f64 / window;
+                *tokens =
+                    (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(capacity);
+                *updated = now;
+                let retry_after = if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    None
+                } else {
+                    Some(ceil_secs((1.0 - *tokens) / rate).max(1))
+                };
+                RateLimitStatus {
+                    limit: capacity as u32,
+                    remaining: tokens.floor() as u32,
+                    reset: ceil_secs((capacity - *tokens) / rate),
+                    retry_after,
+                }
+            }
+            Counter::Window {
+                start,
+                previous,
+                current,
+            } => {
+                let passed = now.duration_since(*start).as_secs_f64() / window;
+                if passed >= 2.0 {
+                    *start = now;
+                    *previous = 0;
+                    *current = 0;
+                } else if passed >= 1.0 {
+                    *start += rule.window();
+                    *previous = *current;
+                    *current = 0;
+                }
+                let elapsed = now.duration_since(*start).as_secs_f64();
+                let limit = rule.limit as f64;
+                let estimate = *previous as f64 * (1.0 - elapsed / window) + *current as f64;
+                let retry_after = if estimate + 1.0 <= limit {
+                    *current += 1;
+                    None
+                } else if *current as f64 + 1.0 > limit {
+                    // 需要等到下一个窗口，且上一个窗口的权重降低到足够小
+                    let next = window * (1.0 - (limit - 1.0) / (*current).max(1) as f64);
+                    Some(ceil_secs(window - elapsed + next).max(1))
+                } else {
+                    let wait = window * (1.0 - (limit - 1.0 - *current as f64) / *previous as f64);
+                    Some(ceil_secs(wait - elapsed).max(1))
+                };
+                let estimate = *previous as f64 * (1.0 - elapsed / window) + *current as f64;
+                RateLimitStatus {
+                    limit: rule.limit,
+                    remaining: (limit - estimate).max(0.0).floor() as u32,
+                    reset: ceil_secs(window - elapsed),
+                    retry_after,
+                }
+            }
+        }
+    }
+
+    /// 退还 `take` 消耗的配额，用于之后的规则拒绝请求时
+    fn refund(&mut self, rule: &RateLimit) {
+        match self {
+            Counter::Bucket { tokens, .. } => {
+                let capacity = rule.burst.unwrap_or(rule.limit) as f64;
+                *tokens = (*tokens + 1.0).min(capacity);
+            }
+            Counter::Window { current, .. } => *current = current.saturating_sub(1),
+        }
+    }
+
+    /// 计数器在此之后与新建的计数器相同，可以清理
+    fn idle_since(&self, rule: &RateLimit) -> Instant {
+        let (since, idle) = match self {
+            Counter::Bucket { updated, .. } => {
+                // 令牌从零补充到容量所需的时间
+                let capacity = rule.burst.unwrap_or(rule.limit) as f64;
+                let rate = rule.limit.max(1) as f64 / rule.window().as_secs_f64();
+                let refill = Duration::try_from_secs_f64(capacity / rate).unwrap_or(MAX_IDLE);
+                (*updated, refill)
+            }
+            Counter::Window { start, .. } => {
+                (*start, rule.window().checked_mul(2).unwrap_or(MAX_IDLE))
+            }
+        };
+        since.checked_add(idle.min(MAX_IDLE)).unwrap_or(since)
+    }
+}
+
+/// 计数器的键：规则所属的 Host 或域名、规则的序号、客户端
+type CounterKey = (String, usize, String);
+
+#[derive(Default)]
+struct Counters {
+    entries: HashMap<CounterKey, Counter>,
+    swept: Option<Instant>,
+}
+
+/// 限流规则及计数器，在所有工作线程间共享
+pub struct RateLimiter {
+    /// 键为请求的 Host 或域名
+    rules: RwLock<HashMap<String, Vec<RateLimit>>>,
+    /// 按键的哈希分片
+    shards: Vec<Mutex<Counters>>,
+    hasher: RandomState,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            rules: RwLock::default(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl RateLimiter {
+    pub async fn rules(&self) -> HashMap<String, Vec<RateLimit>> {
+        self.rules.read().await.clone()
+    }
+
+    /// 替换域名的规则，同时清空它的计数器
+    pub async fn set(&self, domain: String, rules: Vec<RateLimit>) {
+        let mut store = self.rules.write().await;
+        self.reset(&domain);
+        store.insert(domain, rules);
+    }
+
+    /// 删除域名的规则，返回是否存在
+    pub async fn remove(&self, domain: &str) -> bool {
+        let mut store = self.rules.write().await;
+        self.reset(domain);
+        store.remove(domain).is_some()
+    }
+
+    fn reset(&self, domain: &str) {
+        for shard in &self.shards {
+            let mut counters = shard.lock().unwrap();
+            counters.entries.retain(|(name, _, _), _| name != domain);
+        }
+    }
+
+    fn shard(&self, key: &CounterKey) -> &Mutex<Counters> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    /// 按请求的 Host 的规则检查，没有时使用处理请求的域名的规则
+    /// 依次消耗每个匹配的规则的配额，遇到超过限制的规则时停止并退还之前消耗的配额，返回最严格的状态
+    /// `consume` 为否时只计算，不修改计数器
+    pub async fn check(
+        &self,
+        names: [Option<&str>; 2],
+        req: &RequestHeader,
+        client_ip: Option<IpAddr>,
+        consume: bool,
+    ) -> Option<RateLimitStatus> {
+        let store = self.rules.read().await;
+        let (name, rules) = names
+            .into_iter()
+            .flatten()
+            .find_map(|name| store.get_key_value(name))?;
+        let now = Instant::now();
+        let mut taken = Vec::new();
+        let mut result: Option<RateLimitStatus> = None;
+        for (i, rule) in rules.iter().enumerate() {
+            if rule
+                .path
+                .as_ref()
+                .is_some_and(|p| !p.is_match(req.uri.path()))
+            {
+                continue;
+            }
+            let key = (name.clone(), i, rule.key.of(req, client_ip));
+            let mut counters = self.shard(&key).lock().unwrap();
+            let status = if consume {
+                counters.sweep(&store, now);
+                counters
+                    .entry(key.clone(), rule, &store, now)
+                    .take(rule, now)
+            } else {
+                let mut counter = counters
+                    .entries
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or_else(|| Counter::new(rule, now));
+                counter.take(rule, now)
+            };
+            drop(counters);
+            if !status.allowed() {
+                if consume {
+                    self.refund(taken);
+                }
+                return Some(status);
+            }
+            taken.push((key, rule));
+            if result.map_or(true, |r| status.remaining < r.remaining) {
+                result = Some(status);
+            }
+        }
+        result
+    }
+
+    fn refund(&self, taken: Vec<(CounterKey, &RateLimit)>) {
+        for (key, rule) in taken {
+            let mut counters = self.shard(&key).lock().unwrap();
+            if let Some(counter) = counters.entries.get_mut(&key) {
+                counter.refund(rule);
+            }
+        }
+    }
+}
+
+impl Counters {
+    /// 键的计数器，没有时新建，分片已满时先淘汰最早可以清理的计数器
+    fn entry(
+        &mut self,
+        key: CounterKey,
+        rule: &RateLimit,
+        rules: &HashMap<String, Vec<RateLimit>>,
+        now: Instant,
+    ) -> &mut Counter {
+        let rule_of = |(name, i, _): &CounterKey| rules.get(name).and_then(|rules| rules.get(*i));
+        if !self.entries.contains_key(&key) && self.entries.len() >= MAX_COUNTERS_PER_SHARD {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(key, counter)| match rule_of(key) {
+                    Some(rule) => counter.idle_since(rule),
+                    None => now,
+                })
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries
+            .entry(key)
+            .or_insert_with(|| Counter::new(rule, now))
+    }
+
+    /// 定期清理与新建的计数器相同或规则已删除的计数器
+    fn sweep(&mut self, rules: &HashMap<String, Vec<RateLimit>>, now: Instant) {
+        if self
+            .swept
+            .is_some_and(|at| now.duration_since(at) < SWEEP_INTERVAL)
+        {
+            return;
+        }
+        self.swept = Some(now);
+        self.entries.retain(|(name, i, _), counter| {
+            match rules.get(name).and_then(|rules| rules.get(*i)) {
+                Some(rule) => counter.idle_since(rule) > now,
+                None => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(algorithm: RateLimitAlgorithm, limit: u32, burst: Option<u32>) -> RateLimit {
+        RateLimit {
+            path: None,
+            key: RateLimitKey::Ip,
+            algorithm,
+            limit,
+            window_secs: 10,
+            burst,
+        }
+    }
+
+    #[test]
+    fn bucket_take_and_refill() {
+        let rule = rule(RateLimitAlgorithm::TokenBucket, 10, Some(2));
+        let now = Instant::now();
+        let mut counter = Counter::new(&rule, now);
+        assert_eq!(counter.take(&rule, now).remaining, 1);
+        assert_eq!(counter.take(&rule, now).remaining, 0);
+        let rejected = counter.take(&rule, now);
+        assert_eq!(rejected.retry_after, Some(1));
+        assert_eq!(rejected.reset, 2);
+        // 每秒补充一个令牌
+        let status = counter.take(&rule, now + Duration::from_secs(1));
+        assert!(status.allowed());
+        assert_eq!(status.remaining, 0);
+        let status = counter.take(&rule, now + Duration::from_secs(60));
+        assert_eq!(status.limit, 2);
+        assert_eq!(status.remaining, 1);
+    }
+
+    #[test]
+    fn bucket_idle_after_full_refill() {
+        // 容量 20，速率 1/秒，需要 20 秒补满，大于窗口长度
+        let rule = rule(RateLimitAlgorithm::TokenBucket, 10, Some(20));
+        let now = Instant::now();
+        let mut counter = Counter::new(&rule, now);
+        for _ in 0..20 {
+            assert!(counter.take(&rule, now).allowed());
+        }
+        assert_eq!(counter.idle_since(&rule), now + Duration::from_secs(20));
+    }
+
+    #[test]
+    fn idle_since_does_not_overflow() {
+        let now = Instant::now();
+        for algorithm in [
+            RateLimitAlgorithm::TokenBucket,
+            RateLimitAlgorithm::SlidingWindow,
+        ] {
+            let rule = RateLimit {
+                window_secs: u64::MAX,
+                ..rule(algorithm, 1, Some(u32::MAX))
+            };
+            let mut counter = Counter::new(&rule, now);
+            assert!(counter.take(&rule, now).allowed());
+            assert_eq!(counter.idle_since(&rule), now + MAX_IDLE);
+        }
+    }
+
+    #[test]
+    fn window_take_and_slide() {
+        let rule = rule(RateLimitAlgorithm::SlidingWindow, 2, None);
+        let now = Instant::now();
+        let mut counter = Counter::new(&rule, now);
+        assert!(counter.take(&rule, now).allowed());
+        assert!(counter.take(&rule, now).allowed());
+        let rejected = counter.take(&rule, now);
+        assert!(!rejected.allowed());
+        assert_eq!(rejected.reset, 10);
+        // 下一个窗口的一半时，上一个窗口按一半计算
+        let later = now + Duration::from_secs(15);
+        assert!(counter.take(&rule, later).allowed());
+        assert!(!counter.take(&rule, later).allowed());
+        assert!(counter.take(&rule, now + Duration::from_secs(40)).allowed());
+    }
+
+    #[test]
+    fn refund_returns_quota() {
+        let now = Instant::now();
+        let bucket = rule(RateLimitAlgorithm::TokenBucket, 1, None);
+        let mut counter = Counter::new(&bucket, now);
+        assert!(counter.take(&bucket, now).allowed());
+        counter.refund(&bucket);
+        counter.refund(&bucket);
+        assert!(counter.take(&bucket, now).allowed());
+        assert!(!counter.take(&bucket, now).allowed());
+
+        let window = rule(RateLimitAlgorithm::SlidingWindow, 1, None);
+        let mut counter = Counter::new(&window, now);
+        assert!(counter.take(&window, now).allowed());
+        counter.refund(&window);
+        assert!(counter.take(&window, now).allowed());
+    }
+
+    #[tokio::test]
+    async fn rejected_request_refunds_earlier_rules() {
+        let limiter = RateLimiter::default();
+        let mut strict = rule(RateLimitAlgorithm::TokenBucket, 1, None);
+        strict.path = Some(PathPattern::new("^/api").unwrap());
+        limiter
+            .set(
+                "example.com".to_string(),
+                vec![rule(RateLimitAlgorithm::TokenBucket, 2, None), strict],
+            )
+            .await;
+        let limiter = &limiter;
+        let check = |path: &'static str| async move {
+            let req = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+            let ip = Some(IpAddr::from([10, 0, 0, 1]));
+            limiter
+                .check([Some("example.com"), None], &req, ip, true)
+                .await
+                .unwrap()
+        };
+
+        assert_eq!(check("/api").await.remaining, 0);
+        assert!(!check("/api").await.allowed());
+        // 被拒绝的请求不消耗第一个规则的配额
+        assert!(check("/").await.allowed());
+        assert!(!check("/").await.allowed());
+    }
+
+    #[tokio::test]
+    async fn counters_are_capped() {
+        let limiter = RateLimiter::default();
+        limiter
+            .set(
+                "example.com".to_string(),
+                vec![rule(RateLimitAlgorithm::TokenBucket, 1, None)],
+            )
+            .await;
+        let names = [Some("example.com"), None];
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        for i in 0..(SHARDS * MAX_COUNTERS_PER_SHARD + 1000) as u32 {
+            let ip = Some(IpAddr::from(i.to_be_bytes()));
+            limiter.check(names, &req, ip, true).await;
+        }
+        let total: usize = limiter
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum();
+        assert!(total <= SHARDS * MAX_COUNTERS_PER_SHARD);
+    }
+}
@@ -0,0 +1,41 @@
+use bytes::Bytes;
+use hyper::header;
+use pingora::{http::ResponseHeader, proxy::Session, Result};
+
+use super::REQUEST_ID_HEADER;
+
+/// 由代理直接向客户端发送响应，不访问后端，响应已开始发送时忽略
+pub async fn respond(
+    session: &mut Session,
+    mut resp: ResponseHeader,
+    body: Bytes,
+    request_id: &str,
+) -> Result<()> {
+    if session.response_written().is_some() {
+        return Ok(());
+    }
+    resp.insert_header(header::CONTENT_LENGTH, body.len())?;
+    resp.insert_header(REQUEST_ID_HEADER, request_id)?;
+    if body.is_empty() {
+        return session.write_response_header(Box::new(resp), true).await;
+    }
+    session.write_response_header(Box::new(resp), false).await?;
+    session.write_response_body(Some(body), true).await
+}
+
+/// 重定向到 `location`，同时设置 `cookies`
+pub async fn redirect(
+    session: &mut Session,
+    status: u16,
+    location: &str,
+    cookies: &[String],
+    request_id: &str,
+) -> Result<()> {
+    let mut resp = ResponseHeader::build(status, Some(4 + cookies.len()))?;
+    resp.insert_header(header::LOCATION, location)?;
+    resp.insert_header(header::CACHE_CONTROL, "private, no-store")?;
+    for cookie in cookies {
+        resp.append_header(header::SET_COOKIE, cookie)?;
+    }
+    respond(session, resp, Bytes::new(), request_id).await
+}
@@ -0,0 +1,510 @@
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+use bytes::Bytes;
+use flate2::write::{GzDecoder, ZlibDecoder};
+use hyper::header::{self, HeaderValue};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    Result,
+};
+
+use super::ErrorCode;
+use crate::svcs::{parse_authority, Authority, PathPrefix, ResponseRewrite};
+
+/// 可以解压后改写的压缩格式
+const DECODABLE_ENCODINGS: [&str; 4] = ["gzip", "x-gzip", "deflate", "identity"];
+/// 解压后响应体的最大长度，避免解压炸弹
+const MAX_DECODED_BODY: usize = 64 << 20;
+
+/// 将响应中指向后端域名的地址改写为代理的地址
+pub struct Rewriter {
+    /// 需要改写的后端域名，已规范化
+    hosts: Vec<String>,
+    /// 代理的地址，例如 `http://google.local:6188`
+    origin: String,
+    path_prefix: Option<PathPrefix>,
+    cookie_domain: bool,
+}
+
+impl Rewriter {
+    /// `upstream_host` 为发送给后端的 Host，`client` 为客户端请求的 authority，
+    /// 没有需要改写的后端域名及路径时返回空
+    pub fn new(
+        config: &ResponseRewrite,
+        upstream_host: Option<&str>,
+        scheme: &str,
+        client: &Authority,
+    ) -> Option<Self> {
+        let mut hosts: Vec<String> = upstream_host
+            .into_iter()
+            .chain(config.hosts.iter().map(String::as_str))
+            .filter_map(|host| parse_authority(host).ok())
+            .map(|upstream| upstream.host)
+            .filter(|host| *host != client.host)
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+        if hosts.is_empty() && config.path_prefix.is_none() {
+            return None;
+        }
+        let scheme = config.scheme.as_deref().unwrap_or(scheme);
+        Some(Self {
+            hosts,
+            origin: format!("{scheme}://{client}"),
+            path_prefix: config.path_prefix.clone(),
+            cookie_domain: config.cookie_domain,
+        })
+    }
+
+    /// 后端的路径前缀改为代理的路径前缀，前缀需在路径的分段处结束，`/app` 不匹配 `/application`
+    fn rewrite_path(&self, path: &str) -> Option<String> {
+        let prefix = self.path_prefix.as_ref()?;
+        let rest = path.strip_prefix(prefix.upstream.as_str())?;
+        let boundary =
+            prefix.upstream.ends_with('/') || rest.is_empty() || rest.starts_with(['/', '?', '#']);
+        boundary.then(|| format!("{}{rest}", prefix.client))
+    }
+
+    /// 改写指向后端域名的绝对地址及以 `/` 开头的路径，无需改写时返回空
+    fn rewrite_url(&self, url: &str) -> Option<String> {
+        let rest = match url.split_once("://") {
+            Some((scheme, rest))
+                if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+            {
+                rest
+            }
+            Some(_) => return None,
+            None => match url.strip_prefix("//") {
+                Some(rest) => rest,
+                None if url.starts_with('/') => return self.rewrite_path(url),
+                None => return None,
+            },
+        };
+        let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(end);
+        let host = parse_authority(authority).ok()?.host;
+        if !self.hosts.contains(&host) {
+            return None;
+        }
+        let path = self.rewrite_path(path).unwrap_or_else(|| path.to_string());
+        Some(format!("{}{path}", self.origin))
+    }
+
+    /// 改写 `Set-Cookie` 的 `Domain` 及 `Path`，无需改写时返回空
+    fn rewrite_cookie(&self, cookie: &str) -> Option<String> {
+        let mut changed = false;
+        let mut parts = Vec::new();
+        for (i, part) in cookie.split(';').enumerate() {
+            let (name, value) = part.split_once('=').unwrap_or((part, ""));
+            let name = name.trim();
+            if i > 0 && self.cookie_domain && name.eq_ignore_ascii_case("domain") {
+                let domain = value.trim().trim_start_matches('.');
+                let matched = parse_authority(domain).is_ok_and(|domain| {
+                    self.hosts.iter().any(|host| {
+                        host == &domain.host || host.ends_with(&format!(".{}", domain.host))
+                    })
+                });
+                if matched {
+                    changed = true;
+                    continue;
+                }
+            }
+            if i > 0 && name.eq_ignore_ascii_case("path") {
+                if let Some(path) = self.rewrite_path(value.trim()) {
+                    changed = true;
+                    parts.push(format!(" {name}={path}"));
+                    continue;
+                }
+            }
+            parts.push(part.to_string());
+        }
+        changed.then(|| parts.join(";"))
+    }
+
+    /// 改写 `Location`、`Content-Location`、`Refresh` 及 `Set-Cookie`
+    pub fn rewrite_headers(&self, resp: &mut ResponseHeader) -> Result<()> {
+        for name in [header::LOCATION, header::CONTENT_LOCATION] {
+            let url = resp.headers.get(&name).and_then(|v| v.to_str().ok());
+            if let Some(url) = url.and_then(|url| self.rewrite_url(url)) {
+                resp.insert_header(name, url)?;
+            }
+        }
+
+        // 例如 `5; url=https://www.example.com/`
+        let refresh = resp.headers.get("refresh").and_then(|v| v.to_str().ok());
+        if let Some(refresh) = refresh {
+            let start = refresh
+                .to_ascii_lowercase()
+                .find("url=")
+                .map(|i| i + "url=".len());
+            if let Some(start) = start {
+                let (delay, url) = refresh.split_at(start);
+                let quoted = url.trim_matches(['\'', '"']);
+                if let Some(url) = self.rewrite_url(quoted) {
+                    let refresh = format!("{delay}{url}");
+                    resp.insert_header("refresh", refresh)?;
+                }
+            }
+        }
+
+        let cookies: Vec<HeaderValue> = resp
+            .headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .cloned()
+            .collect();
+        let rewritten: Vec<Option<String>> = cookies
+            .iter()
+            .map(|cookie| self.rewrite_cookie(cookie.to_str().ok()?))
+            .collect();
+        if rewritten.iter().any(Option::is_some) {
+            resp.remove_header(&header::SET_COOKIE);
+            for (cookie, rewritten) in cookies.into_iter().zip(rewritten) {
+                match rewritten {
+                    Some(cookie) => resp.append_header(header::SET_COOKIE, cookie)?,
+                    None => resp.append_header(header::SET_COOKIE, cookie)?,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// 响应体中需要替换的内容，包括 JSON 中转义的 `/`
+    fn body_patterns(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let escaped_origin = self.origin.replace('/', "\\/");
+        let mut patterns = Vec::new();
+        for host in &self.hosts {
+            for scheme in ["https", "http"] {
+                patterns.push((
+                    format!("{scheme}://{host}").into_bytes(),
+                    self.origin.clone().into_bytes(),
+                ));
+                patterns.push((
+                    format!("{scheme}:\\/\\/{host}").into_bytes(),
+                    escaped_origin.clone().into_bytes(),
+                ));
+            }
+        }
+        patterns.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        patterns
+    }
+}
+
+/// 只请求可以解压的压缩格式，客户端不接受任何压缩时保持不变
+pub fn restrict_encoding(req: &mut RequestHeader) -> Result<()> {
+    let Some(accept) = req
+        .headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Ok(());
+    };
+    let accepted: Vec<&str> = accept
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| {
+            let name = coding.split(';').next().unwrap_or_default().trim();
+            DECODABLE_ENCODINGS
+                .iter()
+                .any(|d| d.eq_ignore_ascii_case(name))
+        })
+        .collect();
+    let accepted = if accepted.is_empty() {
+        "identity".to_string()
+    } else {
+        accepted.join(", ")
+    };
+    req.insert_header(header::ACCEPT_ENCODING, accepted)?;
+    Ok(())
+}
+
+/// 解压的输出，累计长度超过限制时写入失败
+struct LimitedBuf {
+    buf: Vec<u8>,
+    written: usize,
+    limit: usize,
+}
+
+impl LimitedBuf {
+    fn new(limit: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            written: 0,
+            limit,
+        }
+    }
+}
+
+impl Write for LimitedBuf {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.written += data.len();
+        if self.written > self.limit {
+            return Err(io::Error::other(format!(
+                "decoded body exceeds {} bytes",
+                self.limit
+            )));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 解压响应体
+enum Decoder {
+    Identity,
+    Gzip(GzDecoder<LimitedBuf>),
+    Deflate(ZlibDecoder<LimitedBuf>),
+}
+
+impl Decoder {
+    fn decode(&mut self, data: &[u8], end_of_stream: bool) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Identity => Ok(data.to_vec()),
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                if end_of_stream {
+                    decoder.try_finish()?;
+                }
+                Ok(mem::take(&mut decoder.get_mut().buf))
+            }
+            Decoder::Deflate(decoder) => {
+                decoder.write_all(data)?;
+                if end_of_stream {
+                    decoder.try_finish()?;
+                }
+                Ok(mem::take(&mut decoder.get_mut().buf))
+            }
+        }
+    }
+}
+
+/// 流式改写响应体中的绝对地址，改写后的响应体不压缩
+pub struct BodyRewriter {
+    decoder: Decoder,
+    patterns: Vec<(Vec<u8>, Vec<u8>)>,
+    /// 末尾可能是未完整收到的地址，等待下一块数据
+    pending: Vec<u8>,
+    /// 保留的最大长度，最长的地址及其后的一个字节
+    keep: usize,
+}
+
+impl BodyRewriter {
+    /// 响应的类型及压缩格式可以改写时返回，同时去掉响应头中的长度及压缩格式
+    pub fn new(
+        rewriter: &Rewriter,
+        config: &ResponseRewrite,
+        resp: &mut ResponseHeader,
+    ) -> Result<Option<Self>> {
+        if !config.body
+            || rewriter.hosts.is_empty()
+            || matches!(resp.status.as_u16(), 204 | 206 | 304)
+        {
+            return Ok(None);
+        }
+        let header_str = |name: header::HeaderName| {
+            resp.headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(|v| {
+                    v.split(';')
+                        .next()
+                        .unwrap_or_default()
+                        .trim()
+                        .to_ascii_lowercase()
+                })
+        };
+        let Some(content_type) = header_str(header::CONTENT_TYPE) else {
+            return Ok(None);
+        };
+        if !config
+            .body_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(&content_type))
+        {
+            return Ok(None);
+        }
+        let decoder = match header_str(header::CONTENT_ENCODING).as_deref() {
+            None | Some("identity") => Decoder::Identity,
+            Some("gzip" | "x-gzip") => {
+                Decoder::Gzip(GzDecoder::new(LimitedBuf::new(MAX_DECODED_BODY)))
+            }
+            Some("deflate") => {
+                Decoder::Deflate(ZlibDecoder::new(LimitedBuf::new(MAX_DECODED_BODY)))
+            }
+            Some(_) => return Ok(None),
+        };
+        resp.remove_header(&header::CONTENT_LENGTH);
+        resp.remove_header(&header::CONTENT_ENCODING);
+        resp.insert_header(header::TRANSFER_ENCODING, "chunked")?;
+        let patterns = rewriter.body_patterns();
+        let keep = patterns
+            .iter()
+            .map(|(from, _)| from.len())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        Ok(Some(Self {
+            decoder,
+            patterns,
+            pending: Vec::new(),
+            keep,
+        }))
+    }
+
+    /// 地址后不能紧跟域名中的字符或端口，避免改写 `example.com.evil` 等
+    fn matches_at(&self, buf: &[u8], i: usize, end_of_stream: bool) -> Option<usize> {
+        self.patterns.iter().position(|(from, _)| {
+            buf[i..].starts_with(from)
+                && match buf.get(i + from.len()) {
+                    Some(b) => {
+                        !(b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_' | b':'))
+                    }
+                    None => end_of_stream,
+                }
+        })
+    }
+
+    pub fn filter(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) -> Result<()> {
+        let data = body.as_deref().unwrap_or_default();
+        let decoded = self.decoder.decode(data, end_of_stream).map_err(|e| {
+            ErrorCode::UpstreamProtocolError.error(format!("Decode response body failed: {e}"))
+        })?;
+        self.pending.extend_from_slice(&decoded);
+        let buf = mem::take(&mut self.pending);
+        let safe = if end_of_stream {
+            buf.len()
+        } else {
+            buf.len().saturating_sub(self.keep)
+        };
+
+        let mut out = Vec::with_capacity(buf.len());
+        let (mut i, mut copied) = (0, 0);
+        while i < safe {
+            // 所有地址都以 `http` 开头
+            if buf[i] != b'h' {
+                i += 1;
+                continue;
+            }
+            let Some(index) = self.matches_at(&buf, i, end_of_stream) else {
+                i += 1;
+                continue;
+            };
+            let (from, to) = &self.patterns[index];
+            out.extend_from_slice(&buf[copied..i]);
+            out.extend_from_slice(to);
+            i += from.len();
+            copied = i;
+        }
+        out.extend_from_slice(&buf[copied..i]);
+        self.pending = buf[i..].to_vec();
+        *body = (!out.is_empty()).then(|| Bytes::from(out));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn config() -> ResponseRewrite {
+        ResponseRewrite {
+            hosts: Vec::new(),
+            scheme: None,
+            path_prefix: Some(PathPrefix {
+                upstream: "/app".to_string(),
+                client: "/proxy/app".to_string(),
+            }),
+            cookie_domain: true,
+            body: true,
+            body_types: vec!["text/html".to_string()],
+        }
+    }
+
+    fn rewriter() -> Rewriter {
+        let client = parse_authority("proxy.local:6188").unwrap();
+        Rewriter::new(&config(), Some("backend.internal"), "http", &client).unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn path_prefix_ends_at_segment() {
+        let rewriter = rewriter();
+        let path = |path| rewriter.rewrite_path(path);
+        assert_eq!(path("/app").as_deref(), Some("/proxy/app"));
+        assert_eq!(path("/app/x").as_deref(), Some("/proxy/app/x"));
+        assert_eq!(path("/app?x=1").as_deref(), Some("/proxy/app?x=1"));
+        assert_eq!(path("/application"), None);
+        assert_eq!(path("/other"), None);
+    }
+
+    #[test]
+    fn rewrite_urls_and_cookies() {
+        let rewriter = rewriter();
+        assert_eq!(
+            rewriter
+                .rewrite_url("https://backend.internal/app/login")
+                .as_deref(),
+            Some("http://proxy.local:6188/proxy/app/login")
+        );
+        assert_eq!(rewriter.rewrite_url("https://other.example/app"), None);
+        assert_eq!(
+            rewriter
+                .rewrite_cookie("sid=1; Domain=.backend.internal; Path=/app")
+                .as_deref(),
+            Some("sid=1; Path=/proxy/app")
+        );
+        assert_eq!(rewriter.rewrite_cookie("sid=1; Path=/"), None);
+    }
+
+    #[test]
+    fn rewrite_body_across_chunks() {
+        let rewriter = rewriter();
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .unwrap();
+        resp.insert_header(header::CONTENT_ENCODING, "gzip")
+            .unwrap();
+        let mut body_rewriter = BodyRewriter::new(&rewriter, &config(), &mut resp)
+            .unwrap()
+            .unwrap();
+        assert!(resp.headers.get(header::CONTENT_ENCODING).is_none());
+
+        let html = b"<a href=\"http://backend.internal/x\">http://backend.internal.evil/</a>";
+        let compressed = gzip(html);
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let mut out = Vec::new();
+        for (chunk, end) in [(first, false), (second, true)] {
+            let mut body = Some(Bytes::copy_from_slice(chunk));
+            body_rewriter.filter(&mut body, end).unwrap();
+            out.extend_from_slice(body.as_deref().unwrap_or_default());
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<a href=\"http://proxy.local:6188/x\">http://backend.internal.evil/</a>"
+        );
+    }
+
+    #[test]
+    fn decoded_size_is_limited() {
+        let compressed = gzip(&vec![0; 1 << 20]);
+        let mut decoder = Decoder::Gzip(GzDecoder::new(LimitedBuf::new(64 << 10)));
+        assert!(decoder.decode(&compressed, true).is_err());
+
+        let mut decoder = Decoder::Gzip(GzDecoder::new(LimitedBuf::new(2 << 20)));
+        assert_eq!(decoder.decode(&compressed, true).unwrap().len(), 1 << 20);
+    }
+}
@@ -0,0 +1,250 @@
+use std::time::{Duration, Instant};
+
+use pingora::{prelude::HttpPeer, Error, ErrorSource, ErrorType, Result};
+
+use crate::svcs::Timeouts;
+
+/// 超时的种类，每种超时对应不同的错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    TlsHandshake,
+    FirstByte,
+    ReadIdle,
+    WriteIdle,
+    Total,
+}
+
+impl TimeoutKind {
+    const ALL: [TimeoutKind; 6] = [
+        TimeoutKind::Connect,
+        TimeoutKind::TlsHandshake,
+        TimeoutKind::FirstByte,
+        TimeoutKind::ReadIdle,
+        TimeoutKind::WriteIdle,
+        TimeoutKind::Total,
+    ];
+
+    /// 作为 `ErrorType::Custom` 的错误名称
+    pub fn code(self) -> &'static str {
+        match self {
+            TimeoutKind::Connect => "UpstreamConnectTimeout",
+            TimeoutKind::TlsHandshake => "UpstreamTlsHandshakeTimeout",
+            TimeoutKind::FirstByte => "UpstreamFirstByteTimeout",
+            TimeoutKind::ReadIdle => "UpstreamReadIdleTimeout",
+            TimeoutKind::WriteIdle => "UpstreamWriteIdleTimeout",
+            TimeoutKind::Total => "RequestTotalTimeout",
+        }
+    }
+
+    /// 未能与后端建立连接返回 502，已建立连接但后端未及时响应返回 504
+    pub fn status(self) -> u16 {
+        match self {
+            TimeoutKind::Connect | TimeoutKind::TlsHandshake => 502,
+            _ => 504,
+        }
+    }
+
+    pub fn error(self, context: String) -> Box<Error> {
+        let mut err = Error::explain(ErrorType::Custom(self.code()), context);
+        err.as_up();
+        err
+    }
+
+    /// 错误对应的超时种类，不是超时时返回空
+    /// 读超时在收到响应头之前为等待响应头超时，之后为读空闲超时
+    pub fn of(e: &Error, response_started: bool) -> Option<Self> {
+        match e.etype() {
+            ErrorType::Custom(code) => Self::ALL.into_iter().find(|k| k.code() == *code),
+            _ if e.esource() != &ErrorSource::Upstream => None,
+            ErrorType::ConnectTimedout => Some(TimeoutKind::Connect),
+            ErrorType::TLSHandshakeTimedout => Some(TimeoutKind::TlsHandshake),
+            ErrorType::ReadTimedout if response_started => Some(TimeoutKind::ReadIdle),
+            ErrorType::ReadTimedout => Some(TimeoutKind::FirstByte),
+            ErrorType::WriteTimedout => Some(TimeoutKind::WriteIdle),
+            _ => None,
+        }
+    }
+}
+
+/// 请求剩余的总时间，未设置总超时时为空，已超时时返回错误
+pub fn remaining(timeouts: &Timeouts, start: Instant) -> Result<Option<Duration>> {
+    let Some(total) = timeouts.total() else {
+        return Ok(None);
+    };
+    match total.checked_sub(start.elapsed()) {
+        Some(remaining) if !remaining.is_zero() => Ok(Some(remaining)),
+        _ => Err(TimeoutKind::Total.error(format!("Request exceeded total timeout {total:?}"))),
+    }
+}
+
+/// 区分连接超时，`started` 为开始连接的时间
+/// pingora 的 TCP 连接超时与包含 TLS 握手的总连接超时使用相同的错误类型，
+/// 超过 TCP 连接超时一段时间后才发生的连接超时由总连接超时产生，为 TLS 握手超时
+pub fn classify_connect(e: Box<Error>, peer: &HttpPeer, started: Instant) -> Box<Error> {
+    let Some(total) = peer.options.total_connection_timeout else {
+        return e;
+    };
+    if e.etype() != &ErrorType::ConnectTimedout {
+        return e;
+    }
+    // 两个超时之间的中点，避免计时的误差
+    let threshold = match peer.options.connection_timeout {
+        Some(connect) => connect + total.saturating_sub(connect) / 2,
+        None => Duration::ZERO,
+    };
+    if started.elapsed() < threshold {
+        return e;
+    }
+    let mut err = Error::because(
+        ErrorType::Custom(TimeoutKind::TlsHandshake.code()),
+        format!("TLS handshake not completed in {total:?}"),
+        e,
+    );
+    err.as_up();
+    err
+}
+
+/// 将域名的超时设置应用到 HttpPeer，各项超时不超过请求剩余的总时间
+/// pingora 的读超时作用于每次读取，对响应头和响应体相同，
+/// 因此 `first_byte_ms` 与 `read_idle_ms` 只能设置其中一项或相同的值，由管理 API 检查
+pub fn apply(timeouts: &Timeouts, peer: &mut HttpPeer, start: Instant) -> Result<()> {
+    let remaining = remaining(timeouts, start)?;
+    let clamp = |timeout: Option<Duration>| match (timeout, remaining) {
+        (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+        (timeout, remaining) => timeout.or(remaining),
+    };
+    let options = &mut peer.options;
+    options.connection_timeout = clamp(timeouts.connect());
+    // 总连接超时包含 TCP 连接及 TLS 握手
+    let handshake = timeouts
+        .tls_handshake()
+        .map(|tls| timeouts.connect().unwrap_or_default() + tls);
+    options.total_connection_timeout = clamp(handshake);
+    options.read_timeout = clamp(timeouts.first_byte().or(timeouts.read_idle()));
+    options.write_timeout = clamp(timeouts.write_idle());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(etype: ErrorType) -> Box<Error> {
+        let mut err = Error::explain(etype, "test");
+        err.as_up();
+        err
+    }
+
+    fn peer(connect_ms: Option<u64>, total_ms: Option<u64>) -> HttpPeer {
+        let mut peer = HttpPeer::new("127.0.0.1:443", true, "example.com".to_string());
+        peer.options.connection_timeout = connect_ms.map(Duration::from_millis);
+        peer.options.total_connection_timeout = total_ms.map(Duration::from_millis);
+        peer
+    }
+
+    #[test]
+    fn kind_of_error_type() {
+        let of = |etype, started| TimeoutKind::of(&upstream(etype), started);
+        assert_eq!(
+            of(ErrorType::ConnectTimedout, false),
+            Some(TimeoutKind::Connect)
+        );
+        assert_eq!(
+            of(ErrorType::TLSHandshakeTimedout, false),
+            Some(TimeoutKind::TlsHandshake)
+        );
+        assert_eq!(
+            of(ErrorType::ReadTimedout, false),
+            Some(TimeoutKind::FirstByte)
+        );
+        assert_eq!(
+            of(ErrorType::ReadTimedout, true),
+            Some(TimeoutKind::ReadIdle)
+        );
+        assert_eq!(
+            of(ErrorType::WriteTimedout, false),
+            Some(TimeoutKind::WriteIdle)
+        );
+        assert_eq!(of(ErrorType::ConnectRefused, false), None);
+
+        // 客户端的读超时不是后端超时
+        let mut downstream = Error::explain(ErrorType::ReadTimedout, "test");
+        downstream.as_down();
+        assert_eq!(TimeoutKind::of(&downstream, false), None);
+    }
+
+    #[test]
+    fn kind_of_custom_code() {
+        for kind in TimeoutKind::ALL {
+            let err = kind.error("test".to_string());
+            assert_eq!(TimeoutKind::of(&err, false), Some(kind));
+        }
+    }
+
+    #[test]
+    fn status_by_kind() {
+        assert_eq!(TimeoutKind::Connect.status(), 502);
+        assert_eq!(TimeoutKind::TlsHandshake.status(), 502);
+        assert_eq!(TimeoutKind::FirstByte.status(), 504);
+        assert_eq!(TimeoutKind::ReadIdle.status(), 504);
+        assert_eq!(TimeoutKind::Total.status(), 504);
+    }
+
+    #[test]
+    fn classify_connect_timeouts() {
+        let ago = |ms| Instant::now() - Duration::from_millis(ms);
+        let classify = |peer: &HttpPeer, started| {
+            let err = classify_connect(upstream(ErrorType::ConnectTimedout), peer, started);
+            TimeoutKind::of(&err, false)
+        };
+        let both = peer(Some(1000), Some(3000));
+        assert_eq!(classify(&both, ago(1000)), Some(TimeoutKind::Connect));
+        assert_eq!(classify(&both, ago(3000)), Some(TimeoutKind::TlsHandshake));
+        // 没有总连接超时时只可能是 TCP 连接超时
+        let connect = peer(Some(1000), None);
+        assert_eq!(classify(&connect, ago(5000)), Some(TimeoutKind::Connect));
+        // 只有总连接超时时只可能是 TLS 握手超时
+        let tls = peer(None, Some(2000));
+        assert_eq!(classify(&tls, ago(2000)), Some(TimeoutKind::TlsHandshake));
+
+        let refused = classify_connect(upstream(ErrorType::ConnectRefused), &both, ago(3000));
+        assert_eq!(refused.etype(), &ErrorType::ConnectRefused);
+    }
+
+    #[test]
+    fn read_timeout_from_either_phase() {
+        for timeouts in [
+            Timeouts {
+                first_byte_ms: Some(5000),
+                ..Default::default()
+            },
+            Timeouts {
+                read_idle_ms: Some(5000),
+                ..Default::default()
+            },
+        ] {
+            let mut peer = peer(None, None);
+            apply(&timeouts, &mut peer, Instant::now()).unwrap();
+            assert_eq!(peer.options.read_timeout, Some(Duration::from_secs(5)));
+        }
+    }
+
+    #[test]
+    fn total_timeout_clamps_others() {
+        let timeouts = Timeouts {
+            connect_ms: Some(10_000),
+            total_ms: Some(2000),
+            ..Default::default()
+        };
+        let mut peer = peer(None, None);
+        apply(&timeouts, &mut peer, Instant::now()).unwrap();
+        assert!(peer.options.connection_timeout.unwrap() <= Duration::from_secs(2));
+        assert!(peer.options.read_timeout.unwrap() <= Duration::from_secs(2));
+
+        let started = Instant::now() - Duration::from_secs(3);
+        let err = remaining(&timeouts, started).unwrap_err();
+        assert_eq!(TimeoutKind::of(&err, false), Some(TimeoutKind::Total));
+        assert_eq!(remaining(&Timeouts::default(), started).unwrap(), None);
+    }
+}
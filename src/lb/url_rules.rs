@@ -0,0 +1,338 @@
+use std::{collections::HashMap, sync::Arc};
+
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::pages::render;
+use crate::svcs::PathPattern;
+
+/// 每个域名按顺序执行的地址规则，键为请求的 Host 或域名
+pub type UrlRuleStore = Arc<RwLock<HashMap<String, Vec<UrlRule>>>>;
+
+/// 地址规则，路径匹配时执行
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UrlRule {
+    /// 匹配路径的正则表达式，为空时匹配所有请求，捕获组可在 `to` 及 `location` 中使用
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathPattern>,
+    #[serde(flatten)]
+    pub action: UrlAction,
+    /// 匹配后不再执行后续规则
+    #[serde(default)]
+    pub last: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum UrlAction {
+    /// 替换路径中匹配的部分，`$1`、`${name}` 为捕获组，没有 `path` 时替换整个路径，
+    /// 结果不以 `/` 开头时补上
+    Rewrite {
+        to: String,
+    },
+    /// 去掉路径前缀，只匹配完整的路径段
+    StripPrefix {
+        prefix: String,
+    },
+    AddPrefix {
+        prefix: String,
+    },
+    /// 设置查询参数，已有的同名参数被替换，名称及值会被编码
+    SetQuery {
+        name: String,
+        value: String,
+    },
+    RemoveQuery {
+        name: String,
+    },
+    /// 由代理直接重定向，`location` 中可以使用捕获组及 `{{host}}`、`{{path}}`，
+    /// `keep_query` 时 `location` 没有查询参数则追加请求的查询参数
+    Redirect {
+        location: String,
+        #[serde(default = "default_redirect_status")]
+        status: u16,
+        #[serde(default = "default_keep_query")]
+        keep_query: bool,
+    },
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+fn default_keep_query() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UrlRedirect {
+    pub status: u16,
+    pub location: String,
+}
+
+/// 执行地址规则的结果
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UrlOutcome {
+    /// 改写后的路径
+    pub path: String,
+    /// 改写后的查询参数，不含 `?`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// 匹配的规则的序号
+    pub matched: Vec<usize>,
+    /// 匹配重定向规则时，由代理直接返回的重定向
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<UrlRedirect>,
+}
+
+impl UrlOutcome {
+    /// 改写后的路径及查询参数
+    pub fn uri(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{query}", self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// 按顺序执行地址规则，遇到重定向或 `last` 时停止
+pub fn evaluate_url_rules(
+    rules: &[UrlRule],
+    host: &str,
+    path: &str,
+    query: Option<&str>,
+) -> UrlOutcome {
+    let mut outcome = UrlOutcome {
+        path: path.to_string(),
+        query: query.map(str::to_string),
+        matched: Vec::new(),
+        redirect: None,
+    };
+    for (i, rule) in rules.iter().enumerate() {
+        let pattern = match &rule.path {
+            Some(pattern) if !pattern.is_match(&outcome.path) => continue,
+            pattern => pattern.as_ref(),
+        };
+        outcome.matched.push(i);
+        match &rule.action {
+            UrlAction::Rewrite { to } => {
+                outcome.path = match pattern {
+                    Some(pattern) => pattern.replace(&outcome.path, to.as_str()).into_owned(),
+                    None => to.clone(),
+                };
+                if !outcome.path.starts_with('/') {
+                    outcome.path.insert(0, '/');
+                }
+            }
+            UrlAction::StripPrefix { prefix } => {
+                let prefix = prefix.trim_end_matches('/');
+                if let Some(rest) = outcome.path.strip_prefix(prefix) {
+                    if rest.is_empty() || rest.starts_with('/') {
+                        outcome.path = format!("/{}", rest.trim_start_matches('/'));
+                    }
+                }
+            }
+            UrlAction::AddPrefix { prefix } => {
+                outcome.path = format!("{}{}", prefix.trim_end_matches('/'), outcome.path);
+            }
+            UrlAction::SetQuery { name, value } => {
+                let pair = encode_pair(name, value);
+                let name = pair.split('=').next().unwrap_or_default();
+                let mut pairs = query_pairs(outcome.query.as_deref(), name);
+                pairs.push(pair.clone());
+                outcome.query = Some(pairs.join("&"));
+            }
+            UrlAction::RemoveQuery { name } => {
+                let pair = encode_pair(name, "");
+                let name = pair.split('=').next().unwrap_or_default();
+                let pairs = query_pairs(outcome.query.as_deref(), name);
+                outcome.query = (!pairs.is_empty()).then(|| pairs.join("&"));
+            }
+            UrlAction::Redirect {
+                location,
+                status,
+                keep_query,
+            } => {
+                let mut expanded = String::new();
+                match pattern.and_then(|p| Regex::captures(p, &outcome.path)) {
+                    Some(captures) => captures.expand(location, &mut expanded),
+                    None => expanded.push_str(location),
+                }
+                let mut location =
+                    render(&expanded, &[("host", host), ("path", &outcome.path)], false);
+                if let Some(query) = outcome.query.as_ref().filter(|_| *keep_query) {
+                    if !location.contains('?') {
+                        location = format!("{location}?{query}");
+                    }
+                }
+                outcome.redirect = Some(UrlRedirect {
+                    status: *status,
+                    location,
+                });
+                break;
+            }
+        }
+        if rule.last {
+            break;
+        }
+    }
+    outcome
+}
+
+/// 按 `application/x-www-form-urlencoded` 编码的一个查询参数
+fn encode_pair(name: &str, value: &str) -> String {
+    let mut url = Url::parse("http://localhost/").expect("valid url");
+    url.query_pairs_mut().append_pair(name, value);
+    url.query().unwrap_or_default().to_string()
+}
+
+/// 查询参数中名称不是 `name` 的参数，`name` 为编码后的名称
+fn query_pairs(query: Option<&str>, name: &str) -> Vec<String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty() && pair.split('=').next() != Some(name))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: Option<&str>, action: UrlAction) -> UrlRule {
+        UrlRule {
+            path: path.map(|p| PathPattern::new(p).unwrap()),
+            action,
+            last: false,
+        }
+    }
+
+    fn evaluate(rules: &[UrlRule], path: &str, query: Option<&str>) -> UrlOutcome {
+        evaluate_url_rules(rules, "example.com", path, query)
+    }
+
+    #[test]
+    fn rewrite_with_captures() {
+        let rules = [rule(
+            Some("^/old/(?P<rest>.*)$"),
+            UrlAction::Rewrite {
+                to: "/new/${rest}".to_string(),
+            },
+        )];
+        let outcome = evaluate(&rules, "/old/a/b", Some("x=1"));
+        assert_eq!(outcome.uri(), "/new/a/b?x=1");
+        assert_eq!(outcome.matched, [0]);
+        assert_eq!(
+            evaluate(&rules, "/other", None).matched,
+            Vec::<usize>::new()
+        );
+
+        // 替换后不以 `/` 开头时补上
+        let rules = [rule(
+            Some("^/api(.*)$"),
+            UrlAction::Rewrite {
+                to: "v2$1".to_string(),
+            },
+        )];
+        assert_eq!(evaluate(&rules, "/api/users", None).path, "/v2/users");
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let strip = [rule(
+            None,
+            UrlAction::StripPrefix {
+                prefix: "/app/".to_string(),
+            },
+        )];
+        assert_eq!(evaluate(&strip, "/app/x", None).path, "/x");
+        assert_eq!(evaluate(&strip, "/app", None).path, "/");
+        assert_eq!(evaluate(&strip, "/application", None).path, "/application");
+
+        let add = [rule(
+            None,
+            UrlAction::AddPrefix {
+                prefix: "/v1/".to_string(),
+            },
+        )];
+        assert_eq!(evaluate(&add, "/users", None).path, "/v1/users");
+    }
+
+    #[test]
+    fn set_and_remove_query() {
+        let set = [rule(
+            None,
+            UrlAction::SetQuery {
+                name: "q".to_string(),
+                value: "a b#c&d".to_string(),
+            },
+        )];
+        let outcome = evaluate(&set, "/search", Some("q=old&page=2"));
+        assert_eq!(outcome.query.as_deref(), Some("page=2&q=a+b%23c%26d"));
+        assert!(outcome.uri().parse::<hyper::Uri>().is_ok());
+
+        let remove = [rule(
+            None,
+            UrlAction::RemoveQuery {
+                name: "page".to_string(),
+            },
+        )];
+        assert_eq!(evaluate(&remove, "/", Some("page=2")).query, None);
+        assert_eq!(
+            evaluate(&remove, "/", Some("page=2&pages=3"))
+                .query
+                .as_deref(),
+            Some("pages=3")
+        );
+    }
+
+    #[test]
+    fn redirect_stops_evaluation() {
+        let rules = [
+            rule(
+                Some("^/docs/(.*)$"),
+                UrlAction::Redirect {
+                    location: "https://{{host}}/manual/$1".to_string(),
+                    status: 301,
+                    keep_query: true,
+                },
+            ),
+            rule(
+                None,
+                UrlAction::AddPrefix {
+                    prefix: "/unused".to_string(),
+                },
+            ),
+        ];
+        let outcome = evaluate(&rules, "/docs/intro", Some("lang=en"));
+        assert_eq!(
+            outcome.redirect,
+            Some(UrlRedirect {
+                status: 301,
+                location: "https://example.com/manual/intro?lang=en".to_string(),
+            })
+        );
+        assert_eq!(outcome.matched, [0]);
+    }
+
+    #[test]
+    fn last_stops_evaluation() {
+        let mut first = rule(
+            None,
+            UrlAction::AddPrefix {
+                prefix: "/a".to_string(),
+            },
+        );
+        first.last = true;
+        let second = rule(
+            None,
+            UrlAction::AddPrefix {
+                prefix: "/b".to_string(),
+            },
+        );
+        assert_eq!(evaluate(&[first, second], "/x", None).path, "/a/x");
+    }
+}
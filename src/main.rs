@@ -1,5 +1,14 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
 use clap::Parser;
-use http_proxy::{admin::service, lb::LB};
+use http_proxy::{
+    admin::service,
+    lb::{IpAccessFile, Policies, LB},
+    svcs::{
+        ConsulDiscovery, Discovery, FileDiscovery, Hosts, KubernetesDiscovery, KubernetesSettings,
+        ResolverSettings, KUBERNETES_PROVIDER,
+    },
+};
 use log::info;
 use pingora::{
     prelude::{background_service, Opt},
@@ -7,26 +16,91 @@ use pingora::{
     server::Server,
 };
 
+#[derive(Parser)]
+struct Args {
+    #[clap(flatten)]
+    opt: Opt,
+    #[clap(flatten)]
+    resolver: ResolverSettings,
+    #[clap(flatten)]
+    kubernetes: KubernetesSettings,
+    /// 静态解析表文件，格式同 /etc/hosts，优先于 DNS 查询
+    #[clap(long)]
+    hosts_file: Option<PathBuf>,
+    /// 基于文件的服务发现监听的文件或目录，域名通过 `"provider": "file"` 使用
+    #[clap(long)]
+    discovery_file: Option<PathBuf>,
+    /// Consul HTTP 地址，例如 http://127.0.0.1:8500，域名通过 `"provider": "consul"` 使用
+    #[clap(long)]
+    consul_addr: Option<String>,
+    /// Consul ACL token
+    #[clap(long)]
+    consul_token: Option<String>,
+    /// Consul 数据中心，为空时使用 agent 所在的数据中心
+    #[clap(long)]
+    consul_dc: Option<String>,
+    /// 地址访问控制列表文件，JSON 或 YAML 格式，文件变化时自动重新加载
+    #[clap(long)]
+    ip_access_file: Option<PathBuf>,
+}
+
 fn main() {
     env_logger::init();
 
-    let opt = Opt::parse();
-    let mut my_server = Server::new(Some(opt)).unwrap();
+    let args = Args::parse();
+    let mut my_server = Server::new(Some(args.opt)).unwrap();
     my_server.bootstrap();
 
-    let (mut admin_svc, resolver) = service().unwrap();
+    let hosts = match &args.hosts_file {
+        Some(path) => Hosts::load(path).unwrap(),
+        None => Hosts::default(),
+    };
+    let mut providers: HashMap<String, Arc<dyn Discovery>> = HashMap::new();
+    if let Some(path) = &args.discovery_file {
+        providers.insert("file".to_string(), Arc::new(FileDiscovery::new(path)));
+    }
+    if let Some(addr) = &args.consul_addr {
+        let consul = ConsulDiscovery::new(addr, args.consul_token, args.consul_dc).unwrap();
+        providers.insert("consul".to_string(), Arc::new(consul));
+    }
+    let kubernetes = args
+        .kubernetes
+        .enabled()
+        .then(|| Arc::new(KubernetesDiscovery::new(&args.kubernetes).unwrap()));
+    if let Some(kubernetes) = &kubernetes {
+        providers.insert(KUBERNETES_PROVIDER.to_string(), kubernetes.clone());
+    }
+    let policies = Policies::default();
+    let (mut admin_svc, registry) =
+        service(args.resolver, hosts, providers, policies.clone()).unwrap();
     info!("add admin http service service at 0.0.0.0:6100");
     admin_svc.add_tcp("0.0.0.0:6100");
     my_server.add_service(admin_svc);
 
-    let backgrounds = resolver.backgrounds();
-    let mut lb = http_proxy_service(&my_server.configuration, LB { backgrounds });
+    let lb = LB {
+        backgrounds: registry.backgrounds(),
+        domains: registry.domains(),
+        policies: policies.clone(),
+    };
+    let mut lb = http_proxy_service(&my_server.configuration, lb);
     info!("add http proxy service at 0.0.0.0:6188");
     lb.add_tcp("0.0.0.0:6188");
     my_server.add_service(lb);
 
-    let resolver_bg_svc = background_service("resolver", resolver);
-    my_server.add_service(resolver_bg_svc);
+    if let Some(kubernetes) = kubernetes.filter(|_| args.kubernetes.ingress) {
+        let ingress = kubernetes.ingress(&args.kubernetes, registry.sender());
+        info!("add kubernetes ingress service");
+        my_server.add_service(background_service("kubernetes ingress", ingress));
+    }
+
+    if let Some(path) = &args.ip_access_file {
+        let file = IpAccessFile::new(path, policies.ip_access.clone());
+        info!("add ip access file service");
+        my_server.add_service(background_service("ip access file", file));
+    }
+
+    let registry_bg_svc = background_service("registry", registry);
+    my_server.add_service(registry_bg_svc);
 
     info!("start server");
     my_server.run_forever();
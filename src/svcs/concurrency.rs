@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// 域名同时处理的请求数的限制，超过限制的请求排队等待，队列已满或等待超时时返回 503
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct ConcurrencyLimit {
+    /// 同时处理的最大请求数，自适应模式下为初始值
+    pub max_in_flight: u32,
+    /// 等待队列的长度，为 0 时超过限制直接拒绝
+    #[serde(default)]
+    pub queue: u32,
+    /// 排队的最长时间，单位毫秒
+    #[serde(default = "default_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+    /// 根据后端的延迟调整限制，为空时限制固定
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<AdaptiveLimit>,
+}
+
+fn default_queue_timeout_ms() -> u64 {
+    1000
+}
+
+impl ConcurrencyLimit {
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_millis(self.queue_timeout_ms)
+    }
+}
+
+/// 自适应限制的范围及算法
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct AdaptiveLimit {
+    #[serde(default)]
+    pub algorithm: AdaptiveAlgorithm,
+    #[serde(default = "default_min_limit")]
+    pub min_limit: u32,
+    pub max_limit: u32,
+    /// AIMD 中视为过载的延迟，单位毫秒，为空时只有失败视为过载
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// AIMD 过载时限制乘以的系数
+    #[serde(default = "default_backoff")]
+    pub backoff: f64,
+}
+
+fn default_min_limit() -> u32 {
+    1
+}
+
+fn default_backoff() -> f64 {
+    0.9
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdaptiveAlgorithm {
+    /// 成功时加性增大，过载时乘性减小
+    #[default]
+    Aimd,
+    /// 按最小延迟与当前延迟的比例调整
+    Gradient,
+}
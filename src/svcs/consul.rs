@@ -0,0 +1,359 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::warn;
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::{net::lookup_host, time::sleep};
+
+use super::{Discovery, DomainConfig, Endpoint, EndpointStream, Error};
+
+/// 阻塞查询的最长等待时间，由 Consul 在没有变化时返回
+const BLOCKING_WAIT: &str = "5m";
+/// 请求超时时间，需要大于阻塞查询的等待时间
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(330);
+/// 查询失败后的重试间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// 两次查询的最小间隔，Consul 没有阻塞立即返回时避免频繁查询
+const MIN_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Consul 服务发现
+/// 通过健康检查接口 `/v1/health/service/<service>?passing` 查询通过健康检查的实例，
+/// 使用阻塞查询（`index` 参数）等待实例变化
+/// 域名通过 `service` 指定服务名称，为空时使用域名
+pub struct ConsulDiscovery {
+    client: reqwest::Client,
+    /// Consul HTTP 地址，例如 `http://127.0.0.1:8500`
+    address: Url,
+    token: Option<String>,
+    datacenter: Option<String>,
+}
+
+impl ConsulDiscovery {
+    pub fn new(
+        address: impl Into<String>,
+        token: Option<String>,
+        datacenter: Option<String>,
+    ) -> Result<Self, Error> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| Error::Discovery(format!("Build consul client failed: {e}")))?;
+        let address = address.into();
+        let address = Url::parse(&address)
+            .ok()
+            .filter(|url| !url.cannot_be_a_base())
+            .ok_or_else(|| Error::Discovery(format!("Invalid consul address {address}")))?;
+        Ok(Self {
+            client,
+            address,
+            token,
+            datacenter,
+        })
+    }
+
+    /// 查询服务的健康实例，`index` 不为空时为阻塞查询
+    /// 返回实例对应的后端及 `X-Consul-Index`
+    async fn query(
+        &self,
+        service: &str,
+        index: Option<u64>,
+    ) -> Result<(Vec<Endpoint>, u64), Error> {
+        // 服务名称作为路径的一段，需要编码
+        let mut url = self.address.clone();
+        url.path_segments_mut()
+            .expect("consul address can be a base")
+            .pop_if_empty()
+            .extend(["v1", "health", "service", service]);
+        let mut request = self.client.get(url).query(&[("passing", "true")]);
+        if let Some(dc) = &self.datacenter {
+            request = request.query(&[("dc", dc)]);
+        }
+        if let Some(index) = index {
+            request = request.query(&[
+                ("index", index.to_string().as_str()),
+                ("wait", BLOCKING_WAIT),
+            ]);
+        }
+        if let Some(token) = &self.token {
+            request = request.header("X-Consul-Token", token);
+        }
+
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| Error::Discovery(format!("Query consul service {service} failed: {e}")))?;
+        let index = response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default();
+        let entries = response
+            .json::<Vec<ServiceEntry>>()
+            .await
+            .map_err(|e| Error::Discovery(format!("Parse consul service {service} failed: {e}")))?;
+
+        let mut endpoints = Vec::new();
+        for entry in entries {
+            match entry.endpoint().await {
+                Ok(endpoint) => endpoints.push(endpoint),
+                Err(e) => warn!("ConsulDiscovery skip instance of {service}: {e}"),
+            }
+        }
+        Ok((endpoints, index))
+    }
+}
+
+fn service_of(config: &DomainConfig) -> &str {
+    config.service.as_deref().unwrap_or(&config.domain)
+}
+
+#[async_trait]
+impl Discovery for ConsulDiscovery {
+    async fn discover(&self, config: &DomainConfig) -> Result<Vec<Endpoint>, Error> {
+        let (endpoints, _) = self.query(service_of(config), None).await?;
+        Ok(endpoints)
+    }
+
+    /// 阻塞查询等待实例变化，索引未变化时继续等待
+    fn watch(self: Arc<Self>, config: DomainConfig) -> EndpointStream {
+        // 上一次查询的索引，尚未查询或需要重新开始时为空
+        let state = (self, config, None::<u64>);
+        futures::stream::unfold(state, |(this, config, index)| async move {
+            loop {
+                let start = Instant::now();
+                match this.query(service_of(&config), index).await {
+                    // 索引应大于 0，见 Consul 阻塞查询文档
+                    Ok((_, new_index)) if index == Some(new_index.max(1)) => {
+                        // 索引缺失或为 0 时 Consul 不会阻塞，限制查询频率
+                        if let Some(rest) = MIN_QUERY_INTERVAL.checked_sub(start.elapsed()) {
+                            sleep(rest).await;
+                        }
+                    }
+                    Ok((endpoints, new_index)) => {
+                        // 索引变小时需要重新开始
+                        let new_index = match index {
+                            Some(index) if new_index < index => None,
+                            _ => Some(new_index.max(1)),
+                        };
+                        return Some((Ok(endpoints), (this, config, new_index)));
+                    }
+                    Err(e) => {
+                        sleep(RETRY_INTERVAL).await;
+                        return Some((Err(e), (this, config, index)));
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+/// `/v1/health/service` 返回的实例，只解析需要的字段
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    node: Node,
+    service: Service,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    weights: Option<Weights>,
+    #[serde(default)]
+    meta: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Weights {
+    passing: usize,
+}
+
+impl ServiceEntry {
+    /// 服务地址为空时使用节点地址，地址为主机名时解析为 IP
+    async fn endpoint(self) -> Result<Endpoint, Error> {
+        let host = if self.service.address.is_empty() {
+            self.node.address
+        } else {
+            self.service.address
+        };
+        let port = self.service.port;
+        let addr = match host.parse() {
+            Ok(ip) => SocketAddr::new(ip, port),
+            Err(_) => lookup_host((host.as_str(), port))
+                .await
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .ok_or_else(|| Error::Discovery(format!("Resolve {host} failed")))?,
+        };
+        Ok(Endpoint {
+            weight: self.service.weights.map_or(1, |w| w.passing),
+            metadata: self.service.meta.unwrap_or_default(),
+            ..addr.into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{
+        extract::{Path, Query, State},
+        http::{HeaderMap, HeaderValue, StatusCode},
+        routing::get,
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+    use tokio::{net::TcpListener, sync::watch, time::timeout};
+
+    use super::*;
+
+    /// 只实现 `/v1/health/service/{service}` 的 Consul
+    struct StubConsul {
+        /// 当前的索引及实例
+        catalog: watch::Sender<(u64, Value)>,
+        /// 为 false 时不返回 `X-Consul-Index`，Consul 不会阻塞
+        send_index: bool,
+        requests: AtomicUsize,
+    }
+
+    async fn health_service(
+        State(stub): State<Arc<StubConsul>>,
+        Path(service): Path<String>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Result<(HeaderMap, Json<Value>), StatusCode> {
+        stub.requests.fetch_add(1, Ordering::SeqCst);
+        if service != "web api" || params.get("passing").is_none() {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let mut catalog = stub.catalog.subscribe();
+        let index = params
+            .get("index")
+            .and_then(|index| index.parse::<u64>().ok());
+        if let (Some(index), Some(_)) = (index, params.get("wait")) {
+            let changed = catalog.wait_for(|(current, _)| *current > index);
+            let _ = timeout(Duration::from_secs(5), changed).await;
+        }
+        let (index, entries) = catalog.borrow().clone();
+        let mut headers = HeaderMap::new();
+        if stub.send_index {
+            headers.insert("x-consul-index", HeaderValue::from(index));
+        }
+        Ok((headers, Json(entries)))
+    }
+
+    fn entries(ports: &[u16]) -> Value {
+        ports
+            .iter()
+            .map(|port| {
+                json!({
+                    "Node": { "Address": "127.0.0.1" },
+                    "Service": {
+                        "Address": "",
+                        "Port": port,
+                        "Weights": { "Passing": 2, "Warning": 1 },
+                        "Meta": { "zone": "a" },
+                    },
+                })
+            })
+            .collect()
+    }
+
+    async fn start(send_index: bool, ports: &[u16]) -> (Arc<StubConsul>, Arc<ConsulDiscovery>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (catalog, _) = watch::channel((7, entries(ports)));
+        let stub = Arc::new(StubConsul {
+            catalog,
+            send_index,
+            requests: AtomicUsize::new(0),
+        });
+        let app = Router::new()
+            .route("/v1/health/service/{service}", get(health_service))
+            .with_state(stub.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let consul = ConsulDiscovery::new(format!("http://{addr}/"), None, None).unwrap();
+        (stub, Arc::new(consul))
+    }
+
+    fn config() -> DomainConfig {
+        DomainConfig {
+            domain: "web.example.com".to_string(),
+            service: Some("web api".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn ports(endpoints: &[Endpoint]) -> Vec<u16> {
+        endpoints.iter().map(|e| e.addr.port()).collect()
+    }
+
+    #[tokio::test]
+    async fn discover_maps_passing_instances() {
+        let (_, consul) = start(true, &[8080, 8081]).await;
+        let endpoints = consul.discover(&config()).await.unwrap();
+        assert_eq!(ports(&endpoints), [8080, 8081]);
+        assert_eq!(endpoints[0].addr.ip().to_string(), "127.0.0.1");
+        assert_eq!(endpoints[0].weight, 2);
+        assert_eq!(endpoints[0].metadata["zone"], "a");
+    }
+
+    #[tokio::test]
+    async fn watch_follows_index_changes() {
+        let (stub, consul) = start(true, &[8080]).await;
+        let mut stream = consul.watch(config());
+        assert_eq!(ports(&stream.next().await.unwrap().unwrap()), [8080]);
+
+        stub.catalog.send_modify(|(index, catalog)| {
+            *index += 1;
+            *catalog = entries(&[8081, 8082]);
+        });
+        let endpoints = timeout(Duration::from_secs(3), stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(ports(&endpoints), [8081, 8082]);
+    }
+
+    #[tokio::test]
+    async fn watch_without_index_does_not_spin() {
+        let (stub, consul) = start(false, &[8080]).await;
+        let mut stream = consul.watch(config());
+        assert_eq!(ports(&stream.next().await.unwrap().unwrap()), [8080]);
+
+        // 没有变化时不产生新的后端集合，并限制查询频率
+        let next = timeout(Duration::from_millis(1500), stream.next()).await;
+        assert!(next.is_err());
+        assert!(stub.requests.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn rejects_invalid_address() {
+        assert!(ConsulDiscovery::new("127.0.0.1:8500", None, None).is_err());
+    }
+}
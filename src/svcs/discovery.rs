@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+
+use super::{DomainConfig, Endpoint, Error};
+
+/// 后端集合的变化，每一项都是完整的后端集合
+pub type EndpointStream = BoxStream<'static, Result<Vec<Endpoint>, Error>>;
+
+/// 服务发现
+/// 为域名提供后端集合及其变化，域名通过 `provider` 指定使用的服务发现
+#[async_trait]
+pub trait Discovery: Send + Sync + 'static {
+    /// 发现域名当前的后端集合
+    async fn discover(&self, config: &DomainConfig) -> Result<Vec<Endpoint>, Error>;
+
+    /// 域名后端集合的变化，第一项为当前的后端集合
+    /// 默认只发现一次，后端集合会变化的实现需要覆盖
+    fn watch(self: Arc<Self>, config: DomainConfig) -> EndpointStream {
+        futures::stream::once(async move { self.discover(&config).await }).boxed()
+    }
+
+    /// 通知实现尽快重新发现，例如静态解析表变更后
+    fn refresh(&self) {}
+
+    /// 最近的错误，例如配置文件解析失败，通过管理 API 展示
+    fn errors(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// 静态后端列表，直接使用域名配置中的 `backends`
+pub struct StaticDiscovery;
+
+#[async_trait]
+impl Discovery for StaticDiscovery {
+    async fn discover(&self, config: &DomainConfig) -> Result<Vec<Endpoint>, Error> {
+        if config.backends.is_empty() {
+            return Err(Error::Discovery(format!(
+                "Domain {} has no static backends",
+                config.domain
+            )));
+        }
+        Ok(config.backends.clone())
+    }
+}
@@ -0,0 +1,142 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, Context};
+use clap::{ArgEnum, Args};
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    system_conf, TokioAsyncResolver,
+};
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+
+/// DNS 查询使用的协议
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ArgEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsProtocol {
+    #[default]
+    Udp,
+    Tcp,
+    /// DNS over TLS
+    Tls,
+    /// DNS over HTTPS
+    Https,
+}
+
+impl From<DnsProtocol> for Protocol {
+    fn from(protocol: DnsProtocol) -> Self {
+        match protocol {
+            DnsProtocol::Udp => Protocol::Udp,
+            DnsProtocol::Tcp => Protocol::Tcp,
+            DnsProtocol::Tls => Protocol::Tls,
+            DnsProtocol::Https => Protocol::Https,
+        }
+    }
+}
+
+/// 查询 A/AAAA 记录的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpStrategy {
+    Ipv4Only,
+    Ipv6Only,
+    Ipv4AndIpv6,
+    Ipv6ThenIpv4,
+    Ipv4ThenIpv6,
+}
+
+impl From<IpStrategy> for LookupIpStrategy {
+    fn from(strategy: IpStrategy) -> Self {
+        match strategy {
+            IpStrategy::Ipv4Only => LookupIpStrategy::Ipv4Only,
+            IpStrategy::Ipv6Only => LookupIpStrategy::Ipv6Only,
+            IpStrategy::Ipv4AndIpv6 => LookupIpStrategy::Ipv4AndIpv6,
+            IpStrategy::Ipv6ThenIpv4 => LookupIpStrategy::Ipv6thenIpv4,
+            IpStrategy::Ipv4ThenIpv6 => LookupIpStrategy::Ipv4thenIpv6,
+        }
+    }
+}
+
+/// DNS 解析器配置
+/// 命令行参数作为默认配置，管理 API 可以添加命名的配置供域名选择
+/// 未设置的项沿用 /etc/resolv.conf 中的配置
+#[derive(Debug, Clone, Default, Args, Deserialize, Serialize)]
+pub struct ResolverSettings {
+    /// DNS 服务器地址，例如 1.1.1.1:53，可重复指定，为空时使用 /etc/resolv.conf
+    #[clap(long = "dns-nameserver")]
+    #[serde(default)]
+    pub nameservers: Vec<SocketAddr>,
+    /// 与 DNS 服务器通信的协议
+    #[clap(long = "dns-protocol", arg_enum, default_value = "udp")]
+    #[serde(default)]
+    pub protocol: DnsProtocol,
+    /// DNS 服务器的 TLS 证书名称，使用 tls/https 协议时必须设置
+    #[clap(long = "dns-tls-name")]
+    pub tls_name: Option<String>,
+    /// 单次查询超时时间，单位秒
+    #[clap(long = "dns-timeout")]
+    pub timeout: Option<u64>,
+    /// 查询失败后的重试次数
+    #[clap(long = "dns-attempts")]
+    pub attempts: Option<usize>,
+    /// 缓存的记录数量
+    #[clap(long = "dns-cache-size")]
+    pub cache_size: Option<usize>,
+    /// IPv4/IPv6 查询策略
+    #[clap(long = "dns-ip-strategy", arg_enum)]
+    pub ip_strategy: Option<IpStrategy>,
+}
+
+impl ResolverSettings {
+    pub fn build(&self) -> Result<TokioAsyncResolver, Error> {
+        let (config, mut options) = if self.nameservers.is_empty() {
+            system_conf::read_system_conf().context("DNS Resolver read system config failed")?
+        } else {
+            if matches!(self.protocol, DnsProtocol::Tls | DnsProtocol::Https)
+                && self.tls_name.is_none()
+            {
+                return Err(anyhow!("DNS protocol {:?} requires a TLS name", self.protocol).into());
+            }
+            let mut config = ResolverConfig::new();
+            for socket_addr in &self.nameservers {
+                let mut name_server = NameServerConfig::new(*socket_addr, self.protocol.into());
+                name_server.tls_dns_name = self.tls_name.clone();
+                config.add_name_server(name_server);
+            }
+            let options = system_conf::read_system_conf()
+                .map(|(_, options)| options)
+                .unwrap_or_default();
+            (config, options)
+        };
+
+        if let Some(timeout) = self.timeout {
+            options.timeout = Duration::from_secs(timeout);
+        }
+        if let Some(attempts) = self.attempts {
+            options.attempts = attempts;
+        }
+        if let Some(cache_size) = self.cache_size {
+            options.cache_size = cache_size;
+        }
+        if let Some(ip_strategy) = self.ip_strategy {
+            options.ip_strategy = ip_strategy.into();
+        }
+        Ok(TokioAsyncResolver::tokio(config, options))
+    }
+}
+
+/// 命名的 DNS 解析器配置
+#[derive(Clone)]
+pub struct ResolverProfile {
+    pub settings: ResolverSettings,
+    pub resolver: TokioAsyncResolver,
+}
+
+impl TryFrom<ResolverSettings> for ResolverProfile {
+    type Error = Error;
+
+    fn try_from(settings: ResolverSettings) -> Result<Self, Self::Error> {
+        let resolver = settings.build()?;
+        Ok(Self { settings, resolver })
+    }
+}
@@ -1,126 +1,192 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
-use hickory_resolver::{
-    config::{ResolverConfig, ResolverOpts},
-    system_conf, TokioAsyncResolver,
+use futures::StreamExt;
+use hickory_resolver::TokioAsyncResolver;
+use log::warn;
+use tokio::{
+    sync::{broadcast, RwLock},
+    time::{sleep_until, Instant as TokioInstant},
+};
+
+use super::{
+    Discovery, DomainConfig, Endpoint, EndpointStream, Error, Hosts, ResolverProfile,
+    ResolverSettings,
 };
-use log::info;
-use pingora::{server::ShutdownWatch, services::background::BackgroundService};
-use pingora_runtime::current_handle;
-use tokio::sync::{broadcast, RwLock};
 
-use super::{Error, Op, UpstreamsHealthCheck};
+/// 按 TTL 重新解析的最短间隔，避免 TTL 为 0 时频繁解析
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+/// 按 TTL 重新解析的最长间隔
+const MAX_REFRESH: Duration = Duration::from_secs(3600);
+/// 解析失败后的重试间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 /// DNS 解析器
-/// 用于解析域名并将解析结果转换为 LoadBalancer
+/// 基于 DNS 的服务发现，解析域名得到后端地址，并按解析结果的 TTL 定期重新解析
 pub struct DNSResolver {
     resolver: TokioAsyncResolver,
-    add_domain_queen: broadcast::Receiver<Op>,
-    waitings_sender: broadcast::Sender<(String, Vec<SocketAddr>)>,
-    waitings_receiver: broadcast::Receiver<(String, Vec<SocketAddr>)>,
-    backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+    profiles: Arc<RwLock<HashMap<String, ResolverProfile>>>,
+    hosts: Arc<RwLock<Hosts>>,
+    /// 通知所有域名立即重新解析
+    refresh_sender: broadcast::Sender<()>,
 }
 
 impl DNSResolver {
     pub fn new(
-        config: Option<ResolverConfig>,
-        options: Option<ResolverOpts>,
-        add_domain_queen: broadcast::Receiver<Op>,
-        backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+        settings: &ResolverSettings,
+        profiles: Arc<RwLock<HashMap<String, ResolverProfile>>>,
+        hosts: Arc<RwLock<Hosts>>,
     ) -> Result<Self, Error> {
-        let (sys_config, sys_options) =
-            system_conf::read_system_conf().context("DNS Resolver read system config failed")?;
-
-        let (config, options) = (config.unwrap_or(sys_config), options.unwrap_or(sys_options));
-        let resolver = TokioAsyncResolver::tokio(config, options);
-        // 解析后的生成的 UpstreamsHealthCheck 服务队列
-        let (waitings_sender, waitings_receiver) = broadcast::channel(5);
+        let resolver = settings.build()?;
+        let (refresh_sender, _) = broadcast::channel(1);
         Ok(Self {
             resolver,
-            add_domain_queen,
-            waitings_sender,
-            waitings_receiver,
-            backgrounds,
+            profiles,
+            hosts,
+            refresh_sender,
         })
     }
 
-    /// 添加一个域名
-    /// 会将域名解析为 IP 地址，并创建一个 UpstreamsHealthCheck 服务 提供默认的健康检查
-    /// 并发送到 waitings 通道中，等待后台服务启动
-    async fn add(&self, domain: &str) -> Result<(), Error> {
-        info!("DNSResolver::add {domain}");
-        let socket_addr = self
-            .resolver
-            .lookup_ip(domain)
-            .await
-            .context("Resolve domain {} failed")?
-            .iter()
-            .map(|ip| SocketAddr::new(ip, 443))
-            .collect::<Vec<_>>();
-
-        self.waitings_sender
-            .send((domain.to_owned(), socket_addr))
-            .context("Send to start background service failed")?;
+    /// 解析域名得到后端地址及结果的有效期
+    async fn resolve(&self, config: &DomainConfig) -> Result<(Vec<Endpoint>, Instant), Error> {
+        let resolver = self.resolver_for(config).await?;
+        match &config.srv {
+            Some(srv) => self.resolve_srv(&resolver, srv).await,
+            None => {
+                let (ips, from_hosts, valid_until) =
+                    self.lookup_ip(&resolver, &config.domain).await?;
+                let endpoints = ips
+                    .into_iter()
+                    .map(|ip| Endpoint {
+                        from_hosts,
+                        ..SocketAddr::new(ip, 443).into()
+                    })
+                    .collect();
+                Ok((endpoints, valid_until))
+            }
+        }
+    }
 
-        Ok(())
+    /// 解析 SRV 记录，使用每条记录的端口、优先级和权重
+    /// 有效期取 SRV 记录及各目标地址记录中最早过期的
+    /// 解析失败的目标被跳过并在重试间隔后重新解析，所有目标都解析失败时返回错误
+    async fn resolve_srv(
+        &self,
+        resolver: &TokioAsyncResolver,
+        name: &str,
+    ) -> Result<(Vec<Endpoint>, Instant), Error> {
+        let lookup = resolver
+            .srv_lookup(name)
+            .await
+            .with_context(|| format!("Resolve SRV {name} failed"))?;
+        let mut valid_until = lookup.as_lookup().valid_until();
+        let mut endpoints = Vec::new();
+        let mut failure = None;
+        for srv in lookup.iter() {
+            // 目标为 "." 表示该服务不可用
+            if srv.target().is_root() {
+                continue;
+            }
+            let target = srv.target().to_utf8();
+            let (ips, from_hosts, ips_valid_until) = match self.lookup_ip(resolver, &target).await {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("Resolve SRV {name} target {target} failed: {e}");
+                    valid_until = valid_until.min(Instant::now() + RETRY_INTERVAL);
+                    failure = Some(e);
+                    continue;
+                }
+            };
+            valid_until = valid_until.min(ips_valid_until);
+            endpoints.extend(ips.into_iter().map(|ip| Endpoint {
+                weight: srv.weight() as usize,
+                priority: srv.priority(),
+                from_hosts,
+                ..SocketAddr::new(ip, srv.port()).into()
+            }));
+        }
+        match failure {
+            Some(e) if endpoints.is_empty() => Err(e),
+            _ => Ok((endpoints, valid_until)),
+        }
     }
 
-    async fn remove(&self, domain: &str) {
-        if let Some(background) = self.backgrounds.write().await.remove(domain) {
-            background.stop();
+    /// 解析域名的 IP 地址，静态解析表优先于 DNS 查询
+    /// 返回的 bool 表示结果是否来自静态解析表
+    async fn lookup_ip(
+        &self,
+        resolver: &TokioAsyncResolver,
+        name: &str,
+    ) -> Result<(Vec<IpAddr>, bool, Instant), Error> {
+        if let Some(entry) = self.hosts.read().await.get(name) {
+            let valid_until = entry
+                .valid_until()
+                .unwrap_or_else(|| Instant::now() + MAX_REFRESH);
+            return Ok((entry.ips.clone(), true, valid_until));
         }
+        let lookup = resolver
+            .lookup_ip(name)
+            .await
+            .with_context(|| format!("Resolve domain {name} failed"))?;
+        Ok((lookup.iter().collect(), false, lookup.valid_until()))
     }
 
-    pub fn backgrounds(&self) -> Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>> {
-        self.backgrounds.clone()
+    /// 域名指定的命名解析配置，未指定时使用默认配置
+    async fn resolver_for(&self, config: &DomainConfig) -> Result<TokioAsyncResolver, Error> {
+        match &config.resolver {
+            None => Ok(self.resolver.clone()),
+            Some(name) => self
+                .profiles
+                .read()
+                .await
+                .get(name)
+                .map(|profile| profile.resolver.clone())
+                .ok_or_else(|| anyhow!("Resolver profile {name} not found").into()),
+        }
     }
 }
 
 #[async_trait]
-impl BackgroundService for DNSResolver {
-    async fn start(&self, mut shutdown: ShutdownWatch) {
-        // let mut period = interval(Duration::from_secs(1));
-        let mut receiver = self.waitings_receiver.resubscribe();
-        let mut add_domain_queen = self.add_domain_queen.resubscribe();
-        loop {
-            tokio::select! {
-                _ = shutdown.changed() => {
-                    println!("DNSResolver Shutdown.");
-                    let backgrounds = self.backgrounds.write().await;
-                    for (_, background) in backgrounds.iter() {
-                        background.stop();
-                    }
-                    break;
-                }
-                data = receiver.recv() => {
-                    info!("BackgroundService/DNSResolver::start Received new domain task {data:?}.");
-                    if let Ok((domain, socket_addr)) = data {
-                        let background = Arc::new(UpstreamsHealthCheck::from(socket_addr));
-                        let background_clone = background.clone();
-                        let shutdown = shutdown.clone();
-                        current_handle().spawn(async move  {
-                            background_clone.start(shutdown.clone()).await;
-                        });
-                        self.backgrounds.write().await.insert(domain.clone(), background);
-                        println!("Received new domain task {domain}.");
-                    }
-                }
-                op = add_domain_queen.recv() => {
-                    if let Ok(op) = op {
-                        match op {
-                            Op::Add(domain) => {
-                                self.add(&domain).await.unwrap();
-                            }
-                            Op::Del(domain) => {
-                                self.remove(&domain).await;
-                            }
-                        }
-                    }
+impl Discovery for DNSResolver {
+    async fn discover(&self, config: &DomainConfig) -> Result<Vec<Endpoint>, Error> {
+        let (endpoints, _) = self.resolve(config).await?;
+        Ok(endpoints)
+    }
+
+    /// 按解析结果的 TTL 重新解析，解析失败时按固定间隔重试
+    fn watch(self: Arc<Self>, config: DomainConfig) -> EndpointStream {
+        let refresh = self.refresh_sender.subscribe();
+        let state = (self, config, refresh, None::<Instant>);
+        futures::stream::unfold(state, |(this, config, mut refresh, next)| async move {
+            if let Some(next) = next {
+                tokio::select! {
+                    _ = sleep_until(TokioInstant::from_std(next)) => {}
+                    _ = refresh.recv() => {}
                 }
-                // _ = period.tick() => {}
             }
-        }
+            let (result, next) = match this.resolve(&config).await {
+                Ok((endpoints, valid_until)) => (Ok(endpoints), refresh_at(valid_until)),
+                Err(e) => (Err(e), Instant::now() + RETRY_INTERVAL),
+            };
+            Some((result, (this, config, refresh, Some(next))))
+        })
+        .boxed()
+    }
+
+    fn refresh(&self) {
+        let _ = self.refresh_sender.send(());
     }
 }
+
+fn refresh_at(valid_until: Instant) -> Instant {
+    let now = Instant::now();
+    now + valid_until
+        .saturating_duration_since(now)
+        .clamp(MIN_REFRESH, MAX_REFRESH)
+}
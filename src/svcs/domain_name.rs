@@ -0,0 +1,185 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use super::Error;
+
+/// 域名的最大长度，不含末尾的点
+const MAX_DOMAIN_LEN: usize = 253;
+/// 域名中每一级的最大长度
+const MAX_LABEL_LEN: usize = 63;
+
+/// 请求中的 Host，`host` 已规范化，可直接用于查找域名
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authority {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+/// 格式化为 authority，IPv6 地址加上方括号
+impl fmt::Display for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]", self.host)?;
+        } else {
+            f.write_str(&self.host)?;
+        }
+        match self.port {
+            Some(port) => write!(f, ":{port}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// 解析 Host 请求头或请求地址中的 authority
+/// 域名转换为小写的 punycode 并去掉末尾的点，IPv6 地址去掉方括号并使用标准的写法
+pub fn parse_authority(input: &str) -> Result<Authority, Error> {
+    let invalid = || Error::InvalidHost(input.to_string());
+    if input.contains('@') {
+        return Err(invalid());
+    }
+    let (host, port) = if let Some(rest) = input.strip_prefix('[') {
+        let (ip, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        let ip = ip.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+        let port = match rest {
+            "" => None,
+            _ => Some(rest.strip_prefix(':').ok_or_else(invalid)?),
+        };
+        (ip.to_string(), port)
+    } else {
+        let (host, port) = match input.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (input, None),
+        };
+        (normalize_name(host).ok_or_else(invalid)?, port)
+    };
+    let port = port
+        .map(|port| {
+            // 端口只允许数字，`u16::from_str` 会接受 `+` 前缀
+            if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            port.parse::<u16>().map_err(|_| invalid())
+        })
+        .transpose()?;
+    Ok(Authority { host, port })
+}
+
+/// 规范化通过管理 API 或服务发现添加的域名，与请求的 Host 使用相同的规则
+/// 域名按 Host 匹配时不区分端口，因此不允许带端口
+pub fn normalize_domain(domain: &str) -> Result<String, Error> {
+    if let Ok(ip) = domain.parse::<Ipv6Addr>() {
+        return Ok(ip.to_string());
+    }
+    let authority = parse_authority(domain)?;
+    if authority.port.is_some() {
+        return Err(Error::InvalidHost(domain.to_string()));
+    }
+    Ok(authority.host)
+}
+
+/// 规范化域名或 IPv4 地址，不合法时返回空
+fn normalize_name(name: &str) -> Option<String> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        return None;
+    }
+    // 转换为小写、Unicode 兼容等价的映射及 punycode 编码
+    let ascii = idna::domain_to_ascii(name).ok()?;
+    if let Ok(IpAddr::V4(ip)) = ascii.parse::<IpAddr>() {
+        return Some(ip.to_string());
+    }
+    if ascii.is_empty() || ascii.len() > MAX_DOMAIN_LEN {
+        return None;
+    }
+    let valid = ascii.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LEN
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    });
+    valid.then_some(ascii)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authority(host: &str, port: Option<u16>) -> Authority {
+        Authority {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parse_names() {
+        assert_eq!(
+            parse_authority("Example.COM.").unwrap(),
+            authority("example.com", None)
+        );
+        assert_eq!(
+            parse_authority("example.com:8080").unwrap(),
+            authority("example.com", Some(8080))
+        );
+        assert_eq!(
+            parse_authority("Bücher.example").unwrap(),
+            authority("xn--bcher-kva.example", None)
+        );
+        assert_eq!(
+            parse_authority("127.0.0.1:80").unwrap(),
+            authority("127.0.0.1", Some(80))
+        );
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        let parsed = parse_authority("[0:0::0001]:443").unwrap();
+        assert_eq!(parsed, authority("::1", Some(443)));
+        assert_eq!(parsed.to_string(), "[::1]:443");
+        assert_eq!(parse_authority("[::1]").unwrap(), authority("::1", None));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let long_label = format!("{}.com", "a".repeat(MAX_LABEL_LEN + 1));
+        for input in [
+            "",
+            ".",
+            "user@example.com",
+            "example.com:",
+            "example.com:+80",
+            "example.com:65536",
+            "example.com:80:80",
+            "[::1",
+            "[::1]x",
+            "[::1]:",
+            "[example.com]",
+            "-example.com",
+            "example-.com",
+            "a..com",
+            "exa mple.com",
+            "example.com/path",
+            long_label.as_str(),
+        ] {
+            assert!(
+                matches!(parse_authority(input), Err(Error::InvalidHost(_))),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_domains() {
+        assert_eq!(
+            normalize_domain("WWW.Example.com.").unwrap(),
+            "www.example.com"
+        );
+        assert_eq!(normalize_domain("::0001").unwrap(), "::1");
+        assert!(normalize_domain("example.com:80").is_err());
+    }
+}
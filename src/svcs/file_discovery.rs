@@ -0,0 +1,307 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Once, RwLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::{error, info, warn};
+use notify::{PollWatcher, RecursiveMode, Watcher};
+use pingora_runtime::current_handle;
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::spawn_blocking,
+    time::sleep,
+};
+
+use super::{normalize_domain, Discovery, DomainConfig, Endpoint, EndpointStream, Error};
+
+/// 不支持 inotify 等文件事件时，轮询文件变化的间隔
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// 合并短时间内连续的文件事件，例如编辑器先写临时文件再重命名
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// 域名到后端列表的映射
+type Inventory = HashMap<String, Vec<Endpoint>>;
+
+/// 基于文件的服务发现
+/// 监听一个文件或目录（目录下的 .json/.yaml/.yml 文件），文件内容为域名到后端列表的映射，例如
+/// `{"www.example.com": [{"addr": "10.0.0.1:443", "weight": 2, "metadata": {"zone": "a"}}]}`
+/// 优先使用 inotify 等文件事件，不可用时退化为轮询
+/// 文件解析失败时保留该文件上一次成功加载的内容，错误可通过管理 API 查询
+/// 文件中没有的域名的后端集合为空
+pub struct FileDiscovery {
+    path: PathBuf,
+    /// 每个文件上一次成功加载的内容
+    files: RwLock<HashMap<PathBuf, Inventory>>,
+    /// 合并所有文件后的结果，整体替换以保证一致
+    inventory: RwLock<Arc<Inventory>>,
+    /// 每个文件最近一次加载的错误
+    errors: RwLock<HashMap<PathBuf, String>>,
+    /// 重新加载后通知订阅者
+    changes: broadcast::Sender<()>,
+    started: Once,
+}
+
+impl FileDiscovery {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let (changes, _) = broadcast::channel(1);
+        let discovery = Self {
+            path: path.into(),
+            files: RwLock::new(HashMap::new()),
+            inventory: RwLock::new(Arc::new(HashMap::new())),
+            errors: RwLock::new(HashMap::new()),
+            changes,
+            started: Once::new(),
+        };
+        discovery.reload();
+        discovery
+    }
+
+    /// 重新加载所有文件，读取及解析文件时不持有锁
+    fn reload(&self) {
+        let loaded = self.paths().map(|paths| {
+            paths
+                .into_iter()
+                .map(|path| {
+                    let result = load(&path);
+                    (path, result)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut files = self.files.write().unwrap();
+        let mut errors = self.errors.write().unwrap();
+        errors.clear();
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                warn!("FileDiscovery {e}");
+                errors.insert(self.path.clone(), e);
+                return;
+            }
+        };
+        // 已删除的文件不再提供后端
+        files.retain(|path, _| loaded.iter().any(|(loaded, _)| loaded == path));
+        for (path, result) in loaded {
+            match result {
+                Ok(inventory) => {
+                    files.insert(path, inventory);
+                }
+                Err(e) => {
+                    warn!("FileDiscovery {e}");
+                    errors.insert(path, e);
+                }
+            }
+        }
+
+        let mut inventory = Inventory::new();
+        for file in files.values() {
+            for (domain, endpoints) in file {
+                inventory
+                    .entry(domain.clone())
+                    .or_default()
+                    .extend(endpoints.iter().cloned());
+            }
+        }
+        *self.inventory.write().unwrap() = Arc::new(inventory);
+        let _ = self.changes.send(());
+    }
+
+    /// 需要加载的文件，目录时为目录下的 .json/.yaml/.yml 文件
+    fn paths(&self) -> Result<Vec<PathBuf>, String> {
+        if !self.path.is_dir() {
+            return Ok(vec![self.path.clone()]);
+        }
+        let entries = fs::read_dir(&self.path)
+            .map_err(|e| format!("Read dir {} failed: {e}", self.path.display()))?;
+        let mut paths = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| format_of(path).is_some())
+            .collect::<Vec<_>>();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// 域名的后端集合，文件中没有该域名时为空集合
+    /// 没有任何文件加载成功时返回错误，保留原有后端
+    fn lookup(&self, domain: &str) -> Result<Vec<Endpoint>, Error> {
+        let inventory = self.inventory.read().unwrap().clone();
+        if let Some(endpoints) = inventory.get(domain) {
+            return Ok(endpoints.clone());
+        }
+        // 与 reload 相同的加锁顺序
+        let files = self.files.read().unwrap();
+        let errors = self.errors.read().unwrap();
+        if files.is_empty() && !errors.is_empty() {
+            let errors = errors.values().cloned().collect::<Vec<_>>();
+            return Err(Error::Discovery(errors.join("; ")));
+        }
+        Ok(Vec::new())
+    }
+
+    /// 第一次订阅时开始监听文件变化
+    fn ensure_started(self: &Arc<Self>) {
+        self.started.call_once(|| {
+            current_handle().spawn(self.clone().run());
+        });
+    }
+
+    async fn run(self: Arc<Self>) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let handler = move |event: notify::Result<notify::Event>| {
+            let _ = tx.send(event);
+        };
+        let mut watcher: Box<dyn Watcher + Send> =
+            match notify::recommended_watcher(handler.clone()) {
+                Ok(watcher) => Box::new(watcher),
+                Err(e) => {
+                    warn!("FileDiscovery file events unavailable, fallback to polling: {e}");
+                    let config = notify::Config::default().with_poll_interval(POLL_INTERVAL);
+                    match PollWatcher::new(handler, config) {
+                        Ok(watcher) => Box::new(watcher),
+                        Err(e) => {
+                            error!("FileDiscovery watch {} failed: {e}", self.path.display());
+                            return;
+                        }
+                    }
+                }
+            };
+        // 监听文件所在的目录，文件被重命名替换后仍能收到事件
+        let target = if self.path.is_dir() {
+            self.path.as_path()
+        } else {
+            self.path.parent().unwrap_or(Path::new("."))
+        };
+        if let Err(e) = watcher.watch(target, RecursiveMode::NonRecursive) {
+            error!("FileDiscovery watch {} failed: {e}", target.display());
+            return;
+        }
+        info!("FileDiscovery watching {}", self.path.display());
+
+        while let Some(event) = rx.recv().await {
+            if let Err(e) = event {
+                warn!("FileDiscovery watch event error: {e}");
+                continue;
+            }
+            sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+            let this = self.clone();
+            if let Err(e) = spawn_blocking(move || this.reload()).await {
+                error!("FileDiscovery reload {} failed: {e}", self.path.display());
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Json,
+    Yaml,
+}
+
+fn format_of(path: &Path) -> Option<Format> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => Some(Format::Json),
+        Some("yaml" | "yml") => Some(Format::Yaml),
+        _ => None,
+    }
+}
+
+/// 加载单个文件，根据扩展名选择 JSON 或 YAML 格式，其它扩展名按 JSON 解析
+/// 域名与通过管理 API 添加的域名使用相同的规范化
+fn load(path: &Path) -> Result<Inventory, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("Read {} failed: {e}", path.display()))?;
+    let parsed: Result<Inventory, String> = match format_of(path).unwrap_or(Format::Json) {
+        Format::Json => serde_json::from_str(&content).map_err(|e| e.to_string()),
+        Format::Yaml => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+    };
+    let parsed = parsed.map_err(|e| format!("Parse {} failed: {e}", path.display()))?;
+    let mut inventory = Inventory::new();
+    for (domain, endpoints) in parsed {
+        let domain = normalize_domain(&domain)
+            .map_err(|e| format!("Parse {} failed: {e}", path.display()))?;
+        inventory.entry(domain).or_default().extend(endpoints);
+    }
+    Ok(inventory)
+}
+
+#[async_trait]
+impl Discovery for FileDiscovery {
+    async fn discover(&self, config: &DomainConfig) -> Result<Vec<Endpoint>, Error> {
+        self.lookup(&config.domain)
+    }
+
+    /// 文件重新加载后，域名的后端集合变化时产生新的集合
+    fn watch(self: Arc<Self>, config: DomainConfig) -> EndpointStream {
+        self.ensure_started();
+        let changes = self.changes.subscribe();
+        // 上一次产生的后端集合，尚未产生时为空
+        let state = (self, config, changes, None::<Option<Vec<Endpoint>>>);
+        futures::stream::unfold(state, |(this, config, mut changes, last)| async move {
+            loop {
+                if last.is_some() {
+                    // 通知积压时 recv 返回 Lagged，同样重新读取当前结果
+                    let _ = changes.recv().await;
+                }
+                let result = this.lookup(&config.domain);
+                let current = result.as_ref().ok().cloned();
+                if last.as_ref() == Some(&current) {
+                    continue;
+                }
+                return Some((result, (this, config, changes, Some(current))));
+            }
+        })
+        .boxed()
+    }
+
+    fn errors(&self) -> Vec<String> {
+        self.errors.read().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(endpoints: &[Endpoint]) -> Vec<String> {
+        endpoints.iter().map(|e| e.addr.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_error_keeps_last_good_state() {
+        let path = std::env::temp_dir().join(format!("discovery-{}.json", uuid::Uuid::new_v4()));
+        fs::write(&path, r#"{"WWW.Example.com.": [{"addr": "10.0.0.1:443"}]}"#).unwrap();
+        let discovery = FileDiscovery::new(&path);
+        let lookup = |domain| discovery.lookup(domain).map(|e| addrs(&e));
+        assert_eq!(lookup("www.example.com").unwrap(), ["10.0.0.1:443"]);
+        assert!(discovery.errors().is_empty());
+
+        fs::write(&path, r#"{"www.example.com": [{"addr": "#).unwrap();
+        discovery.reload();
+        assert_eq!(lookup("www.example.com").unwrap(), ["10.0.0.1:443"]);
+        assert_eq!(discovery.errors().len(), 1);
+
+        fs::write(&path, r#"{"api.example.com": [{"addr": "10.0.0.2:443"}]}"#).unwrap();
+        discovery.reload();
+        assert!(discovery.errors().is_empty());
+        assert_eq!(lookup("api.example.com").unwrap(), ["10.0.0.2:443"]);
+        // 从文件中删除的域名不再有后端
+        assert!(lookup("www.example.com").unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_domain_fails_the_file() {
+        let path = std::env::temp_dir().join(format!("discovery-{}.yaml", uuid::Uuid::new_v4()));
+        fs::write(&path, "-bad.example.com:\n  - addr: 10.0.0.1:443\n").unwrap();
+        let discovery = FileDiscovery::new(&path);
+        assert_eq!(discovery.errors().len(), 1);
+        assert!(discovery.lookup("bad.example.com").is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
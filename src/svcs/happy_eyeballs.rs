@@ -0,0 +1,150 @@
+use std::{net::SocketAddr, time::Duration};
+
+use pingora::lb::Backend;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::TcpStream,
+    time::{sleep, timeout},
+};
+
+/// 单次连接尝试的超时时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// 首选地址族连接未完成时，等待多久开始尝试另一个地址族，见 RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// 域名的地址族策略，同时作用于后端选择和健康检查
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    /// 仅使用 IPv4 地址
+    V4Only,
+    /// 仅使用 IPv6 地址
+    V6Only,
+    /// 两个地址族竞速连接，IPv4 先行
+    PreferV4,
+    /// 两个地址族竞速连接，IPv6 先行
+    PreferV6,
+    /// 两个地址族同时竞速连接
+    #[default]
+    Both,
+}
+
+impl AddressFamily {
+    /// 地址是否允许使用
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::V4Only => addr.is_ipv4(),
+            AddressFamily::V6Only => addr.is_ipv6(),
+            _ => true,
+        }
+    }
+
+    /// 竞速连接时先行的地址族及另一个地址族的延迟，不需要竞速时为空
+    pub(super) fn race_order(&self) -> Option<(IpFamily, Duration)> {
+        match self {
+            AddressFamily::V4Only | AddressFamily::V6Only => None,
+            AddressFamily::PreferV4 => Some((IpFamily::V4, CONNECTION_ATTEMPT_DELAY)),
+            AddressFamily::PreferV6 => Some((IpFamily::V6, CONNECTION_ATTEMPT_DELAY)),
+            AddressFamily::Both => Some((IpFamily::V6, Duration::ZERO)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub(super) fn of(addr: &SocketAddr) -> Self {
+        if addr.is_ipv4() {
+            IpFamily::V4
+        } else {
+            IpFamily::V6
+        }
+    }
+
+    pub(super) fn other(self) -> Self {
+        match self {
+            IpFamily::V4 => IpFamily::V6,
+            IpFamily::V6 => IpFamily::V4,
+        }
+    }
+}
+
+/// 竞速连接两个后端，返回先连接成功的后端，都失败时为空
+/// 连接建立后即关闭，只用于在后台确定使用哪个地址族
+/// `primary` 先行，若其在 `delay` 内失败则立即开始连接 `secondary`
+pub(super) async fn race(primary: Backend, secondary: Backend, delay: Duration) -> Option<Backend> {
+    let connect = |backend: Backend| async move {
+        let addr = *backend.addr.as_inet()?;
+        match timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => Some(backend),
+            _ => None,
+        }
+    };
+
+    let first = connect(primary);
+    tokio::pin!(first);
+    tokio::select! {
+        winner = &mut first => {
+            return match winner {
+                Some(_) => winner,
+                None => connect(secondary).await,
+            };
+        }
+        _ = sleep(delay) => {}
+    }
+
+    let second = connect(secondary);
+    tokio::pin!(second);
+    tokio::select! {
+        winner = &mut first => match winner {
+            Some(_) => winner,
+            None => second.await,
+        },
+        winner = &mut second => match winner {
+            Some(_) => winner,
+            None => first.await,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn backend(addr: &str) -> Backend {
+        Backend::new(addr).unwrap()
+    }
+
+    #[tokio::test]
+    async fn refused_primary_falls_back_to_secondary() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let v4 = backend(&format!("127.0.0.1:{port}"));
+        let v6 = backend(&format!("[::1]:{port}"));
+
+        // 首选地址族被拒绝时不必等到延迟结束
+        let started = std::time::Instant::now();
+        let winner = race(v6.clone(), v4.clone(), Duration::from_secs(2)).await;
+        assert_eq!(winner, Some(v4.clone()));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let winner = race(v4.clone(), v6, CONNECTION_ATTEMPT_DELAY).await;
+        assert_eq!(winner, Some(v4));
+    }
+
+    #[tokio::test]
+    async fn both_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let v4 = backend(&format!("127.0.0.1:{port}"));
+        let v6 = backend(&format!("[::1]:{port}"));
+        assert_eq!(race(v6, v4, Duration::ZERO).await, None);
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use super::PathPattern;
+
+/// 请求头及响应头的改写规则，先应用预设，再依次应用域名的规则及匹配的路由的规则
+/// 值为模板，支持的变量：client_ip、request_id、host、scheme、method、path 及路由的命名捕获组
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HeaderRules {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub presets: Vec<HeaderPreset>,
+    /// 发送给后端的请求头
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request: Vec<HeaderAction>,
+    /// 返回给客户端的响应头
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response: Vec<HeaderAction>,
+    /// 按路径匹配的规则，只使用第一个匹配的路由
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<RouteHeaders>,
+}
+
+impl HeaderRules {
+    pub fn is_empty(&self) -> bool {
+        self.presets.is_empty()
+            && self.request.is_empty()
+            && self.response.is_empty()
+            && self.routes.is_empty()
+    }
+
+    /// 所有规则中的请求头及响应头动作
+    pub fn actions(&self) -> impl Iterator<Item = &HeaderAction> {
+        self.request.iter().chain(&self.response).chain(
+            self.routes
+                .iter()
+                .flat_map(|r| r.request.iter().chain(&r.response)),
+        )
+    }
+}
+
+/// 常用的请求头及响应头处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderPreset {
+    /// 添加 `X-Forwarded-For`、`X-Forwarded-Proto`、`X-Forwarded-Host`
+    XForwarded,
+    /// 添加 RFC 7239 的 `Forwarded`
+    Forwarded,
+    /// 去掉请求及响应中的逐跳头，升级协议的请求保留 `Connection` 及 `Upgrade`
+    StripHopByHop,
+    /// 去掉响应中暴露后端实现的头，例如 `Server`、`X-Powered-By`
+    StripServer,
+}
+
+/// 对一个头的操作
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum HeaderAction {
+    /// 追加一个值，已有的值保留
+    Add {
+        name: String,
+        value: String,
+    },
+    /// 替换所有的值
+    Set {
+        name: String,
+        value: String,
+    },
+    Remove {
+        name: String,
+    },
+    /// 将所有的值移到新的名称下
+    Rename {
+        from: String,
+        to: String,
+    },
+}
+
+impl HeaderAction {
+    /// 操作涉及的头名称
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            HeaderAction::Add { name, .. }
+            | HeaderAction::Set { name, .. }
+            | HeaderAction::Remove { name } => vec![name],
+            HeaderAction::Rename { from, to } => vec![from, to],
+        }
+    }
+}
+
+/// 路径匹配 `path` 的请求使用的规则
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RouteHeaders {
+    pub path: PathPattern,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub request: Vec<HeaderAction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub response: Vec<HeaderAction>,
+}
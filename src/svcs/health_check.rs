@@ -1,33 +1,367 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
 
 use async_trait::async_trait;
+use log::error;
 use pingora::{
-    lb::{health_check, LoadBalancer},
+    lb::{discovery::ServiceDiscovery, health_check, Backend, Backends, LoadBalancer},
     prelude::{background_service, RoundRobin},
     server::ShutdownWatch,
     services::background::{BackgroundService, GenBackgroundService},
 };
 use pingora_runtime::current_handle;
+use serde::{Deserialize, Serialize};
 use tokio::{sync::watch, time::interval};
 
+use super::{
+    happy_eyeballs::{self, IpFamily},
+    AddressFamily, Endpoint,
+};
+
+/// 地址族竞速结果的缓存时间，过期后在后台重新竞速
+const RACE_WINNER_TTL: Duration = Duration::from_secs(60);
+/// 两个地址族都连接失败时，多久后再次竞速
+const RACE_RETRY: Duration = Duration::from_secs(5);
+
+/// 后端的管理状态，优先于健康检查结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendState {
+    /// 不参与选择
+    Disabled,
+    /// 不再接收新请求，仅在没有其它可用后端时兜底
+    Draining,
+    /// 忽略健康检查结果，始终参与选择
+    ForcedHealthy,
+}
+
+/// 单个后端的管理覆盖，`expires_at` 为空时永久有效
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackendOverride {
+    pub state: BackendState,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl BackendOverride {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// 后端的当前状态，用于排查请求的去向
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendStatus {
+    pub addr: String,
+    pub weight: usize,
+    pub priority: u16,
+    /// 健康检查结果
+    pub healthy: bool,
+    /// 生效中的管理覆盖
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<BackendState>,
+}
+
+/// 地址族竞速的状态，竞速在后台进行，不占用请求的时间
+#[derive(Default)]
+struct RaceState {
+    /// 最近一次竞速胜出的地址族及其过期时间
+    winner: Option<(IpFamily, Instant)>,
+    racing: bool,
+}
+
+/// 可在运行时替换的后端集合，作为 LoadBalancer 的服务发现
+#[derive(Clone, Default)]
+struct Members(Arc<RwLock<BTreeSet<Backend>>>);
+
+#[async_trait]
+impl ServiceDiscovery for Members {
+    async fn discover(&self) -> pingora::Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        Ok((self.0.read().unwrap().clone(), HashMap::new()))
+    }
+}
+
 pub struct UpstreamsHealthCheck {
     stop_sender: watch::Sender<bool>,
     upstreams: Arc<GenBackgroundService<LoadBalancer<RoundRobin>>>,
+    members: Members,
+    endpoints: RwLock<HashMap<SocketAddr, Endpoint>>,
+    overrides: RwLock<HashMap<SocketAddr, BackendOverride>>,
+    family: RwLock<AddressFamily>,
+    race: Arc<Mutex<RaceState>>,
 }
 
 impl UpstreamsHealthCheck {
-    pub fn new(upstreams: GenBackgroundService<LoadBalancer<RoundRobin>>) -> Self {
+    /// 使用 `endpoints` 创建后端集合，启动后每秒进行一次 TCP 健康检查
+    pub async fn new(endpoints: Vec<Endpoint>) -> pingora::Result<Self> {
+        let members = Members::default();
+        let mut upstreams: LoadBalancer<RoundRobin> =
+            LoadBalancer::from_backends(Backends::new(Box::new(members.clone())));
+        upstreams.set_health_check(health_check::TcpHealthCheck::new());
+        upstreams.health_check_frequency = Some(Duration::from_secs(1));
+
         let (stop_sender, _) = watch::channel(false);
-        Self {
+        let upstreams = Self {
             stop_sender,
-            upstreams: Arc::new(upstreams),
-        }
+            upstreams: Arc::new(background_service("health check", upstreams)),
+            members,
+            endpoints: RwLock::new(HashMap::new()),
+            overrides: RwLock::new(HashMap::new()),
+            family: RwLock::new(AddressFamily::default()),
+            race: Arc::default(),
+        };
+        upstreams.set_members(endpoints);
+        upstreams.task().update().await?;
+        Ok(upstreams)
     }
 
     pub fn task(&self) -> Arc<LoadBalancer<RoundRobin>> {
         self.upstreams.task()
     }
 
+    /// 替换后端集合，已存在后端的健康状态会被保留
+    pub async fn update(&self, endpoints: Vec<Endpoint>) {
+        self.set_members(endpoints);
+        if let Err(e) = self.task().update().await {
+            error!("UpstreamsHealthCheck update backends failed: {e}");
+        }
+    }
+
+    /// 设置地址族策略，后端集合应已按该策略过滤
+    pub fn set_family(&self, family: AddressFamily) {
+        *self.family.write().unwrap() = family;
+        self.race.lock().unwrap().winner = None;
+    }
+
+    fn set_members(&self, endpoints: Vec<Endpoint>) {
+        let backends = endpoints
+            .iter()
+            .filter_map(|e| Backend::new_with_weight(&e.addr.to_string(), e.weight.max(1)).ok())
+            .collect();
+        *self.endpoints.write().unwrap() = endpoints.into_iter().map(|e| (e.addr, e)).collect();
+        *self.members.0.write().unwrap() = backends;
+    }
+
+    /// 选择一个后端，管理覆盖优先于健康检查结果
+    /// 按 priority 从小到大分组，只有前一组没有可用后端时才会使用下一组
+    /// 只有在没有其它可用后端时才会选择 draining 状态的后端
+    pub fn select(&self) -> Option<Backend> {
+        self.select_matching(|_| true)
+    }
+
+    /// 按地址族策略选择后端，两个地址族都可用时使用竞速胜出的地址族
+    /// 竞速结果过期时在后台重新竞速，期间沿用上次的结果，从未竞速时使用首选地址族
+    pub fn select_racing(&self) -> Option<Backend> {
+        let family = *self.family.read().unwrap();
+        let Some((first, delay)) = family.race_order() else {
+            return self.select();
+        };
+        let preferred = self.race_winner(first, delay);
+        self.select_family(preferred)
+            .or_else(|| self.select_family(preferred.other()))
+    }
+
+    /// 当前使用的地址族，需要时开始后台竞速
+    fn race_winner(&self, first: IpFamily, delay: Duration) -> IpFamily {
+        let mut state = self.race.lock().unwrap();
+        let preferred = state.winner.map_or(first, |(family, _)| family);
+        let fresh = state
+            .winner
+            .is_some_and(|(_, expires_at)| expires_at > Instant::now());
+        if fresh || state.racing {
+            return preferred;
+        }
+        // 竞速使用每个地址族的第一个候选后端，不改变轮询的位置
+        let candidate = |family| {
+            self.candidates(|backend| is_family(backend, family))
+                .into_iter()
+                .next()
+        };
+        let (Some(primary), Some(secondary)) = (candidate(first), candidate(first.other())) else {
+            return preferred;
+        };
+        state.racing = true;
+        let race = self.race.clone();
+        tokio::spawn(async move {
+            let winner = happy_eyeballs::race(primary, secondary, delay).await;
+            let mut state = race.lock().unwrap();
+            state.racing = false;
+            state.winner = Some(match winner.as_ref().and_then(|b| b.addr.as_inet()) {
+                Some(addr) => (IpFamily::of(addr), Instant::now() + RACE_WINNER_TTL),
+                None => (preferred, Instant::now() + RACE_RETRY),
+            });
+        });
+        preferred
+    }
+
+    /// 将要参与选择的后端，不改变轮询的位置，也不建立连接
+    /// 实际请求从这些后端中按加权轮询选择一个
+    pub fn preview(&self) -> Vec<Backend> {
+        let family = *self.family.read().unwrap();
+        let Some((first, _)) = family.race_order() else {
+            return self.candidates(|_| true);
+        };
+        let winner = self.race.lock().unwrap().winner;
+        let first = winner.map_or(first, |(family, _)| family);
+        let candidates = self.candidates(|backend| is_family(backend, first));
+        if candidates.is_empty() {
+            return self.candidates(|backend| is_family(backend, first.other()));
+        }
+        candidates
+    }
+
+    /// 所有后端及其健康状态，按优先级及地址排序
+    pub fn statuses(&self) -> Vec<BackendStatus> {
+        let overrides = self.overrides();
+        let endpoints = self.endpoints.read().unwrap();
+        let backends = self.task().backends();
+        let mut statuses: Vec<BackendStatus> = backends
+            .get_backend()
+            .iter()
+            .map(|backend| {
+                let addr = backend.addr.as_inet();
+                let endpoint = addr.and_then(|addr| endpoints.get(addr));
+                BackendStatus {
+                    addr: backend.addr.to_string(),
+                    weight: backend.weight,
+                    priority: endpoint.map(|e| e.priority).unwrap_or_default(),
+                    healthy: backends.ready(backend),
+                    state: addr.and_then(|addr| overrides.get(addr)).map(|o| o.state),
+                }
+            })
+            .collect();
+        statuses.sort_by(|a, b| (a.priority, &a.addr).cmp(&(b.priority, &b.addr)));
+        statuses
+    }
+
+    fn select_family(&self, family: IpFamily) -> Option<Backend> {
+        self.select_matching(|backend| is_family(backend, family))
+    }
+
+    fn select_matching(&self, matches: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let upstreams = self.task();
+        self.choose(matches, |accept| {
+            upstreams.select_with(b"", 256, |backend, healthy| accept(backend, healthy))
+        })
+    }
+
+    /// 满足条件的后端，不经过轮询
+    fn candidates(&self, matches: impl Fn(&Backend) -> bool) -> Vec<Backend> {
+        let backends = self.task().backends();
+        let all = backends.get_backend();
+        self.choose(matches, |accept| {
+            let chosen: Vec<Backend> = all
+                .iter()
+                .filter(|backend| accept(backend, backends.ready(backend)))
+                .cloned()
+                .collect();
+            (!chosen.is_empty()).then_some(chosen)
+        })
+        .unwrap_or_default()
+    }
+
+    /// 按优先级分组依次调用 `pick`，`pick` 从 `accept` 接受的后端中选择，返回第一个结果
+    /// 管理覆盖优先于健康检查结果，所有分组都没有结果时再尝试 draining 状态的后端
+    fn choose<T>(
+        &self,
+        matches: impl Fn(&Backend) -> bool,
+        mut pick: impl FnMut(&dyn Fn(&Backend, bool) -> bool) -> Option<T>,
+    ) -> Option<T> {
+        let now = SystemTime::now();
+        let overrides = self.overrides.read().unwrap();
+        let endpoints = self.endpoints.read().unwrap();
+        let state_of = |backend: &Backend| {
+            backend
+                .addr
+                .as_inet()
+                .and_then(|addr| overrides.get(addr))
+                .filter(|o| !o.is_expired(now))
+                .map(|o| o.state)
+        };
+        let priority_of = |backend: &Backend| {
+            backend
+                .addr
+                .as_inet()
+                .and_then(|addr| endpoints.get(addr))
+                .map(|e| e.priority)
+                .unwrap_or_default()
+        };
+        let mut tiers = endpoints.values().map(|e| e.priority).collect::<Vec<_>>();
+        tiers.sort_unstable();
+        tiers.dedup();
+        if tiers.is_empty() {
+            tiers.push(0);
+        }
+
+        for tier in &tiers {
+            let accept = |backend: &Backend, healthy: bool| {
+                if priority_of(backend) != *tier || !matches(backend) {
+                    return false;
+                }
+                match state_of(backend) {
+                    Some(BackendState::Disabled | BackendState::Draining) => false,
+                    Some(BackendState::ForcedHealthy) => true,
+                    None => healthy,
+                }
+            };
+            if let Some(chosen) = pick(&accept) {
+                return Some(chosen);
+            }
+        }
+        tiers.iter().find_map(|tier| {
+            pick(&|backend: &Backend, healthy: bool| {
+                priority_of(backend) == *tier
+                    && matches(backend)
+                    && healthy
+                    && state_of(backend) == Some(BackendState::Draining)
+            })
+        })
+    }
+
+    /// 设置后端的管理覆盖，`ttl` 为空时永久有效
+    pub fn set_override(&self, addr: SocketAddr, state: BackendState, ttl: Option<Duration>) {
+        let expires_at = ttl.map(|ttl| SystemTime::now() + ttl);
+        self.overrides
+            .write()
+            .unwrap()
+            .insert(addr, BackendOverride { state, expires_at });
+    }
+
+    /// 清除后端的管理覆盖，返回是否存在该覆盖
+    pub fn clear_override(&self, addr: &SocketAddr) -> bool {
+        self.overrides.write().unwrap().remove(addr).is_some()
+    }
+
+    /// 当前生效的管理覆盖，顺便清理已过期的条目
+    pub fn overrides(&self) -> HashMap<SocketAddr, BackendOverride> {
+        let now = SystemTime::now();
+        let mut overrides = self.overrides.write().unwrap();
+        overrides.retain(|_, o| !o.is_expired(now));
+        overrides.clone()
+    }
+
+    /// 来自静态解析表的后端地址
+    pub fn get_hosts_overrides(&self) -> Vec<String> {
+        self.endpoints
+            .read()
+            .unwrap()
+            .values()
+            .filter(|e| e.from_hosts)
+            .map(|e| e.addr.to_string())
+            .collect()
+    }
+
+    pub fn has_backend(&self, addr: &SocketAddr) -> bool {
+        self.task()
+            .backends()
+            .get_backend()
+            .iter()
+            .any(|b| b.addr.as_inet() == Some(addr))
+    }
+
     pub fn stop(&self) {
         let _ = self.stop_sender.send(true);
     }
@@ -42,18 +376,11 @@ impl UpstreamsHealthCheck {
     }
 }
 
-impl From<Vec<SocketAddr>> for UpstreamsHealthCheck {
-    fn from(socket_addr: Vec<SocketAddr>) -> Self {
-        let mut upstreams: LoadBalancer<RoundRobin> =
-            LoadBalancer::try_from_iter(socket_addr).unwrap();
-
-        let hc = health_check::TcpHealthCheck::new();
-        upstreams.set_health_check(hc);
-        upstreams.health_check_frequency = Some(Duration::from_secs(1));
-        let background = background_service("health check", upstreams);
-
-        Self::new(background)
-    }
+fn is_family(backend: &Backend, family: IpFamily) -> bool {
+    backend
+        .addr
+        .as_inet()
+        .is_some_and(|addr| IpFamily::of(addr) == family)
 }
 
 #[async_trait]
@@ -82,3 +409,145 @@ impl BackgroundService for UpstreamsHealthCheck {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(addr: &str, priority: u16) -> Endpoint {
+        Endpoint {
+            priority,
+            ..Endpoint::from(addr.parse::<SocketAddr>().unwrap())
+        }
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    /// 多次选择得到的不同后端
+    fn selected(upstreams: &UpstreamsHealthCheck) -> Vec<String> {
+        let selected: BTreeSet<String> = (0..8)
+            .filter_map(|_| upstreams.select())
+            .map(|backend| backend.addr.to_string())
+            .collect();
+        selected.into_iter().collect()
+    }
+
+    /// 模拟健康检查失败
+    fn set_unhealthy(upstreams: &UpstreamsHealthCheck, addr: &str) {
+        let backends = upstreams.task().backends();
+        let backend = backends
+            .get_backend()
+            .iter()
+            .find(|b| b.addr.to_string() == addr)
+            .cloned()
+            .unwrap();
+        backends.set_enable(&backend, false);
+    }
+
+    async fn upstreams(endpoints: &[(&str, u16)]) -> UpstreamsHealthCheck {
+        let endpoints = endpoints
+            .iter()
+            .map(|(addr, priority)| endpoint(addr, *priority))
+            .collect();
+        UpstreamsHealthCheck::new(endpoints).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn race_picks_the_family_that_connects() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let v4 = listener.local_addr().unwrap().to_string();
+        // 没有监听的 IPv6 地址，连接被拒绝或不可达
+        let v6 = format!("[::1]:{}", listener.local_addr().unwrap().port());
+        let upstreams = upstreams(&[(v4.as_str(), 0), (v6.as_str(), 0)]).await;
+        upstreams.set_family(AddressFamily::PreferV6);
+
+        // 第一次选择时在后台竞速，沿用首选的地址族
+        assert_eq!(upstreams.select_racing().unwrap().addr.to_string(), v6);
+        let winner = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some((family, _)) = upstreams.race.lock().unwrap().winner {
+                    return family;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(winner, IpFamily::V4);
+        for _ in 0..4 {
+            assert_eq!(upstreams.select_racing().unwrap().addr.to_string(), v4);
+        }
+        assert_eq!(upstreams.preview().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn race_does_not_advance_round_robin() {
+        let endpoints = [
+            ("10.0.0.1:80", 0),
+            ("10.0.0.2:80", 0),
+            ("10.0.0.3:80", 0),
+            ("[fd00::1]:80", 0),
+            ("[fd00::2]:80", 0),
+        ];
+        let raced = upstreams(&endpoints).await;
+        let untouched = upstreams(&endpoints).await;
+        raced.race_winner(IpFamily::V4, Duration::ZERO);
+        assert!(raced.race.lock().unwrap().racing);
+        // 开始竞速后轮询的位置与未竞速时相同
+        let sequence = |upstreams: &UpstreamsHealthCheck| {
+            (0..5)
+                .map(|_| upstreams.select().unwrap().addr.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(&raced), sequence(&untouched));
+    }
+
+    #[tokio::test]
+    async fn disabled_backends_are_skipped() {
+        let upstreams = upstreams(&[("10.0.0.1:80", 0), ("10.0.0.2:80", 0)]).await;
+        assert_eq!(selected(&upstreams), ["10.0.0.1:80", "10.0.0.2:80"]);
+        upstreams.set_override(addr("10.0.0.1:80"), BackendState::Disabled, None);
+        assert_eq!(selected(&upstreams), ["10.0.0.2:80"]);
+        upstreams.set_override(addr("10.0.0.2:80"), BackendState::Disabled, None);
+        assert!(upstreams.select().is_none());
+
+        // 过期的覆盖不再生效
+        upstreams.set_override(
+            addr("10.0.0.2:80"),
+            BackendState::Disabled,
+            Some(Duration::ZERO),
+        );
+        assert_eq!(selected(&upstreams), ["10.0.0.2:80"]);
+        assert!(upstreams.clear_override(&addr("10.0.0.1:80")));
+        assert_eq!(selected(&upstreams), ["10.0.0.1:80", "10.0.0.2:80"]);
+        assert!(upstreams.overrides().is_empty());
+    }
+
+    #[tokio::test]
+    async fn draining_backends_are_the_last_resort() {
+        let upstreams = upstreams(&[("10.0.0.1:80", 0), ("10.0.0.2:80", 1)]).await;
+        upstreams.set_override(addr("10.0.0.1:80"), BackendState::Draining, None);
+        // 下一优先级的可用后端优先于 draining 状态的后端
+        assert_eq!(selected(&upstreams), ["10.0.0.2:80"]);
+        set_unhealthy(&upstreams, "10.0.0.2:80");
+        assert_eq!(selected(&upstreams), ["10.0.0.1:80"]);
+        // draining 状态的后端仍需通过健康检查
+        set_unhealthy(&upstreams, "10.0.0.1:80");
+        assert!(upstreams.select().is_none());
+    }
+
+    #[tokio::test]
+    async fn forced_healthy_ignores_health_check() {
+        let upstreams = upstreams(&[("10.0.0.1:80", 0), ("10.0.0.2:80", 1)]).await;
+        assert_eq!(selected(&upstreams), ["10.0.0.1:80"]);
+        set_unhealthy(&upstreams, "10.0.0.1:80");
+        assert_eq!(selected(&upstreams), ["10.0.0.2:80"]);
+        upstreams.set_override(addr("10.0.0.1:80"), BackendState::ForcedHealthy, None);
+        assert_eq!(selected(&upstreams), ["10.0.0.1:80"]);
+        let statuses = upstreams.statuses();
+        assert!(!statuses[0].healthy);
+        assert_eq!(statuses[0].state, Some(BackendState::ForcedHealthy));
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::{normalize_domain, Error};
+
+/// 一个域名的静态解析结果，`expires_at` 为空时永久有效
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HostsEntry {
+    pub ips: Vec<IpAddr>,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl HostsEntry {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// 结果的有效期，用于安排重新解析
+    pub fn valid_until(&self) -> Option<Instant> {
+        let at = self.expires_at?;
+        let remain = at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+        Some(Instant::now() + remain)
+    }
+}
+
+/// 静态解析表，类似于代理自己的 /etc/hosts
+/// 解析域名时优先于 DNS 查询
+#[derive(Debug, Default)]
+pub struct Hosts {
+    entries: HashMap<String, HostsEntry>,
+}
+
+impl Hosts {
+    /// 从 /etc/hosts 格式的文件加载，每行为 `IP 域名 [域名...]`，`#` 之后为注释
+    /// 同一个域名出现在多行时会合并所有 IP
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Read hosts file {} failed", path.display()))?;
+        let mut hosts = Self::default();
+        for (no, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(ip) = fields.next() else {
+                continue;
+            };
+            let ip = ip
+                .parse::<IpAddr>()
+                .with_context(|| format!("Invalid IP {ip} at {}:{}", path.display(), no + 1))?;
+            for name in fields {
+                let name = normalize_domain(name).with_context(|| {
+                    format!("Invalid name {name} at {}:{}", path.display(), no + 1)
+                })?;
+                hosts
+                    .entries
+                    .entry(name)
+                    .or_insert_with(|| HostsEntry {
+                        ips: Vec::new(),
+                        expires_at: None,
+                    })
+                    .ips
+                    .push(ip);
+            }
+        }
+        Ok(hosts)
+    }
+
+    /// 查询未过期的静态解析结果，`name` 按 `normalize_domain` 规范化后查找
+    pub fn get(&self, name: &str) -> Option<&HostsEntry> {
+        let now = SystemTime::now();
+        self.entries
+            .get(&normalize_domain(name).ok()?)
+            .filter(|entry| !entry.is_expired(now))
+    }
+
+    /// 设置域名的静态解析结果，`ttl` 为空时永久有效
+    /// `name` 应已通过 `normalize_domain` 规范化
+    pub fn insert(&mut self, name: String, ips: Vec<IpAddr>, ttl: Option<Duration>) {
+        let expires_at = ttl.map(|ttl| SystemTime::now() + ttl);
+        self.entries.insert(name, HostsEntry { ips, expires_at });
+    }
+
+    /// 删除域名的静态解析结果，`name` 应已通过 `normalize_domain` 规范化
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.entries.remove(name).is_some()
+    }
+
+    /// 当前生效的静态解析结果，顺便清理已过期的条目
+    pub fn entries(&mut self) -> HashMap<String, HostsEntry> {
+        let now = SystemTime::now();
+        self.entries.retain(|_, entry| !entry.is_expired(now));
+        self.entries.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_normalized_like_domains() {
+        let path = std::env::temp_dir().join(format!("hosts-{}", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            "10.0.0.1 Bücher.Example. www.example.com # comment\n10.0.0.2 www.example.com\n",
+        )
+        .unwrap();
+        let mut hosts = Hosts::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            hosts.get("xn--bcher-kva.example").unwrap().ips,
+            [ip("10.0.0.1")]
+        );
+        assert_eq!(hosts.get("BÜCHER.example.").unwrap().ips, [ip("10.0.0.1")]);
+        assert_eq!(
+            hosts.get("WWW.example.com.").unwrap().ips,
+            [ip("10.0.0.1"), ip("10.0.0.2")]
+        );
+        assert!(hosts.get("not a name").is_none());
+
+        let name = normalize_domain("Café.Example").unwrap();
+        hosts.insert(name.clone(), vec![ip("10.0.0.3")], None);
+        assert_eq!(hosts.get("CAFÉ.example.").unwrap().ips, [ip("10.0.0.3")]);
+        assert!(hosts.remove(&name));
+    }
+
+    #[test]
+    fn invalid_names_fail_to_load() {
+        let path = std::env::temp_dir().join(format!("hosts-{}", uuid::Uuid::new_v4()));
+        fs::write(&path, "10.0.0.1 -bad.example\n").unwrap();
+        let result = Hosts::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
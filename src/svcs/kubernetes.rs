@@ -0,0 +1,1201 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use clap::Args;
+use futures::{stream::BoxStream, StreamExt};
+use log::{info, warn};
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use reqwest::StatusCode;
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::{sync::broadcast, time::sleep};
+
+use super::{normalize_domain, Discovery, DomainConfig, Endpoint, EndpointStream, Error, Op};
+
+/// 域名使用 Kubernetes 服务发现时的名称
+pub const KUBERNETES_PROVIDER: &str = "kubernetes";
+
+/// Pod 内 ServiceAccount 的 token、CA 证书及命名空间所在目录
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+/// watch 请求由 API 服务端结束的时间，单位秒，结束后从最新的 resourceVersion 继续
+const WATCH_TIMEOUT: u64 = 290;
+/// 请求超时时间，需要大于 watch 的时间
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(330);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// watch 在该时间内结束时视为异常，避免 API 服务端立即关闭连接时频繁重连
+const MIN_WATCH_DURATION: Duration = Duration::from_secs(1);
+/// 请求失败后的重试间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// 旧版本 Ingress 通过注解指定 IngressClass
+const INGRESS_CLASS_ANNOTATION: &str = "kubernetes.io/ingress.class";
+
+/// Kubernetes 配置
+/// 设置 `--k8s-in-cluster` 或 `--k8s-api` 后启用 `kubernetes` 服务发现
+#[derive(Debug, Clone, Default, Args)]
+pub struct KubernetesSettings {
+    /// 运行在 Pod 内，使用 ServiceAccount 访问 API 服务
+    #[clap(long = "k8s-in-cluster")]
+    pub in_cluster: bool,
+    /// API 服务地址，例如 `kubectl proxy` 或本地模拟的 API 服务 http://127.0.0.1:8001，
+    /// 优先于集群内的地址
+    #[clap(long = "k8s-api")]
+    pub api: Option<String>,
+    /// Bearer token 文件，每次请求时读取，在集群内默认使用 ServiceAccount 的 token
+    #[clap(long = "k8s-token-file")]
+    pub token_file: Option<PathBuf>,
+    /// API 服务的 CA 证书文件，在集群内默认使用 ServiceAccount 的 CA 证书
+    #[clap(long = "k8s-ca-file")]
+    pub ca_file: Option<PathBuf>,
+    /// 服务未指定命名空间时使用的命名空间，同时限制 Ingress 模式监听的命名空间
+    #[clap(long = "k8s-namespace")]
+    pub namespace: Option<String>,
+    /// 监听 Ingress，按规则中的 host 自动添加/删除代理域名
+    #[clap(long = "k8s-ingress")]
+    pub ingress: bool,
+    /// 只处理指定 IngressClass 的 Ingress，为空时处理所有 Ingress
+    #[clap(long = "k8s-ingress-class")]
+    pub ingress_class: Option<String>,
+}
+
+impl KubernetesSettings {
+    pub fn enabled(&self) -> bool {
+        self.in_cluster || self.api.is_some()
+    }
+
+    fn service_account_file(&self, name: &str) -> Option<PathBuf> {
+        self.in_cluster
+            .then(|| PathBuf::from(SERVICE_ACCOUNT_DIR).join(name))
+    }
+}
+
+/// 访问 API 服务的客户端
+#[derive(Clone)]
+struct KubeClient {
+    client: reqwest::Client,
+    base: String,
+    token_file: Option<PathBuf>,
+}
+
+impl KubeClient {
+    fn new(settings: &KubernetesSettings) -> Result<Self, Error> {
+        let base = match &settings.api {
+            Some(api) => api.trim_end_matches('/').to_string(),
+            None => {
+                let host = env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+                    Error::Discovery("KUBERNETES_SERVICE_HOST not set, use --k8s-api".to_string())
+                })?;
+                let port =
+                    env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+                match host.parse::<IpAddr>() {
+                    Ok(IpAddr::V6(ip)) => format!("https://[{ip}]:{port}"),
+                    _ => format!("https://{host}:{port}"),
+                }
+            }
+        };
+
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT);
+        let ca_file = settings
+            .ca_file
+            .clone()
+            .or_else(|| settings.service_account_file("ca.crt"));
+        if let Some(path) = ca_file {
+            let pem = fs::read(&path)
+                .map_err(|e| Error::Discovery(format!("Read {} failed: {e}", path.display())))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| Error::Discovery(format!("Parse {} failed: {e}", path.display())))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| Error::Discovery(format!("Build kubernetes client failed: {e}")))?;
+
+        Ok(Self {
+            client,
+            base,
+            token_file: settings
+                .token_file
+                .clone()
+                .or_else(|| settings.service_account_file("token")),
+        })
+    }
+
+    /// 发送 GET 请求，token 文件会被定期轮换，因此每次请求时读取
+    async fn get(&self, path: &str, query: &[(&str, String)]) -> Result<reqwest::Response, Error> {
+        let mut request = self.client.get(format!("{}{path}", self.base)).query(query);
+        if let Some(token_file) = &self.token_file {
+            match fs::read_to_string(token_file) {
+                Ok(token) => request = request.bearer_auth(token.trim()),
+                Err(e) => warn!("Kubernetes read {} failed: {e}", token_file.display()),
+            }
+        }
+        request
+            .send()
+            .await
+            .map_err(|e| Error::Discovery(format!("Request {path} failed: {e}")))
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, Error> {
+        self.get(path, query)
+            .await?
+            .error_for_status()
+            .map_err(|e| Error::Discovery(format!("Request {path} failed: {e}")))?
+            .json()
+            .await
+            .map_err(|e| Error::Discovery(format!("Parse {path} failed: {e}")))
+    }
+}
+
+/// 域名 `service` 引用的 Kubernetes Service，格式为 `[namespace/]name[:port]`
+/// port 为 Service 端口名称或后端端口号，为空时使用 EndpointSlice 中的第一个端口
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ServiceRef {
+    namespace: String,
+    name: String,
+    port: PortRef,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PortRef {
+    Any,
+    Name(String),
+    Number(u16),
+}
+
+impl ServiceRef {
+    fn parse(value: &str, default_namespace: &str) -> Result<Self, Error> {
+        let (service, port) = match value.split_once(':') {
+            Some((service, port)) => match port.parse() {
+                Ok(number) => (service, PortRef::Number(number)),
+                Err(_) => (service, PortRef::Name(port.to_string())),
+            },
+            None => (value, PortRef::Any),
+        };
+        let (namespace, name) = service
+            .split_once('/')
+            .unwrap_or((default_namespace, service));
+        if namespace.is_empty() || name.is_empty() {
+            return Err(Error::Discovery(format!(
+                "Invalid kubernetes service {value}, expect [namespace/]name[:port]"
+            )));
+        }
+        Ok(Self {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            port,
+        })
+    }
+
+    fn slices_path(&self) -> (String, Vec<(&'static str, String)>) {
+        let path = format!(
+            "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices",
+            self.namespace
+        );
+        let selector = format!("kubernetes.io/service-name={}", self.name);
+        (path, vec![("labelSelector", selector)])
+    }
+}
+
+impl fmt::Display for ServiceRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)?;
+        match &self.port {
+            PortRef::Any => Ok(()),
+            PortRef::Name(name) => write!(f, ":{name}"),
+            PortRef::Number(number) => write!(f, ":{number}"),
+        }
+    }
+}
+
+impl PortRef {
+    fn select(&self, ports: &[SlicePort]) -> Option<u16> {
+        let port = match self {
+            PortRef::Any => ports.first(),
+            PortRef::Name(name) => ports.iter().find(|p| p.name.as_deref() == Some(name)),
+            PortRef::Number(number) => ports.iter().find(|p| p.port == Some(*number)),
+        };
+        port.and_then(|p| p.port)
+    }
+}
+
+/// Kubernetes 服务发现
+/// 通过 list+watch 监听域名 `service` 引用的 Service 的 EndpointSlice，使用其中就绪的地址
+/// 可用区及节点名称作为后端的元数据
+pub struct KubernetesDiscovery {
+    client: KubeClient,
+    namespace: String,
+}
+
+impl KubernetesDiscovery {
+    pub fn new(settings: &KubernetesSettings) -> Result<Self, Error> {
+        let namespace = settings
+            .namespace
+            .clone()
+            .or_else(|| {
+                let path = settings.service_account_file("namespace")?;
+                fs::read_to_string(path)
+                    .ok()
+                    .map(|ns| ns.trim().to_string())
+            })
+            .unwrap_or_else(|| "default".to_string());
+        Ok(Self {
+            client: KubeClient::new(settings)?,
+            namespace,
+        })
+    }
+
+    /// Ingress 模式，监听 Ingress 并通过 `sender` 添加/删除代理域名
+    pub fn ingress(
+        &self,
+        settings: &KubernetesSettings,
+        sender: broadcast::Sender<Op>,
+    ) -> KubernetesIngress {
+        KubernetesIngress {
+            client: self.client.clone(),
+            namespace: settings.namespace.clone(),
+            class: settings.ingress_class.clone(),
+            sender,
+        }
+    }
+
+    fn service_ref(&self, config: &DomainConfig) -> Result<ServiceRef, Error> {
+        let service = config.service.as_deref().ok_or_else(|| {
+            Error::Discovery(format!(
+                "Domain {} has no kubernetes service",
+                config.domain
+            ))
+        })?;
+        ServiceRef::parse(service, &self.namespace)
+    }
+}
+
+#[async_trait]
+impl Discovery for KubernetesDiscovery {
+    async fn discover(&self, config: &DomainConfig) -> Result<Vec<Endpoint>, Error> {
+        let service = self.service_ref(config)?;
+        let (path, query) = service.slices_path();
+        let list: ObjectList<EndpointSlice> = self.client.get_json(&path, &query).await?;
+        Ok(endpoints_of(&list.items, &service.port))
+    }
+
+    /// EndpointSlice 每次变化后产生 Service 当前的全部后端
+    fn watch(self: Arc<Self>, config: DomainConfig) -> EndpointStream {
+        let service = match self.service_ref(&config) {
+            Ok(service) => service,
+            Err(e) => return futures::stream::once(async move { Err(e) }).boxed(),
+        };
+        let (path, query) = service.slices_path();
+        reflect::<EndpointSlice>(self.client.clone(), path, query)
+            .map(move |result| result.map(|slices| endpoints_of(&slices, &service.port)))
+            .boxed()
+    }
+}
+
+/// EndpointSlice 中就绪的地址，同一地址出现在多个 EndpointSlice 中时只保留一个
+fn endpoints_of(slices: &[EndpointSlice], port: &PortRef) -> Vec<Endpoint> {
+    let mut endpoints = Vec::new();
+    for slice in slices {
+        // FQDN 类型的地址不是 IP，忽略
+        if !matches!(slice.address_type.as_str(), "IPv4" | "IPv6") {
+            continue;
+        }
+        let Some(port) = port.select(&slice.ports) else {
+            continue;
+        };
+        for endpoint in &slice.endpoints {
+            // ready 为空表示状态未知，按就绪处理
+            if endpoint.conditions.ready == Some(false) {
+                continue;
+            }
+            let mut metadata = BTreeMap::new();
+            if let Some(zone) = &endpoint.zone {
+                metadata.insert("zone".to_string(), zone.clone());
+            }
+            if let Some(node) = &endpoint.node_name {
+                metadata.insert("node".to_string(), node.clone());
+            }
+            for address in &endpoint.addresses {
+                let Ok(ip) = address.parse::<IpAddr>() else {
+                    continue;
+                };
+                endpoints.push(Endpoint {
+                    metadata: metadata.clone(),
+                    ..SocketAddr::new(ip, port).into()
+                });
+            }
+        }
+    }
+    endpoints.sort_by_key(|e| e.addr);
+    endpoints.dedup_by_key(|e| e.addr);
+    endpoints
+}
+
+/// Ingress 模式
+/// 监听 Ingress，将规则中的 host 作为代理域名，后端为规则引用的 Service，
+/// 通过与管理 API 相同的操作队列添加/删除域名，只删除由 Ingress 添加的域名
+pub struct KubernetesIngress {
+    client: KubeClient,
+    /// 监听的命名空间，为空时监听所有命名空间
+    namespace: Option<String>,
+    class: Option<String>,
+    sender: broadcast::Sender<Op>,
+}
+
+impl KubernetesIngress {
+    fn matches_class(&self, ingress: &Ingress) -> bool {
+        let Some(class) = &self.class else {
+            return true;
+        };
+        let name = ingress
+            .spec
+            .ingress_class_name
+            .as_ref()
+            .or_else(|| ingress.metadata.annotations.get(INGRESS_CLASS_ANNOTATION));
+        name == Some(class)
+    }
+
+    /// 所有 Ingress 规则中 host 到 Service 的映射
+    /// 同一 host 出现在多个 Ingress 中时使用按命名空间及名称排序的第一个
+    async fn hosts(&self, mut ingresses: Vec<Ingress>) -> HashMap<String, ServiceRef> {
+        ingresses.sort_by(|a, b| key_of(&a.metadata).cmp(&key_of(&b.metadata)));
+        let mut hosts = HashMap::new();
+        for ingress in ingresses.iter().filter(|i| self.matches_class(i)) {
+            let namespace = &ingress.metadata.namespace;
+            for rule in &ingress.spec.rules {
+                let Some(host) = rule.host.as_deref() else {
+                    continue;
+                };
+                if host.starts_with('*') {
+                    warn!("KubernetesIngress skip wildcard host {host}");
+                    continue;
+                }
+                let host = match normalize_domain(host) {
+                    Ok(host) => host,
+                    Err(e) => {
+                        warn!(
+                            "KubernetesIngress skip host in {}: {e}",
+                            key_of(&ingress.metadata)
+                        );
+                        continue;
+                    }
+                };
+                if hosts.contains_key(&host) {
+                    warn!(
+                        "KubernetesIngress host {host} in {} already defined, ignored",
+                        key_of(&ingress.metadata)
+                    );
+                    continue;
+                }
+                let Some(backend) = rule.backend().or(ingress.spec.default_backend.as_ref()) else {
+                    continue;
+                };
+                let Some(service) = &backend.service else {
+                    continue;
+                };
+                let port = self.port_of(namespace, service).await;
+                hosts.insert(
+                    host,
+                    ServiceRef {
+                        namespace: namespace.clone(),
+                        name: service.name.clone(),
+                        port,
+                    },
+                );
+            }
+        }
+        hosts
+    }
+
+    /// Ingress 使用 Service 的端口，EndpointSlice 中为端口名称及后端端口号，
+    /// 端口号需要通过 Service 找到对应的端口名称
+    async fn port_of(&self, namespace: &str, backend: &IngressServiceBackend) -> PortRef {
+        if let Some(name) = &backend.port.name {
+            return PortRef::Name(name.clone());
+        }
+        let Some(number) = backend.port.number else {
+            return PortRef::Any;
+        };
+        let path = format!("/api/v1/namespaces/{namespace}/services/{}", backend.name);
+        let service = match self.client.get_json::<Service>(&path, &[]).await {
+            Ok(service) => service,
+            Err(e) => {
+                warn!("KubernetesIngress get service port {number} failed: {e}");
+                return PortRef::Any;
+            }
+        };
+        match service.spec.ports.into_iter().find(|p| p.port == number) {
+            Some(ServicePort {
+                name: Some(name), ..
+            }) if !name.is_empty() => PortRef::Name(name),
+            Some(_) => PortRef::Any,
+            None => {
+                warn!("KubernetesIngress service {path} has no port {number}");
+                PortRef::Any
+            }
+        }
+    }
+
+    /// 应用 Ingress 的变化，返回应用后由 Ingress 管理的域名
+    fn apply(
+        &self,
+        applied: HashMap<String, ServiceRef>,
+        hosts: HashMap<String, ServiceRef>,
+    ) -> HashMap<String, ServiceRef> {
+        for domain in applied.keys().filter(|d| !hosts.contains_key(*d)) {
+            info!("KubernetesIngress remove domain {domain}");
+            let _ = self.sender.send(Op::Del(domain.clone()));
+        }
+        for (domain, service) in &hosts {
+            if applied.get(domain) == Some(service) {
+                continue;
+            }
+            info!("KubernetesIngress add domain {domain} with service {service}");
+            let _ = self.sender.send(Op::Add(DomainConfig {
+                domain: domain.clone(),
+                provider: Some(KUBERNETES_PROVIDER.to_string()),
+                service: Some(service.to_string()),
+                ..Default::default()
+            }));
+        }
+        hosts
+    }
+}
+
+#[async_trait]
+impl BackgroundService for KubernetesIngress {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let path = match &self.namespace {
+            Some(namespace) => {
+                format!("/apis/networking.k8s.io/v1/namespaces/{namespace}/ingresses")
+            }
+            None => "/apis/networking.k8s.io/v1/ingresses".to_string(),
+        };
+        let mut ingresses = reflect::<Ingress>(self.client.clone(), path, Vec::new());
+        let mut applied = HashMap::new();
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    println!("KubernetesIngress Shutdown.");
+                    break;
+                }
+                result = ingresses.next() => match result {
+                    Some(Ok(ingresses)) => {
+                        let hosts = self.hosts(ingresses).await;
+                        applied = self.apply(applied, hosts);
+                    }
+                    Some(Err(e)) => warn!("KubernetesIngress watch failed: {e}"),
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+/// list+watch 一类资源，每次变化后产生当前的全部对象
+/// 断开后从最新的 resourceVersion 继续 watch，resourceVersion 过期（410）时重新 list
+fn reflect<T: Resource>(
+    client: KubeClient,
+    path: String,
+    query: Vec<(&'static str, String)>,
+) -> BoxStream<'static, Result<Vec<T>, Error>> {
+    let reflector = Reflector {
+        client,
+        path,
+        query,
+        objects: HashMap::new(),
+        resource_version: None,
+        watch: None,
+        watch_started: Instant::now(),
+        buffer: Vec::new(),
+    };
+    futures::stream::unfold(reflector, |mut reflector| async move {
+        let result = reflector.next().await;
+        if result.is_err() {
+            sleep(RETRY_INTERVAL).await;
+        }
+        Some((result, reflector))
+    })
+    .boxed()
+}
+
+struct Reflector<T> {
+    client: KubeClient,
+    path: String,
+    query: Vec<(&'static str, String)>,
+    /// 命名空间/名称到对象的映射
+    objects: HashMap<String, T>,
+    /// 已同步到的版本，为空时需要重新 list
+    resource_version: Option<String>,
+    watch: Option<reqwest::Response>,
+    watch_started: Instant,
+    /// watch 响应中尚未读完的一行
+    buffer: Vec<u8>,
+}
+
+impl<T: Resource> Reflector<T> {
+    /// 等待下一次变化，返回变化后的全部对象
+    async fn next(&mut self) -> Result<Vec<T>, Error> {
+        loop {
+            if self.resource_version.is_none() {
+                return self.list().await;
+            }
+            if self.watch.is_none() {
+                self.start_watch().await?;
+                continue;
+            }
+            let Some(line) = self.read_line().await? else {
+                continue;
+            };
+            if !line.trim().is_empty() && self.apply(&line)? {
+                return Ok(self.objects.values().cloned().collect());
+            }
+        }
+    }
+
+    async fn list(&mut self) -> Result<Vec<T>, Error> {
+        let list: ObjectList<T> = self.client.get_json(&self.path, &self.query).await?;
+        self.objects = list
+            .items
+            .into_iter()
+            .map(|object| (key_of(object.metadata()), object))
+            .collect();
+        self.resource_version = Some(list.metadata.resource_version);
+        Ok(self.objects.values().cloned().collect())
+    }
+
+    async fn start_watch(&mut self) -> Result<(), Error> {
+        let mut query = self.query.clone();
+        query.extend([
+            ("watch", "true".to_string()),
+            ("allowWatchBookmarks", "true".to_string()),
+            ("timeoutSeconds", WATCH_TIMEOUT.to_string()),
+            (
+                "resourceVersion",
+                self.resource_version.clone().unwrap_or_default(),
+            ),
+        ]);
+        let response = self.client.get(&self.path, &query).await?;
+        if response.status() == StatusCode::GONE {
+            self.resource_version = None;
+            return Ok(());
+        }
+        let response = response
+            .error_for_status()
+            .map_err(|e| Error::Discovery(format!("Watch {} failed: {e}", self.path)))?;
+        self.buffer.clear();
+        self.watch = Some(response);
+        self.watch_started = Instant::now();
+        Ok(())
+    }
+
+    /// 读取 watch 响应中的一个事件，每个事件为一行 JSON
+    /// watch 正常结束时返回空
+    async fn read_line(&mut self) -> Result<Option<String>, Error> {
+        loop {
+            if let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line = self.buffer.drain(..=pos).collect::<Vec<_>>();
+                return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+            }
+            let Some(response) = self.watch.as_mut() else {
+                return Ok(None);
+            };
+            match response.chunk().await {
+                Ok(Some(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Ok(None) => {
+                    self.watch = None;
+                    if self.watch_started.elapsed() < MIN_WATCH_DURATION {
+                        return Err(Error::Discovery(format!(
+                            "Watch {} closed by server",
+                            self.path
+                        )));
+                    }
+                    return Ok(None);
+                }
+                Err(e) => {
+                    self.watch = None;
+                    return Err(Error::Discovery(format!("Watch {} failed: {e}", self.path)));
+                }
+            }
+        }
+    }
+
+    /// 应用一个 watch 事件，返回对象是否变化
+    fn apply(&mut self, line: &str) -> Result<bool, Error> {
+        let event: WatchEvent = serde_json::from_str(line)
+            .map_err(|e| Error::Discovery(format!("Parse {} event failed: {e}", self.path)))?;
+        match event.kind.as_str() {
+            "ADDED" | "MODIFIED" | "DELETED" => {
+                let object: T = serde_json::from_value(event.object).map_err(|e| {
+                    Error::Discovery(format!("Parse {} object failed: {e}", self.path))
+                })?;
+                let metadata = object.metadata();
+                self.resource_version = Some(metadata.resource_version.clone());
+                let key = key_of(metadata);
+                if event.kind == "DELETED" {
+                    self.objects.remove(&key);
+                } else {
+                    self.objects.insert(key, object);
+                }
+                Ok(true)
+            }
+            "BOOKMARK" => {
+                let version = event.object.pointer("/metadata/resourceVersion");
+                if let Some(version) = version.and_then(|v| v.as_str()) {
+                    self.resource_version = Some(version.to_string());
+                }
+                Ok(false)
+            }
+            "ERROR" => {
+                self.watch = None;
+                // 410 Gone 表示 resourceVersion 已过期，需要重新 list
+                if event.object.get("code").and_then(|c| c.as_u64()) == Some(410) {
+                    self.resource_version = None;
+                    return Ok(false);
+                }
+                Err(Error::Discovery(format!(
+                    "Watch {} error: {}",
+                    self.path, event.object
+                )))
+            }
+            kind => {
+                warn!("Kubernetes watch {} unknown event {kind}", self.path);
+                Ok(false)
+            }
+        }
+    }
+}
+
+trait Resource: DeserializeOwned + Clone + Send + Sync + 'static {
+    fn metadata(&self) -> &ObjectMeta;
+}
+
+fn key_of(metadata: &ObjectMeta) -> String {
+    format!("{}/{}", metadata.namespace, metadata.name)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    namespace: String,
+    #[serde(default)]
+    resource_version: String,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ObjectList<T> {
+    #[serde(default)]
+    metadata: ObjectMeta,
+    #[serde(default)]
+    items: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EndpointSlice {
+    metadata: ObjectMeta,
+    #[serde(default)]
+    address_type: String,
+    #[serde(default)]
+    endpoints: Vec<SliceEndpoint>,
+    #[serde(default)]
+    ports: Vec<SlicePort>,
+}
+
+impl Resource for EndpointSlice {
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SliceEndpoint {
+    #[serde(default)]
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: Conditions,
+    node_name: Option<String>,
+    zone: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Conditions {
+    ready: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SlicePort {
+    name: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Ingress {
+    metadata: ObjectMeta,
+    #[serde(default)]
+    spec: IngressSpec,
+}
+
+impl Resource for Ingress {
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IngressSpec {
+    ingress_class_name: Option<String>,
+    default_backend: Option<IngressBackend>,
+    #[serde(default)]
+    rules: Vec<IngressRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct IngressRule {
+    host: Option<String>,
+    http: Option<HttpIngressRuleValue>,
+}
+
+impl IngressRule {
+    /// 按域名代理不区分路径，优先使用根路径的后端，没有时使用第一个路径的后端
+    fn backend(&self) -> Option<&IngressBackend> {
+        let paths = &self.http.as_ref()?.paths;
+        paths
+            .iter()
+            .find(|p| matches!(p.path.as_deref(), None | Some("" | "/")))
+            .or(paths.first())
+            .map(|p| &p.backend)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct HttpIngressRuleValue {
+    #[serde(default)]
+    paths: Vec<HttpIngressPath>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct HttpIngressPath {
+    path: Option<String>,
+    backend: IngressBackend,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct IngressBackend {
+    service: Option<IngressServiceBackend>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct IngressServiceBackend {
+    name: String,
+    #[serde(default)]
+    port: ServiceBackendPort,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ServiceBackendPort {
+    name: Option<String>,
+    number: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Service {
+    #[serde(default)]
+    spec: ServiceSpec,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ServiceSpec {
+    #[serde(default)]
+    ports: Vec<ServicePort>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServicePort {
+    name: Option<String>,
+    port: u16,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    use axum::{
+        body::Body,
+        extract::{Query, State},
+        http::{StatusCode, Uri},
+        response::{IntoResponse, Response},
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+    use tokio::{
+        net::TcpListener,
+        sync::{mpsc, watch},
+        time::timeout,
+    };
+
+    use super::*;
+
+    const SLICES: &str = "/apis/discovery.k8s.io/v1/namespaces/default/endpointslices";
+    const INGRESSES: &str = "/apis/networking.k8s.io/v1/namespaces/apps/ingresses";
+
+    /// 对象列表及正在进行的 watch
+    #[derive(Default)]
+    struct Collection {
+        version: String,
+        items: Vec<Value>,
+        watches: Vec<mpsc::UnboundedSender<String>>,
+    }
+
+    /// 只实现 list、watch 及 get 的 API 服务
+    #[derive(Default)]
+    struct FakeApi {
+        collections: Mutex<HashMap<String, Collection>>,
+        objects: Mutex<HashMap<String, Value>>,
+        lists: AtomicUsize,
+        selectors: Mutex<Vec<String>>,
+        /// 每次 watch 请求的 resourceVersion
+        watch_versions: Mutex<Vec<String>>,
+    }
+
+    impl FakeApi {
+        fn set(&self, path: &str, version: &str, items: Vec<Value>) {
+            let mut collections = self.collections.lock().unwrap();
+            let collection = collections.entry(path.to_string()).or_default();
+            collection.version = version.to_string();
+            collection.items = items;
+        }
+
+        fn event(&self, path: &str, kind: &str, object: Value) {
+            let line = format!("{}\n", json!({ "type": kind, "object": object }));
+            let mut collections = self.collections.lock().unwrap();
+            let collection = collections.get_mut(path).unwrap();
+            collection
+                .watches
+                .retain(|watch| watch.send(line.clone()).is_ok());
+        }
+
+        /// 等待第 `count` 个 watch 请求
+        async fn wait_watches(&self, count: usize) {
+            let wait = async {
+                while self.watch_versions.lock().unwrap().len() < count {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            };
+            timeout(Duration::from_secs(3), wait).await.unwrap();
+        }
+    }
+
+    async fn handle(
+        State(api): State<Arc<FakeApi>>,
+        uri: Uri,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Response {
+        if let Some(object) = api.objects.lock().unwrap().get(uri.path()) {
+            return Json(object.clone()).into_response();
+        }
+        let mut collections = api.collections.lock().unwrap();
+        let Some(collection) = collections.get_mut(uri.path()) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if let Some(selector) = params.get("labelSelector") {
+            api.selectors.lock().unwrap().push(selector.clone());
+        }
+        if params.get("watch").map(String::as_str) == Some("true") {
+            let version = params.get("resourceVersion").cloned().unwrap_or_default();
+            api.watch_versions.lock().unwrap().push(version);
+            let (sender, receiver) = mpsc::unbounded_channel();
+            collection.watches.push(sender);
+            let lines = futures::stream::unfold(receiver, |mut receiver| async move {
+                let line = receiver.recv().await?;
+                Some((Ok::<_, Infallible>(line), receiver))
+            });
+            return Body::from_stream(lines).into_response();
+        }
+        api.lists.fetch_add(1, Ordering::SeqCst);
+        Json(json!({
+            "metadata": { "resourceVersion": collection.version },
+            "items": collection.items,
+        }))
+        .into_response()
+    }
+
+    async fn start() -> (Arc<FakeApi>, KubernetesSettings) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let api = Arc::new(FakeApi::default());
+        let app = Router::new().fallback(handle).with_state(api.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let settings = KubernetesSettings {
+            api: Some(format!("http://{addr}/")),
+            namespace: Some("default".to_string()),
+            ..Default::default()
+        };
+        (api, settings)
+    }
+
+    /// 包含一个未就绪地址的 EndpointSlice
+    fn slice(name: &str, version: &str, ready: &[&str]) -> Value {
+        let mut endpoints: Vec<Value> = ready
+            .iter()
+            .map(|address| {
+                json!({
+                    "addresses": [address],
+                    "conditions": { "ready": true },
+                    "zone": "zone-a",
+                    "nodeName": "node-1",
+                })
+            })
+            .collect();
+        endpoints.push(json!({ "addresses": ["10.0.9.9"], "conditions": { "ready": false } }));
+        json!({
+            "metadata": { "name": name, "namespace": "default", "resourceVersion": version },
+            "addressType": "IPv4",
+            "ports": [{ "name": "metrics", "port": 9090 }, { "name": "http", "port": 8080 }],
+            "endpoints": endpoints,
+        })
+    }
+
+    fn web() -> DomainConfig {
+        DomainConfig {
+            domain: "web.example.com".to_string(),
+            provider: Some(KUBERNETES_PROVIDER.to_string()),
+            service: Some("web:http".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn addrs(endpoints: &[Endpoint]) -> Vec<String> {
+        endpoints.iter().map(|e| e.addr.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_service_ref() {
+        let service = ServiceRef::parse("apps/web:8080", "default").unwrap();
+        assert_eq!(service.namespace, "apps");
+        assert_eq!(service.name, "web");
+        assert_eq!(service.port, PortRef::Number(8080));
+        let service = ServiceRef::parse("web:http", "default").unwrap();
+        assert_eq!(service.to_string(), "default/web:http");
+        assert_eq!(
+            ServiceRef::parse("web", "default").unwrap().port,
+            PortRef::Any
+        );
+        assert!(ServiceRef::parse("/web", "default").is_err());
+        assert!(ServiceRef::parse("apps/", "default").is_err());
+    }
+
+    #[tokio::test]
+    async fn discover_lists_ready_endpoints() {
+        let (api, settings) = start().await;
+        api.set(
+            SLICES,
+            "10",
+            vec![slice("web-a", "10", &["10.0.0.2", "10.0.0.1"])],
+        );
+        let discovery = KubernetesDiscovery::new(&settings).unwrap();
+        let endpoints = discovery.discover(&web()).await.unwrap();
+        assert_eq!(addrs(&endpoints), ["10.0.0.1:8080", "10.0.0.2:8080"]);
+        assert_eq!(endpoints[0].metadata["zone"], "zone-a");
+        assert_eq!(endpoints[0].metadata["node"], "node-1");
+        assert_eq!(
+            *api.selectors.lock().unwrap(),
+            ["kubernetes.io/service-name=web"]
+        );
+    }
+
+    async fn next(
+        changes: &mut mpsc::UnboundedReceiver<Result<Vec<String>, Error>>,
+    ) -> Vec<String> {
+        timeout(Duration::from_secs(3), changes.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn watch_applies_events_and_relists_when_gone() {
+        let (api, settings) = start().await;
+        api.set(SLICES, "10", vec![slice("web-a", "10", &["10.0.0.1"])]);
+        let discovery = Arc::new(KubernetesDiscovery::new(&settings).unwrap());
+        let mut stream = discovery.watch(web());
+        let (sender, mut changes) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(result) = stream.next().await {
+                let _ = sender.send(result.map(|endpoints| addrs(&endpoints)));
+            }
+        });
+        assert_eq!(next(&mut changes).await, ["10.0.0.1:8080"]);
+        api.wait_watches(1).await;
+        assert_eq!(*api.watch_versions.lock().unwrap(), ["10"]);
+
+        let modified = slice("web-a", "11", &["10.0.0.1", "10.0.0.3"]);
+        api.event(SLICES, "MODIFIED", modified);
+        assert_eq!(next(&mut changes).await, ["10.0.0.1:8080", "10.0.0.3:8080"]);
+        api.event(SLICES, "ADDED", slice("web-b", "12", &["10.0.0.4"]));
+        assert_eq!(
+            next(&mut changes).await,
+            ["10.0.0.1:8080", "10.0.0.3:8080", "10.0.0.4:8080"]
+        );
+        api.event(SLICES, "DELETED", slice("web-a", "13", &[]));
+        assert_eq!(next(&mut changes).await, ["10.0.0.4:8080"]);
+
+        // resourceVersion 过期后重新 list，并从新的版本继续 watch
+        api.set(SLICES, "20", vec![slice("web-c", "20", &["10.0.0.5"])]);
+        api.event(SLICES, "ERROR", json!({ "kind": "Status", "code": 410 }));
+        assert_eq!(next(&mut changes).await, ["10.0.0.5:8080"]);
+        assert_eq!(api.lists.load(Ordering::SeqCst), 2);
+        api.wait_watches(2).await;
+        assert_eq!(api.watch_versions.lock().unwrap()[1], "20");
+    }
+
+    fn ingress(name: &str, version: &str, class: Option<&str>, rules: Value) -> Value {
+        let mut ingress = json!({
+            "metadata": { "name": name, "namespace": "apps", "resourceVersion": version },
+            "spec": { "rules": rules },
+        });
+        if let Some(class) = class {
+            ingress["metadata"]["annotations"] = json!({ INGRESS_CLASS_ANNOTATION: class });
+        }
+        ingress
+    }
+
+    fn rule(host: &str, path: &str, service: &str, port: Value) -> Value {
+        json!({
+            "host": host,
+            "http": { "paths": [{ "path": path, "backend": { "service": { "name": service, "port": port } } }] },
+        })
+    }
+
+    /// 等待 Ingress 模式发出的操作，按域名排序，删除操作的服务为空
+    async fn ops(
+        receiver: &mut broadcast::Receiver<Op>,
+        count: usize,
+    ) -> Vec<(String, Option<String>)> {
+        let mut ops = Vec::new();
+        for _ in 0..count {
+            let op = timeout(Duration::from_secs(3), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            ops.push(match op {
+                Op::Add(config) => (config.domain, config.service),
+                Op::Del(domain) => (domain, None),
+                Op::Refresh => panic!("unexpected refresh"),
+            });
+        }
+        ops.sort();
+        ops
+    }
+
+    #[tokio::test]
+    async fn ingress_adds_and_removes_domains() {
+        let (api, mut settings) = start().await;
+        settings.namespace = Some("apps".to_string());
+        settings.ingress = true;
+        settings.ingress_class = Some("proxy".to_string());
+        api.objects.lock().unwrap().insert(
+            "/api/v1/namespaces/apps/services/web".to_string(),
+            json!({ "spec": { "ports": [{ "name": "http", "port": 80 }] } }),
+        );
+        let first = ingress(
+            "a",
+            "10",
+            Some("proxy"),
+            json!([
+                rule("Web.Example.com", "/", "web", json!({ "number": 80 })),
+                rule("*.wild.example.com", "/", "web", json!({ "number": 80 })),
+                rule("api.example.com", "/v1", "api", json!({ "name": "grpc" })),
+            ]),
+        );
+        let other = ingress(
+            "b",
+            "10",
+            Some("other"),
+            json!([rule(
+                "other.example.com",
+                "/",
+                "other",
+                json!({ "name": "http" })
+            )]),
+        );
+        let duplicate = ingress(
+            "c",
+            "10",
+            Some("proxy"),
+            json!([rule(
+                "web.example.com",
+                "/",
+                "legacy",
+                json!({ "name": "http" })
+            )]),
+        );
+        api.set(INGRESSES, "10", vec![duplicate, other, first.clone()]);
+
+        let (sender, mut receiver) = broadcast::channel(16);
+        let discovery = KubernetesDiscovery::new(&settings).unwrap();
+        let ingress_mode = discovery.ingress(&settings, sender);
+        let (shutdown, watch) = watch::channel(false);
+        tokio::spawn(async move { ingress_mode.start(watch).await });
+
+        assert_eq!(
+            ops(&mut receiver, 2).await,
+            [
+                (
+                    "api.example.com".to_string(),
+                    Some("apps/api:grpc".to_string())
+                ),
+                (
+                    "web.example.com".to_string(),
+                    Some("apps/web:http".to_string())
+                ),
+            ]
+        );
+
+        // 删除后由另一个 Ingress 接管 web.example.com
+        api.wait_watches(1).await;
+        api.event(INGRESSES, "DELETED", first);
+        assert_eq!(
+            ops(&mut receiver, 2).await,
+            [
+                ("api.example.com".to_string(), None),
+                (
+                    "web.example.com".to_string(),
+                    Some("apps/legacy:http".to_string())
+                ),
+            ]
+        );
+        let _ = shutdown.send(true);
+    }
+}
@@ -1,30 +1,162 @@
-use std::fmt;
+use std::{collections::BTreeMap, fmt, net::SocketAddr};
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+pub use concurrency::{AdaptiveAlgorithm, AdaptiveLimit, ConcurrencyLimit};
+pub use consul::ConsulDiscovery;
+pub use discovery::{Discovery, EndpointStream, StaticDiscovery};
+pub use dns_config::{DnsProtocol, IpStrategy, ResolverProfile, ResolverSettings};
 pub use dns_resolver::DNSResolver;
-pub use health_check::UpstreamsHealthCheck;
+pub use domain_name::{normalize_domain, parse_authority, Authority};
+pub use file_discovery::FileDiscovery;
+pub use happy_eyeballs::AddressFamily;
+pub use headers::{HeaderAction, HeaderPreset, HeaderRules, RouteHeaders};
+pub use health_check::{BackendOverride, BackendState, BackendStatus, UpstreamsHealthCheck};
+pub use hosts::{Hosts, HostsEntry};
+pub use kubernetes::{
+    KubernetesDiscovery, KubernetesIngress, KubernetesSettings, KUBERNETES_PROVIDER,
+};
+pub use pattern::PathPattern;
+pub use registry::{Registry, DEFAULT_PROVIDER};
+pub use rewrite::{PathPrefix, ResponseRewrite};
+pub use timeouts::Timeouts;
 
+mod concurrency;
+mod consul;
+mod discovery;
+mod dns_config;
 mod dns_resolver;
+mod domain_name;
+mod file_discovery;
+mod happy_eyeballs;
+mod headers;
 mod health_check;
+mod hosts;
+mod kubernetes;
+mod pattern;
+mod registry;
+mod rewrite;
+mod timeouts;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("DNS resolution error: {0}")]
     Resolver(#[from] anyhow::Error),
+    #[error("Discovery error: {0}")]
+    Discovery(String),
+    #[error("Invalid host: {0}")]
+    InvalidHost(String),
+}
+
+/// 服务发现得到的一个后端地址
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Endpoint {
+    pub addr: SocketAddr,
+    /// 权重，用于加权轮询
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+    /// 优先级，数值越小越优先，同一优先级的后端组成一组
+    #[serde(default)]
+    pub priority: u16,
+    /// 是否来自静态解析表
+    #[serde(skip)]
+    pub from_hosts: bool,
+    /// 服务发现附带的元数据，例如所在可用区
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            weight: 1,
+            priority: 0,
+            from_hosts: false,
+            metadata: BTreeMap::new(),
+        }
+    }
+}
+
+/// 添加代理域名时的配置
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DomainConfig {
+    pub domain: String,
+    /// 使用的命名 DNS 解析配置，为空时使用默认配置
+    #[serde(default)]
+    pub resolver: Option<String>,
+    /// SRV 记录名称，例如 `_https._tcp.service.internal`
+    /// 设置后通过 SRV 记录发现后端，使用记录中的端口、优先级和权重
+    #[serde(default)]
+    pub srv: Option<String>,
+    /// 地址族策略
+    #[serde(default)]
+    pub family: AddressFamily,
+    /// 使用的服务发现名称，为空时使用 DNS
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// 静态后端列表，供 `static` 服务发现使用
+    #[serde(default)]
+    pub backends: Vec<Endpoint>,
+    /// 服务发现中的服务名称，例如 Consul 服务名，为空时使用域名
+    #[serde(default)]
+    pub service: Option<String>,
+    /// 访问后端的超时设置
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// 错误响应中展示的详细程度
+    #[serde(default)]
+    pub error_detail: ErrorDetail,
+    /// 作为目标域名的别名，使用目标域名的后端集合、健康检查及配置，
+    /// 此时除 `upstream_host` 及 `rewrite` 外的配置无效，目标域名需另行添加且不能是别名
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias_of: Option<String>,
+    /// 发送给后端的 Host 及 TLS SNI，为空时别名使用目标域名，其它域名沿用请求的 Host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_host: Option<String>,
+    /// 改写响应中指向后端域名的地址，为空时不改写
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<ResponseRewrite>,
+    /// 请求头及响应头的改写规则
+    #[serde(default, skip_serializing_if = "HeaderRules::is_empty")]
+    pub headers: HeaderRules,
+    /// 同时处理的请求数的限制，为空时不限制
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<ConcurrencyLimit>,
+}
+
+/// 错误响应中展示的详细程度
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorDetail {
+    /// 只包含错误码、状态码及请求 ID
+    Code,
+    /// 额外包含面向客户端的错误说明
+    #[default]
+    Message,
+    /// 额外包含内部错误信息，例如后端地址，仅用于调试
+    Internal,
 }
 
 #[derive(Clone)]
 pub enum Op {
-    Add(String),
+    Add(DomainConfig),
     Del(String),
+    /// 通知所有服务发现立即重新发现，例如静态解析表变更后
+    Refresh,
 }
 
 impl fmt::Debug for Op {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Op::Add(domain) => write!(f, "Add domain: {domain}"),
+            Op::Add(config) => write!(f, "Add domain: {}", config.domain),
             Op::Del(domain) => write!(f, "Remove domain: {domain}"),
+            Op::Refresh => write!(f, "Refresh all domains"),
         }
     }
 }
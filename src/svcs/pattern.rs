@@ -0,0 +1,64 @@
+use std::{fmt, ops::Deref};
+
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// 匹配请求路径的正则表达式，反序列化时编译，命名捕获组可在模板中使用
+#[derive(Clone)]
+pub struct PathPattern(Regex);
+
+impl PathPattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Regex::new(pattern).map(Self)
+    }
+
+    /// 匹配时返回命名捕获组的值，未参与匹配的捕获组为空字符串
+    pub fn captures(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let captures = self.0.captures(path)?;
+        Some(
+            self.0
+                .capture_names()
+                .flatten()
+                .map(|name| {
+                    let value = captures.name(name).map_or("", |m| m.as_str());
+                    (name.to_string(), value.to_string())
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Deref for PathPattern {
+    type Target = Regex;
+
+    fn deref(&self) -> &Regex {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PathPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.0.as_str(), f)
+    }
+}
+
+impl PartialEq for PathPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for PathPattern {}
+
+impl Serialize for PathPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for PathPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Self::new(&pattern).map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use log::{error, info, warn};
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use pingora_runtime::current_handle;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        RwLock,
+    },
+    task::JoinHandle,
+};
+
+use super::{Discovery, DomainConfig, Op, UpstreamsHealthCheck};
+
+/// 未指定服务发现时使用的服务发现名称
+pub const DEFAULT_PROVIDER: &str = "dns";
+
+/// 域名注册表
+/// 处理管理 API 添加/删除域名的操作，通过域名指定的服务发现获取后端集合，
+/// 并为每个域名维护一个 UpstreamsHealthCheck 服务
+pub struct Registry {
+    providers: Arc<RwLock<HashMap<String, Arc<dyn Discovery>>>>,
+    /// 创建时订阅，启动时取出，启动前发送的操作不会丢失
+    add_domain_queen: Mutex<Option<broadcast::Receiver<Op>>>,
+    /// 与管理 API 共用的操作队列，供 Ingress 等自动添加/删除域名
+    sender: broadcast::Sender<Op>,
+    backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+    /// 已添加域名的配置，供代理读取超时等请求相关的设置
+    domains: Arc<RwLock<HashMap<String, Arc<DomainConfig>>>>,
+    /// 每个域名订阅后端集合变化的任务
+    watchers: Mutex<HashMap<String, JoinHandle<()>>>,
+}
+
+impl Registry {
+    pub fn new(
+        providers: Arc<RwLock<HashMap<String, Arc<dyn Discovery>>>>,
+        sender: broadcast::Sender<Op>,
+        backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+        domains: Arc<RwLock<HashMap<String, Arc<DomainConfig>>>>,
+    ) -> Self {
+        Self {
+            providers,
+            add_domain_queen: Mutex::new(Some(sender.subscribe())),
+            sender,
+            backgrounds,
+            domains,
+            watchers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 注册一个服务发现，域名可以通过 `provider` 使用
+    pub async fn add_provider(&self, name: impl Into<String>, provider: Arc<dyn Discovery>) {
+        self.providers.write().await.insert(name.into(), provider);
+    }
+
+    /// 添加/删除域名的操作队列，效果与通过管理 API 操作相同
+    pub fn sender(&self) -> broadcast::Sender<Op> {
+        self.sender.clone()
+    }
+
+    pub fn backgrounds(&self) -> Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>> {
+        self.backgrounds.clone()
+    }
+
+    pub fn domains(&self) -> Arc<RwLock<HashMap<String, Arc<DomainConfig>>>> {
+        self.domains.clone()
+    }
+
+    /// 添加一个域名
+    /// 订阅域名的后端集合变化，第一次得到后端集合时创建 UpstreamsHealthCheck 服务，
+    /// 之后的变化原地更新，已存在的域名会使用新的配置重新订阅
+    /// 别名只记录配置，请求时使用目标域名的后端集合
+    async fn add(&self, config: DomainConfig, shutdown: ShutdownWatch) {
+        if let Some(target) = &config.alias_of {
+            info!("Registry::add {} as alias of {target}", config.domain);
+            let domain = config.domain.clone();
+            self.domains
+                .write()
+                .await
+                .insert(domain.clone(), Arc::new(config));
+            self.stop(&domain).await;
+            return;
+        }
+        let name = config.provider.as_deref().unwrap_or(DEFAULT_PROVIDER);
+        let Some(provider) = self.providers.read().await.get(name).cloned() else {
+            error!(
+                "Registry add domain {} failed: provider {name} not found",
+                config.domain
+            );
+            return;
+        };
+        info!("Registry::add {} with provider {name}", config.domain);
+
+        let domain = config.domain.clone();
+        self.domains
+            .write()
+            .await
+            .insert(domain.clone(), Arc::new(config.clone()));
+        let backgrounds = self.backgrounds.clone();
+        let watcher = current_handle().spawn(watch(provider, config, backgrounds, shutdown));
+        if let Some(old) = self.watchers.lock().unwrap().insert(domain, watcher) {
+            old.abort();
+        }
+    }
+
+    async fn remove(&self, domain: &str) {
+        self.domains.write().await.remove(domain);
+        self.stop(domain).await;
+    }
+
+    /// 停止订阅域名的后端集合并停止 UpstreamsHealthCheck 服务
+    async fn stop(&self, domain: &str) {
+        if let Some(watcher) = self.watchers.lock().unwrap().remove(domain) {
+            watcher.abort();
+        }
+        if let Some(background) = self.backgrounds.write().await.remove(domain) {
+            background.stop();
+        }
+    }
+
+    async fn refresh(&self) {
+        for provider in self.providers.read().await.values() {
+            provider.refresh();
+        }
+    }
+}
+
+/// 订阅域名的后端集合变化并应用到对应的 UpstreamsHealthCheck
+/// 发现失败时保留原有后端；服务发现返回空集合时清空后端，请求返回 503
+async fn watch(
+    provider: Arc<dyn Discovery>,
+    config: DomainConfig,
+    backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>>,
+    shutdown: ShutdownWatch,
+) {
+    let domain = config.domain.as_str();
+    let mut changes = provider.watch(config.clone());
+    while let Some(result) = changes.next().await {
+        let mut endpoints = match result {
+            Ok(endpoints) => endpoints,
+            Err(e) => {
+                warn!("Registry discover domain {domain} failed: {e}");
+                continue;
+            }
+        };
+        endpoints.retain(|e| config.family.allows(&e.addr));
+        if endpoints.is_empty() {
+            warn!("Registry discover domain {domain} got no backend");
+        }
+
+        let existing = backgrounds.read().await.get(domain).cloned();
+        if let Some(background) = existing {
+            background.set_family(config.family);
+            background.update(endpoints).await;
+            continue;
+        }
+        let background = match UpstreamsHealthCheck::new(endpoints).await {
+            Ok(background) => Arc::new(background),
+            Err(e) => {
+                error!("Registry create backends of domain {domain} failed: {e}");
+                continue;
+            }
+        };
+        background.set_family(config.family);
+        backgrounds
+            .write()
+            .await
+            .insert(domain.to_owned(), background.clone());
+        let shutdown = shutdown.clone();
+        current_handle().spawn(async move {
+            background.start(shutdown).await;
+        });
+        info!("Registry started domain {domain}.");
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Registry {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let taken = self.add_domain_queen.lock().unwrap().take();
+        let mut add_domain_queen = taken.unwrap_or_else(|| self.sender.subscribe());
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    println!("Registry Shutdown.");
+                    for (_, watcher) in self.watchers.lock().unwrap().drain() {
+                        watcher.abort();
+                    }
+                    let backgrounds = self.backgrounds.write().await;
+                    for (_, background) in backgrounds.iter() {
+                        background.stop();
+                    }
+                    break;
+                }
+                op = add_domain_queen.recv() => {
+                    let op = match op {
+                        Ok(op) => op,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Registry skipped {skipped} operations, refresh all domains");
+                            self.refresh().await;
+                            continue;
+                        }
+                        Err(RecvError::Closed) => {
+                            warn!("Registry operation queue closed");
+                            break;
+                        }
+                    };
+                    match op {
+                        Op::Add(config) => {
+                            self.add(config, shutdown.clone()).await;
+                        }
+                        Op::Del(domain) => {
+                            self.remove(&domain).await;
+                        }
+                        Op::Refresh => {
+                            self.refresh().await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tokio::time::{sleep, timeout};
+
+    use super::*;
+    use crate::svcs::{EndpointStream, Error};
+
+    /// 按顺序产生预设的结果
+    #[derive(Default)]
+    struct Scripted {
+        results: Mutex<Vec<Result<Vec<Endpoint>, Error>>>,
+        refreshes: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Discovery for Scripted {
+        async fn discover(&self, _config: &DomainConfig) -> Result<Vec<Endpoint>, Error> {
+            Err(Error::Discovery("not scripted".to_string()))
+        }
+
+        fn watch(self: Arc<Self>, _config: DomainConfig) -> EndpointStream {
+            let results = std::mem::take(&mut *self.results.lock().unwrap());
+            futures::stream::iter(results).boxed()
+        }
+
+        fn refresh(&self) {
+            self.refreshes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn config() -> DomainConfig {
+        DomainConfig {
+            domain: "example.com".to_string(),
+            provider: Some("scripted".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn endpoints(addrs: &[&str]) -> Vec<Endpoint> {
+        addrs
+            .iter()
+            .map(|addr| Endpoint::from(addr.parse::<std::net::SocketAddr>().unwrap()))
+            .collect()
+    }
+
+    async fn backends(
+        backgrounds: &RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>,
+    ) -> Vec<String> {
+        let background = backgrounds.read().await["example.com"].clone();
+        background.statuses().into_iter().map(|s| s.addr).collect()
+    }
+
+    #[tokio::test]
+    async fn empty_set_clears_backends_and_errors_keep_them() {
+        let backgrounds: Arc<RwLock<HashMap<String, Arc<UpstreamsHealthCheck>>>> = Arc::default();
+        let (_shutdown, shutdown) = tokio::sync::watch::channel(false);
+        let run = |results| {
+            let provider = Arc::new(Scripted {
+                results: Mutex::new(results),
+                ..Default::default()
+            });
+            watch(provider, config(), backgrounds.clone(), shutdown.clone())
+        };
+        run(vec![
+            Ok(endpoints(&["10.0.0.1:80"])),
+            Err(Error::Discovery("unreachable".to_string())),
+        ])
+        .await;
+        assert_eq!(backends(&backgrounds).await, ["10.0.0.1:80"]);
+
+        run(vec![Ok(Vec::new())]).await;
+        assert!(backends(&backgrounds).await.is_empty());
+        let background = backgrounds.read().await["example.com"].clone();
+        assert!(background.select().is_none());
+    }
+
+    #[tokio::test]
+    async fn lagged_operations_refresh_all_domains() {
+        let provider = Arc::new(Scripted::default());
+        let providers: HashMap<String, Arc<dyn Discovery>> = HashMap::from([(
+            "scripted".to_string(),
+            provider.clone() as Arc<dyn Discovery>,
+        )]);
+        let (sender, _) = broadcast::channel(1);
+        let registry = Arc::new(Registry::new(
+            Arc::new(RwLock::new(providers)),
+            sender.clone(),
+            Arc::default(),
+            Arc::default(),
+        ));
+        for _ in 0..3 {
+            sender.send(Op::Del("example.com".to_string())).unwrap();
+        }
+        let (stop, shutdown) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.start(shutdown).await }
+        });
+        timeout(Duration::from_secs(3), async {
+            while provider.refreshes.load(Ordering::SeqCst) == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        stop.send(true).unwrap();
+        timeout(Duration::from_secs(3), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
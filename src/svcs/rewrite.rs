@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+/// 响应改写的配置，将后端返回的指向后端域名的地址改写为代理的地址，
+/// 避免通过其它域名代理时客户端被重定向回原域名
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResponseRewrite {
+    /// 除发送给后端的 Host 外需要改写的后端域名
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    /// 改写后地址的协议，为空时与客户端请求的协议相同
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<String>,
+    /// 路径前缀的映射，用于 `Location` 等响应头及 `Set-Cookie` 的 `Path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<PathPrefix>,
+    /// 是否去掉 `Set-Cookie` 中指向后端域名的 `Domain`，使 Cookie 属于代理的域名
+    #[serde(default = "default_cookie_domain")]
+    pub cookie_domain: bool,
+    /// 是否改写响应体中的绝对地址，gzip 及 deflate 压缩的响应体会先解压
+    #[serde(default)]
+    pub body: bool,
+    /// 改写响应体的内容类型
+    #[serde(default = "default_body_types")]
+    pub body_types: Vec<String>,
+}
+
+/// 后端路径前缀与代理路径前缀的映射
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PathPrefix {
+    pub upstream: String,
+    pub client: String,
+}
+
+fn default_cookie_domain() -> bool {
+    true
+}
+
+fn default_body_types() -> Vec<String> {
+    [
+        "text/html",
+        "text/css",
+        "application/javascript",
+        "text/javascript",
+    ]
+    .map(String::from)
+    .to_vec()
+}
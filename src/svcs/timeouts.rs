@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// 域名的超时设置，单位毫秒，为空时不限制
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Timeouts {
+    /// 与后端建立 TCP 连接
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_ms: Option<u64>,
+    /// TCP 连接建立后与后端完成 TLS 握手
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_handshake_ms: Option<u64>,
+    /// 请求发出后等待后端的响应头
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_byte_ms: Option<u64>,
+    /// 读取后端响应体时，两次收到数据之间的最长间隔
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_idle_ms: Option<u64>,
+    /// 向后端写入请求时，单次写入的最长时间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_idle_ms: Option<u64>,
+    /// 从收到请求到响应结束的总时间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_ms: Option<u64>,
+}
+
+impl Timeouts {
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect_ms.map(Duration::from_millis)
+    }
+
+    pub fn tls_handshake(&self) -> Option<Duration> {
+        self.tls_handshake_ms.map(Duration::from_millis)
+    }
+
+    pub fn first_byte(&self) -> Option<Duration> {
+        self.first_byte_ms.map(Duration::from_millis)
+    }
+
+    pub fn read_idle(&self) -> Option<Duration> {
+        self.read_idle_ms.map(Duration::from_millis)
+    }
+
+    pub fn write_idle(&self) -> Option<Duration> {
+        self.write_idle_ms.map(Duration::from_millis)
+    }
+
+    pub fn total(&self) -> Option<Duration> {
+        self.total_ms.map(Duration::from_millis)
+    }
+}